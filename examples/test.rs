@@ -1,7 +1,7 @@
 extern crate leveldb;
 
 fn main() {
-    let mut db = leveldb::open("level");
+    let db = leveldb::open("level", Default::default()).unwrap();
     // db.set("key0", "value0");
     // db.set("key1", "value1");
     // db.set("key2", "value2");
@@ -0,0 +1,468 @@
+use crc::crc32;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use checkpoint::LiveFiles;
+use env::{self, Env};
+use filename::{self, FileType};
+use leveldb::LevelDB;
+
+// A backup listed by BackupEngine::list_backups
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackupInfo {
+    pub id: u64,
+    // Sequence number of the last write in the backup
+    pub sequence: u64,
+    pub timestamp_micros: u64,
+    // Total size of the files, including tables shared with other backups
+    pub size: u64,
+    pub num_files: usize,
+}
+
+// BackupEngine keeps backups of DBs in a directory:
+//  shared/<number>_<crc32>_<size>.ldb: tables, which are shared by backups
+//  private/<id>/: the MANIFEST and logs of a backup
+//  meta/<id>: the sequence number and the files of a backup
+// A table is read and copied only if no backup has it yet, so a backup taken after another
+// one reads only tables written between them.
+pub struct BackupEngine {
+    dir: String,
+    env: Arc<dyn Env>,
+}
+
+// A file of a backup. path is relative to the backup directory, and name is the one in the DB.
+struct BackupFile {
+    path: String,
+    name: String,
+    size: u64,
+    crc: u32,
+}
+
+struct BackupMeta {
+    sequence: u64,
+    timestamp_micros: u64,
+    manifest_number: u64,
+    files: Vec<BackupFile>,
+}
+
+impl BackupEngine {
+    // Opens the backups in dir, creating it if missing
+    pub fn open(dir: &str, env: Arc<dyn Env>) -> Result<Self, String> {
+        let engine = BackupEngine {
+            dir: dir.to_owned(),
+            env,
+        };
+        for sub in &["", "/shared", "/private", "/meta"] {
+            let path = format!("{:}{:}", dir, sub);
+            if !engine.env.file_exists(&path) {
+                engine.env.create_dir(&path)?;
+            }
+        }
+        Ok(engine)
+    }
+
+    // Copies the files of db as a new backup, and returns its id. Writes to db go on while
+    // the files are copied, and the backup has every write which finished before the call.
+    pub fn create_backup(&self, db: &LevelDB) -> Result<u64, String> {
+        let id = self.backup_ids()?.last().map_or(1, |id| id + 1);
+        // The backup exists once its meta is written
+        let result = db
+            .with_live_files(|files| self.copy_files(files, id))
+            .and_then(|meta| self.write_file(meta.encode().as_bytes(), &self.meta_file(id)));
+        if result.is_err() {
+            self.remove_private_files(id);
+            let _ = self.purge_shared_files();
+        }
+        result.map(|_| id)
+    }
+
+    pub fn list_backups(&self) -> Result<Vec<BackupInfo>, String> {
+        self.backup_ids()?
+            .into_iter()
+            .map(|id| {
+                let meta = self.read_meta(id)?;
+                Ok(BackupInfo {
+                    id,
+                    sequence: meta.sequence,
+                    timestamp_micros: meta.timestamp_micros,
+                    size: meta.files.iter().map(|f| f.size).sum(),
+                    num_files: meta.files.len(),
+                })
+            })
+            .collect()
+    }
+
+    // Deletes a backup, and its tables which no other backup has
+    pub fn delete_backup(&self, id: u64) -> Result<(), String> {
+        let fname = self.meta_file(id);
+        if !self.env.file_exists(&fname) {
+            return Err(format!("backup {:} not found", id));
+        }
+        self.env.remove_file(&fname)?;
+        self.remove_private_files(id);
+        self.purge_shared_files()
+    }
+
+    // Checks that every file of a backup has the size and the CRC recorded in the backup
+    pub fn verify_backup(&self, id: u64) -> Result<(), String> {
+        let meta = self.read_meta(id)?;
+        for f in &meta.files {
+            self.read_backup_file(f)?;
+        }
+        Ok(())
+    }
+
+    // Writes the files of a backup to db_dir, which must not exist, so that it can be opened
+    // as the DB at the time of the backup. Files are checked as verify_backup does.
+    pub fn restore(&self, id: u64, db_dir: &str) -> Result<(), String> {
+        let meta = self.read_meta(id)?;
+        if self.env.file_exists(db_dir) {
+            return Err(format!("{:}: already exists", db_dir));
+        }
+        self.env.create_dir(db_dir)?;
+
+        let result = self.restore_files(&meta, db_dir);
+        if result.is_err() {
+            self.remove_dir_all(db_dir);
+        }
+        result
+    }
+
+    fn copy_files(&self, files: &LiveFiles, id: u64) -> Result<BackupMeta, String> {
+        let env = &*self.env;
+        let mut backup_files = vec![];
+        let shared = self.shared_tables()?;
+        for &(num, size) in &files.tables {
+            let src = FileType::Table(&files.dbname, num).filename();
+            // Tables are never rewritten, so a shared one with the number and the size is
+            // the same table, and it is neither read nor checksummed again
+            let crc = match shared.get(&(num, size)) {
+                Some(&crc) => crc,
+                None => {
+                    let data = env::read_file_prefix(env, &src, size)?;
+                    let crc = crc32::checksum_ieee(&data);
+                    let fname = format!("{:}/{:}", self.dir, shared_path(num, crc, size));
+                    self.write_file(&data, &fname)?;
+                    crc
+                }
+            };
+            backup_files.push(BackupFile {
+                path: shared_path(num, crc, size),
+                name: base_name(&src).to_owned(),
+                size,
+                crc,
+            });
+        }
+
+        env.create_dir(&self.private_dir(id))?;
+        let manifest = FileType::Manifest(&files.dbname, files.manifest_number as usize);
+        let mut private = vec![(manifest.filename(), files.manifest_size)];
+        for &(num, size) in &files.logs {
+            private.push((FileType::Log(&files.dbname, num).filename(), size));
+        }
+        for (src, size) in private {
+            let data = env::read_file_prefix(env, &src, size)?;
+            let name = base_name(&src).to_owned();
+            let path = format!("private/{:}/{:}", id, name);
+            env::write_string_to_file(env, &data, &format!("{:}/{:}", self.dir, path), true)?;
+            backup_files.push(BackupFile {
+                path,
+                name,
+                size,
+                crc: crc32::checksum_ieee(&data),
+            });
+        }
+
+        Ok(BackupMeta {
+            sequence: files.last_sequence,
+            timestamp_micros: env.now_micros(),
+            manifest_number: files.manifest_number,
+            files: backup_files,
+        })
+    }
+
+    fn restore_files(&self, meta: &BackupMeta, db_dir: &str) -> Result<(), String> {
+        for f in &meta.files {
+            let data = self.read_backup_file(f)?;
+            let fname = format!("{:}/{:}", db_dir, f.name);
+            env::write_string_to_file(&*self.env, &data, &fname, true)?;
+        }
+        filename::set_current_file(&*self.env, db_dir, meta.manifest_number as usize)
+    }
+
+    fn read_backup_file(&self, f: &BackupFile) -> Result<Vec<u8>, String> {
+        let fname = format!("{:}/{:}", self.dir, f.path);
+        let size = self.env.get_file_size(&fname)?;
+        if size != f.size {
+            return Err(format!(
+                "{:}: size is {:} bytes, but {:} in the backup",
+                fname, size, f.size
+            ));
+        }
+        let data = env::read_file_prefix(&*self.env, &fname, size)?;
+        if crc32::checksum_ieee(&data) != f.crc {
+            return Err(format!("{:}: checksum mismatch", fname));
+        }
+        Ok(data)
+    }
+
+    // Writes data to a temporary file and renames it, so that fname is never left partial
+    fn write_file(&self, data: &[u8], fname: &str) -> Result<(), String> {
+        let tmp = format!("{:}.tmp", fname);
+        let result = env::write_string_to_file(&*self.env, data, &tmp, true)
+            .and_then(|_| self.env.rename_file(&tmp, fname));
+        if result.is_err() {
+            let _ = self.env.remove_file(&tmp);
+        }
+        result
+    }
+
+    // Removes the tables which no backup has, including ones left by a failed backup
+    fn purge_shared_files(&self) -> Result<(), String> {
+        let mut live = HashSet::new();
+        for id in self.backup_ids()? {
+            live.extend(self.read_meta(id)?.files.into_iter().map(|f| f.path));
+        }
+        let shared_dir = format!("{:}/shared", self.dir);
+        for name in self.env.get_children(&shared_dir)? {
+            if !live.contains(&format!("shared/{:}", name)) {
+                self.env
+                    .remove_file(&format!("{:}/{:}", shared_dir, name))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the CRCs of the shared tables by their numbers and sizes
+    fn shared_tables(&self) -> Result<HashMap<(u64, u64), u32>, String> {
+        let mut tables = HashMap::new();
+        for name in self.env.get_children(&format!("{:}/shared", self.dir))? {
+            if !name.ends_with(".ldb") {
+                continue;
+            }
+            let fields: Vec<_> = name[..name.len() - 4].split('_').collect();
+            if let [num, crc, size] = fields.as_slice() {
+                if let (Some(num), Some(crc), Some(size)) = (parse(num), parse(crc), parse(size)) {
+                    tables.insert((num, size), crc);
+                }
+            }
+        }
+        Ok(tables)
+    }
+
+    fn remove_private_files(&self, id: u64) {
+        self.remove_dir_all(&self.private_dir(id));
+    }
+
+    // Errors are ignored, so that as many files as possible are removed
+    fn remove_dir_all(&self, dir: &str) {
+        for name in self.env.get_children(dir).unwrap_or_default() {
+            let _ = self.env.remove_file(&format!("{:}/{:}", dir, name));
+        }
+        let _ = self.env.remove_dir(dir);
+    }
+
+    // Returns the ids of the backups in ascending order
+    fn backup_ids(&self) -> Result<Vec<u64>, String> {
+        let mut ids: Vec<u64> = self
+            .env
+            .get_children(&format!("{:}/meta", self.dir))?
+            .iter()
+            .filter_map(|name| name.parse().ok())
+            .collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn read_meta(&self, id: u64) -> Result<BackupMeta, String> {
+        let fname = self.meta_file(id);
+        if !self.env.file_exists(&fname) {
+            return Err(format!("backup {:} not found", id));
+        }
+        env::read_file_to_string(&*self.env, &fname)
+            .and_then(|data| BackupMeta::decode(&data))
+            .map_err(|msg| format!("{:}: {:}", fname, msg))
+    }
+
+    fn meta_file(&self, id: u64) -> String {
+        format!("{:}/meta/{:}", self.dir, id)
+    }
+
+    fn private_dir(&self, id: u64) -> String {
+        format!("{:}/private/{:}", self.dir, id)
+    }
+}
+
+impl BackupMeta {
+    // One field per line, such as "sequence 10", and
+    // "file <path> <name> <size> <crc32>" for each file
+    fn encode(&self) -> String {
+        let mut s = format!(
+            "sequence {:}\ntimestamp {:}\nmanifest {:}\n",
+            self.sequence, self.timestamp_micros, self.manifest_number
+        );
+        for f in &self.files {
+            s.push_str(&format!(
+                "file {:} {:} {:} {:}\n",
+                f.path, f.name, f.size, f.crc
+            ));
+        }
+        s
+    }
+
+    fn decode(data: &str) -> Result<Self, String> {
+        let mut meta = BackupMeta {
+            sequence: 0,
+            timestamp_micros: 0,
+            manifest_number: 0,
+            files: vec![],
+        };
+        for line in data.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let malformed = || format!("malformed line: {:}", line);
+            match fields.as_slice() {
+                ["sequence", v] => meta.sequence = parse(v).ok_or_else(malformed)?,
+                ["timestamp", v] => meta.timestamp_micros = parse(v).ok_or_else(malformed)?,
+                ["manifest", v] => meta.manifest_number = parse(v).ok_or_else(malformed)?,
+                ["file", path, name, size, crc] => meta.files.push(BackupFile {
+                    path: path.to_string(),
+                    name: name.to_string(),
+                    size: parse(size).ok_or_else(malformed)?,
+                    crc: parse(crc).ok_or_else(malformed)?,
+                }),
+                _ => return Err(malformed()),
+            }
+        }
+        Ok(meta)
+    }
+}
+
+fn parse<T: FromStr>(s: &str) -> Option<T> {
+    s.parse().ok()
+}
+
+fn shared_path(num: u64, crc: u32, size: u64) -> String {
+    format!("shared/{:07}_{:}_{:}.ldb", num, crc, size)
+}
+
+fn base_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use env::MemEnv;
+    use leveldb::open;
+    use std::io::Read;
+    use test_util::{mem_options, set_all};
+
+    use super::*;
+
+    fn get(db: &LevelDB, i: usize) -> Option<Bytes> {
+        db.get(&format!("key{:04}", i))
+    }
+
+    fn value(i: usize, round: usize) -> Option<Bytes> {
+        Some(Bytes::from(format!("value{:}-{:}", i, round)))
+    }
+
+    #[test]
+    fn backup_and_restore() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let engine = BackupEngine::open("/backup", env.clone()).unwrap();
+        let db = open("/backup_db", mem_options(&env)).unwrap();
+        set_all(&db, 0..100, 0);
+        db.compact_range(None, None).unwrap();
+        // Left in the log
+        set_all(&db, 50..60, 1);
+        assert_eq!(engine.create_backup(&db).unwrap(), 1);
+
+        // The second backup shares the table, and has a longer log
+        set_all(&db, 1000..1100, 2);
+        assert_eq!(engine.create_backup(&db).unwrap(), 2);
+        assert_eq!(env.get_children("/backup/shared").unwrap().len(), 1);
+
+        let backups = engine.list_backups().unwrap();
+        assert_eq!(
+            backups
+                .iter()
+                .map(|b| (b.id, b.sequence))
+                .collect::<Vec<_>>(),
+            vec![(1, 110), (2, 210)]
+        );
+        engine.verify_backup(1).unwrap();
+        engine.verify_backup(2).unwrap();
+
+        engine.restore(1, "/restore1").unwrap();
+        assert!(engine.restore(1, "/restore1").is_err());
+        let restored = open("/restore1", mem_options(&env)).unwrap();
+        assert_eq!(get(&restored, 0), value(0, 0));
+        assert_eq!(get(&restored, 50), value(50, 1));
+        assert_eq!(get(&restored, 1000), None);
+
+        // Deleting a backup keeps the tables which the other one has
+        engine.delete_backup(1).unwrap();
+        assert!(engine.delete_backup(1).is_err());
+        assert_eq!(env.get_children("/backup/shared").unwrap().len(), 1);
+        engine.restore(2, "/restore2").unwrap();
+        let restored = open("/restore2", mem_options(&env)).unwrap();
+        assert_eq!(get(&restored, 50), value(50, 1));
+        assert_eq!(get(&restored, 1000), value(1000, 2));
+
+        engine.delete_backup(2).unwrap();
+        assert!(env.get_children("/backup/shared").unwrap().is_empty());
+        assert!(engine.list_backups().unwrap().is_empty());
+    }
+
+    #[test]
+    fn backup_skips_shared_tables() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let engine = BackupEngine::open("/backup_shared", env.clone()).unwrap();
+        let db = open("/backup_shared_db", mem_options(&env)).unwrap();
+        set_all(&db, 0..100, 0);
+        db.compact_range(None, None).unwrap();
+        engine.create_backup(&db).unwrap();
+
+        // The second backup does not read the table, so it does not see the garbage
+        let tables: Vec<_> = env
+            .get_children("/backup_shared_db")
+            .unwrap()
+            .into_iter()
+            .filter(|name| name.ends_with(".ldb"))
+            .collect();
+        assert_eq!(tables.len(), 1);
+        let fname = format!("/backup_shared_db/{:}", tables[0]);
+        let size = env.get_file_size(&fname).unwrap() as usize;
+        env::write_string_to_file(&*env, &vec![0; size], &fname, true).unwrap();
+        let id = engine.create_backup(&db).unwrap();
+        assert_eq!(env.get_children("/backup_shared/shared").unwrap().len(), 1);
+        engine.verify_backup(id).unwrap();
+    }
+
+    #[test]
+    fn backup_corrupted() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let engine = BackupEngine::open("/backup_corrupted", env.clone()).unwrap();
+        let db = open("/backup_corrupted_db", mem_options(&env)).unwrap();
+        set_all(&db, 0..100, 0);
+        db.compact_range(None, None).unwrap();
+        let id = engine.create_backup(&db).unwrap();
+
+        let shared = env.get_children("/backup_corrupted/shared").unwrap();
+        let fname = format!("/backup_corrupted/shared/{:}", shared[0]);
+        let mut data = vec![];
+        env.new_sequential_file(&fname)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        data[10] ^= 0xff;
+        env::write_string_to_file(&*env, &data, &fname, true).unwrap();
+
+        let err = engine.verify_backup(id).unwrap_err();
+        assert!(err.contains("checksum mismatch"), "{}", err);
+        assert!(engine.restore(id, "/backup_corrupted_restore").is_err());
+        assert!(!env.file_exists("/backup_corrupted_restore"));
+    }
+}
@@ -1,7 +1,7 @@
 use byteorder::{ByteOrder, LittleEndian};
 use bytes::{BufMut, Bytes, BytesMut};
-// use memdb::MemDB;
-use ikey::KeyKind;
+use ikey::{InternalKey, KeyKind};
+use memdb::MemDB;
 use std::iter::{IntoIterator, Iterator};
 
 const COUNT_INDEX: usize = 8;
@@ -17,9 +17,16 @@ const VALUE_LENGTH_SIZE: usize = 4;
 type Key = Bytes;
 type Value = Bytes;
 
+// Callback interface used by WriteBatch::iterate
+pub trait Handler {
+    fn put(&mut self, key: &Bytes, value: &Bytes);
+    fn delete(&mut self, key: &Bytes);
+}
+
 // Need External Lock
 // data: | seq (8byte) | count (4byte) | record (n byte) |
 // record: | type value (1 byte) | key len (4bytes) | key | value len (4bytes) | value |
+// A Delete record has no value part.
 pub struct WriteBatch {
     seq: u64,
     count: u32,
@@ -35,7 +42,11 @@ impl WriteBatch {
         }
     }
 
-    pub fn load_data(mut data: Bytes) -> Self {
+    pub fn load_data(mut data: Bytes) -> Result<Self, String> {
+        if data.len() < RECORD_INDEX {
+            return Err("malformed WriteBatch (too small)".to_owned());
+        }
+
         let seq = {
             let c = data.split_to(SEQ_SIZE);
             LittleEndian::read_u64(c.as_ref())
@@ -46,12 +57,14 @@ impl WriteBatch {
             LittleEndian::read_u32(c.as_ref())
         };
 
-        let data = data.try_mut().unwrap();
-        WriteBatch { seq, count, data }
+        let data = data.try_mut().unwrap_or_else(|b| BytesMut::from(&b[..]));
+        let batch = WriteBatch { seq, count, data };
+        batch.iterate(&mut RecordCounter)?;
+        Ok(batch)
     }
 
     pub fn data(&self) -> Bytes {
-        let mut v = BytesMut::with_capacity(RECORD_INDEX);
+        let mut v = BytesMut::with_capacity(self.approximate_size());
         v.put_u64_le(self.seq);
         v.put_u32_le(self.count);
         v.extend(self.data.clone()); // XXX
@@ -65,6 +78,68 @@ impl WriteBatch {
         self.append_str(value);
     }
 
+    pub fn delete(&mut self, key: &str) {
+        self.inc_count();
+        self.data.put_u8(KeyKind::Delete as u8);
+        self.append_str(key);
+    }
+
+    pub fn clear(&mut self) {
+        self.seq = 0;
+        self.count = 0;
+        self.data.clear();
+    }
+
+    // Copies the records of other to the end of this batch. The sequence number is kept as is.
+    pub fn append(&mut self, other: &WriteBatch) {
+        self.count += other.count;
+        self.data.extend_from_slice(&other.data);
+    }
+
+    // The size of the batch when it is written to a log file
+    pub fn approximate_size(&self) -> usize {
+        RECORD_INDEX + self.data.len()
+    }
+
+    pub fn iterate(&self, handler: &mut dyn Handler) -> Result<(), String> {
+        let data = Bytes::from(&self.data[..]);
+        let mut pos = 0;
+        let mut found = 0;
+
+        while pos < data.len() {
+            found += 1;
+            let kind = data[pos];
+            pos += TYPE_SIZE;
+
+            let key = read_length_prefixed(&data, &mut pos)
+                .ok_or_else(|| "bad WriteBatch Put".to_owned())?;
+            match kind {
+                k if k == KeyKind::Value as u8 => {
+                    let value = read_length_prefixed(&data, &mut pos)
+                        .ok_or_else(|| "bad WriteBatch Put".to_owned())?;
+                    handler.put(&key, &value);
+                }
+                k if k == KeyKind::Delete as u8 => handler.delete(&key),
+                _ => return Err("unknown WriteBatch tag".to_owned()),
+            }
+        }
+
+        if found != self.count {
+            Err("WriteBatch has wrong count".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+
+    // Insert all records to mem. Sequence numbers are assigned from self.seq in order.
+    pub fn insert_into(&self, mem: &MemDB) -> Result<(), String> {
+        let mut inserter = MemTableInserter {
+            seq: self.seq,
+            mem: mem,
+        };
+        self.iterate(&mut inserter)
+    }
+
     fn append_str(&mut self, value: &str) {
         // TODO: use varint
         let value_size = value.len();
@@ -89,6 +164,48 @@ impl WriteBatch {
     }
 }
 
+fn read_length_prefixed(data: &Bytes, pos: &mut usize) -> Option<Bytes> {
+    if *pos + KEY_LENGTH_SIZE > data.len() {
+        return None;
+    }
+
+    let len = LittleEndian::read_u32(&data[*pos..*pos + KEY_LENGTH_SIZE]) as usize;
+    *pos += KEY_LENGTH_SIZE;
+    if *pos + len > data.len() {
+        return None;
+    }
+
+    let v = data.slice(*pos, *pos + len);
+    *pos += len;
+    Some(v)
+}
+
+struct RecordCounter;
+
+impl Handler for RecordCounter {
+    fn put(&mut self, _key: &Bytes, _value: &Bytes) {}
+    fn delete(&mut self, _key: &Bytes) {}
+}
+
+struct MemTableInserter<'a> {
+    seq: u64,
+    mem: &'a MemDB,
+}
+
+impl<'a> Handler for MemTableInserter<'a> {
+    fn put(&mut self, key: &Bytes, value: &Bytes) {
+        let ikey = InternalKey::new_with_kind(key, self.seq, KeyKind::Value);
+        self.mem.add(&ikey, value);
+        self.seq += 1;
+    }
+
+    fn delete(&mut self, key: &Bytes) {
+        let ikey = InternalKey::new_with_kind(key, self.seq, KeyKind::Delete);
+        self.mem.add(&ikey, &Bytes::new());
+        self.seq += 1;
+    }
+}
+
 impl IntoIterator for WriteBatch {
     type Item = (KeyKind, Bytes, Bytes);
     type IntoIter = WriteBatchIterator;
@@ -133,10 +250,12 @@ impl Iterator for WriteBatchIterator {
             key
         };
 
-        let value = {
+        let value = if let KeyKind::Delete = typev {
+            Bytes::new()
+        } else {
             let value_len = {
                 let d = self.data.slice(self.pos, self.pos + VALUE_LENGTH_SIZE);
-                self.pos += KEY_LENGTH_SIZE;
+                self.pos += VALUE_LENGTH_SIZE;
                 LittleEndian::read_u32(&d) as usize
             };
 
@@ -162,3 +281,125 @@ impl WriteBatchIterator {
         self.idx >= self.count
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Recorder {
+        log: Vec<String>,
+    }
+
+    impl Handler for Recorder {
+        fn put(&mut self, key: &Bytes, value: &Bytes) {
+            self.log.push(format!(
+                "Put({:}, {:})",
+                String::from_utf8_lossy(key),
+                String::from_utf8_lossy(value)
+            ));
+        }
+
+        fn delete(&mut self, key: &Bytes) {
+            self.log
+                .push(format!("Delete({:})", String::from_utf8_lossy(key)));
+        }
+    }
+
+    fn contents(b: &WriteBatch) -> Vec<String> {
+        let mut r = Recorder { log: vec![] };
+        b.iterate(&mut r).unwrap();
+        r.log
+    }
+
+    #[test]
+    fn write_batch_put_and_delete() {
+        let mut b = WriteBatch::new();
+        b.put("foo", "bar");
+        b.delete("box");
+        b.put("baz", "boo");
+        b.set_seq(100);
+
+        assert_eq!(b.count(), 3);
+        assert_eq!(
+            contents(&b),
+            vec!["Put(foo, bar)", "Delete(box)", "Put(baz, boo)"]
+        );
+
+        let b2 = WriteBatch::load_data(b.data()).unwrap();
+        assert_eq!(b2.seq(), 100);
+        assert_eq!(b2.count(), 3);
+        assert_eq!(contents(&b2), contents(&b));
+    }
+
+    #[test]
+    fn write_batch_clear() {
+        let mut b = WriteBatch::new();
+        b.put("foo", "bar");
+        b.set_seq(10);
+        b.clear();
+
+        assert_eq!(b.count(), 0);
+        assert_eq!(b.seq(), 0);
+        assert_eq!(b.approximate_size(), RECORD_INDEX);
+        assert!(contents(&b).is_empty());
+    }
+
+    #[test]
+    fn write_batch_append() {
+        let mut b1 = WriteBatch::new();
+        let mut b2 = WriteBatch::new();
+        b1.set_seq(200);
+        b2.set_seq(300);
+        b1.append(&b2);
+        assert!(contents(&b1).is_empty());
+
+        b2.put("a", "va");
+        b1.append(&b2);
+        assert_eq!(contents(&b1), vec!["Put(a, va)"]);
+
+        b2.clear();
+        b2.put("b", "vb");
+        b2.delete("foo");
+        b1.append(&b2);
+        assert_eq!(
+            contents(&b1),
+            vec!["Put(a, va)", "Put(b, vb)", "Delete(foo)"]
+        );
+        assert_eq!(b1.seq(), 200);
+        assert_eq!(b1.count(), 3);
+    }
+
+    #[test]
+    fn write_batch_approximate_size() {
+        let mut b = WriteBatch::new();
+        let empty_size = b.approximate_size();
+
+        b.put("foo", "bar");
+        let one_key_size = b.approximate_size();
+        assert!(empty_size < one_key_size);
+
+        b.delete("box");
+        let two_key_size = b.approximate_size();
+        assert!(one_key_size < two_key_size);
+        assert_eq!(two_key_size, b.data().len());
+    }
+
+    #[test]
+    fn write_batch_corruption() {
+        let mut b = WriteBatch::new();
+        b.put("foo", "bar");
+        b.delete("box");
+
+        let data = b.data();
+        assert!(WriteBatch::load_data(data.slice_to(RECORD_INDEX - 1)).is_err());
+        assert!(WriteBatch::load_data(data.slice_to(data.len() - 1)).is_err());
+
+        let mut bad_count = BytesMut::from(&data[..]);
+        LittleEndian::write_u32(&mut bad_count[COUNT_INDEX..RECORD_INDEX], 3);
+        assert!(WriteBatch::load_data(bad_count.freeze()).is_err());
+
+        let mut bad_tag = BytesMut::from(&data[..]);
+        bad_tag[RECORD_INDEX] = 0xff;
+        assert!(WriteBatch::load_data(bad_tag.freeze()).is_err());
+    }
+}
@@ -0,0 +1,344 @@
+extern crate leveldb;
+
+use leveldb::{LevelDB, Options, Range, WriteBatch};
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::process;
+use std::slice;
+
+// Lines read by batch-load are applied in batches of this many lines
+const BATCH_LOAD_SIZE: usize = 1000;
+
+fn usage() -> ! {
+    eprintln!("Usage: leveldb-cli <db_dir> <command> [args]");
+    eprintln!("Commands:");
+    eprintln!("  get <key>                         print the value of key");
+    eprintln!("  put <key> <value>                 set key to value");
+    eprintln!("  delete <key>                      delete key");
+    eprintln!("  scan [--from k] [--to k] [--limit n]");
+    eprintln!("                                    print keys in [from, to) and their values");
+    eprintln!("  batch-load                        apply lines of stdin, which are");
+    eprintln!("                                    put<TAB>key<TAB>value or delete<TAB>key");
+    eprintln!("  compact [--from k] [--to k]       compact keys in [from, to]");
+    eprintln!("  properties [name...]              print properties, all of them by default");
+    eprintln!("  repair                            rebuild the DB from its surviving files");
+    eprintln!("  checksum                          check the CRCs of the tables and logs");
+    eprintln!("  approximate-size <start> <limit>  print the size and key count of [start, limit)");
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 || args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        usage();
+    }
+
+    let dir = &args[0];
+    let command = &args[1];
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if let Err(msg) = run(dir, command, &args[2..], &mut stdin.lock(), &mut out) {
+        let _ = out.flush();
+        eprintln!("{:}: {:}", command, msg);
+        process::exit(1);
+    }
+}
+
+fn run(
+    dir: &str,
+    command: &str,
+    rest: &[String],
+    input: &mut dyn BufRead,
+    out: &mut dyn Write,
+) -> Result<(), String> {
+    match command {
+        "get" => get(dir, rest, out),
+        "put" => put(dir, rest),
+        "delete" => delete(dir, rest),
+        "scan" => scan(dir, rest, out),
+        "batch-load" => batch_load(dir, rest, input, out),
+        "compact" => compact(dir, rest),
+        "properties" => properties(dir, rest, out),
+        "repair" => repair(dir, rest),
+        "checksum" => checksum(dir, rest, out),
+        "approximate-size" => approximate_size(dir, rest, out),
+        _ => Err(format!("unknown command: {:}", command)),
+    }
+}
+
+// Opens an existing DB for writes, so that a mistyped dir is not made a new DB
+fn open(dir: &str) -> Result<LevelDB, String> {
+    let options = Options::default();
+    if !options.env.file_exists(&format!("{:}/CURRENT", dir)) {
+        return Err(format!("{:}: no DB is found", dir));
+    }
+    leveldb::open(dir, options)
+}
+
+// Opens a DB for reads, which does not take the lock of the DB
+fn open_read_only(dir: &str) -> Result<LevelDB, String> {
+    leveldb::open_read_only(dir, Options::default())
+}
+
+// Returns args if there are exactly n of them
+fn positional(args: &[String], n: usize) -> Result<&[String], String> {
+    if args.len() == n {
+        Ok(args)
+    } else {
+        Err(format!("expected {:} arguments, got {:}", n, args.len()))
+    }
+}
+
+// Parses --from, --to and --limit. Options not in allowed are rejected.
+struct RangeArgs {
+    from: Option<String>,
+    to: Option<String>,
+    limit: Option<usize>,
+}
+
+fn range_args(args: &[String], allowed: &[&str]) -> Result<RangeArgs, String> {
+    let mut range = RangeArgs {
+        from: None,
+        to: None,
+        limit: None,
+    };
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if !allowed.contains(&arg.as_ref()) {
+            return Err(format!("unknown argument: {:}", arg));
+        }
+        let value = iter
+            .next()
+            .ok_or_else(|| format!("{:} needs a value", arg))?
+            .clone();
+        match arg.as_ref() {
+            "--from" => range.from = Some(value),
+            "--to" => range.to = Some(value),
+            _ => {
+                let limit = value
+                    .parse()
+                    .map_err(|_| format!("invalid --limit: {:}", value))?;
+                range.limit = Some(limit);
+            }
+        }
+    }
+    Ok(range)
+}
+
+fn write_err(e: io::Error) -> String {
+    format!("failed to write: {:}", e)
+}
+
+fn get(dir: &str, args: &[String], out: &mut dyn Write) -> Result<(), String> {
+    let args = positional(args, 1)?;
+    match open_read_only(dir)?.get(&args[0]) {
+        Some(value) => writeln!(out, "{:}", String::from_utf8_lossy(&value)).map_err(write_err),
+        None => Err(format!("{:}: not found", args[0])),
+    }
+}
+
+fn put(dir: &str, args: &[String]) -> Result<(), String> {
+    let args = positional(args, 2)?;
+    open(dir)?.set(&args[0], &args[1])
+}
+
+fn delete(dir: &str, args: &[String]) -> Result<(), String> {
+    let args = positional(args, 1)?;
+    open(dir)?.delete(&args[0])
+}
+
+fn scan(dir: &str, args: &[String], out: &mut dyn Write) -> Result<(), String> {
+    let range = range_args(args, &["--from", "--to", "--limit"])?;
+    let db = open_read_only(dir)?;
+    let from = range.from.as_ref().map(|k| k.as_bytes());
+    let to = range.to.as_ref().map(|k| k.as_bytes());
+    let entries = db
+        .iter()
+        .skip_while(|(k, _)| from.map_or(false, |from| k.as_ref() < from))
+        .take_while(|(k, _)| to.map_or(true, |to| k.as_ref() < to))
+        .take(range.limit.unwrap_or(usize::MAX));
+    for (k, v) in entries {
+        writeln!(
+            out,
+            "{:} => {:}",
+            String::from_utf8_lossy(&k),
+            String::from_utf8_lossy(&v)
+        )
+        .map_err(write_err)?;
+    }
+    Ok(())
+}
+
+fn batch_load(
+    dir: &str,
+    args: &[String],
+    input: &mut dyn BufRead,
+    out: &mut dyn Write,
+) -> Result<(), String> {
+    positional(args, 0)?;
+    let db = open(dir)?;
+    let mut batch = WriteBatch::new();
+    let (mut lines, mut applied, mut pending) = (0, 0, 0);
+    for line in input.lines() {
+        let line = line.map_err(|e| format!("failed to read stdin: {:}", e))?;
+        lines += 1;
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.as_slice() {
+            ["put", key, value] => batch.put(key, value),
+            ["delete", key] => batch.delete(key),
+            [""] => continue,
+            _ => return Err(format!("line {:}: malformed: {:}", lines, line)),
+        }
+        applied += 1;
+        pending += 1;
+        if pending == BATCH_LOAD_SIZE {
+            db.apply(batch)?;
+            batch = WriteBatch::new();
+            pending = 0;
+        }
+    }
+    if pending > 0 {
+        db.apply(batch)?;
+    }
+    writeln!(out, "applied {:} operations", applied).map_err(write_err)
+}
+
+fn compact(dir: &str, args: &[String]) -> Result<(), String> {
+    let range = range_args(args, &["--from", "--to"])?;
+    let begin = range.from.as_ref().map(|k| k.as_bytes());
+    let end = range.to.as_ref().map(|k| k.as_bytes());
+    open(dir)?.compact_range(begin, end)
+}
+
+fn properties(dir: &str, args: &[String], out: &mut dyn Write) -> Result<(), String> {
+    let db = open_read_only(dir)?;
+    let mut names: Vec<String> = args.to_vec();
+    if names.is_empty() {
+        let levels = (0..)
+            .map(|level| format!("leveldb.num-files-at-level{:}", level))
+            .take_while(|name| db.property(name).is_some());
+        names.extend(levels);
+        for name in &[
+            "stats",
+            "sstables",
+            "approximate-memory-usage",
+            "total-bytes",
+        ] {
+            names.push(format!("leveldb.{:}", name));
+        }
+    }
+
+    for name in names {
+        let value = db
+            .property(&name)
+            .ok_or_else(|| format!("unknown property: {:}", name))?;
+        writeln!(out, "{:}: {:}", name, value.trim_end()).map_err(write_err)?;
+    }
+    Ok(())
+}
+
+fn repair(dir: &str, args: &[String]) -> Result<(), String> {
+    positional(args, 0)?;
+    leveldb::repair(dir, Options::default())
+}
+
+fn checksum(dir: &str, args: &[String], out: &mut dyn Write) -> Result<(), String> {
+    positional(args, 0)?;
+    let report = open_read_only(dir)?.verify_checksums();
+    for c in &report.corruptions {
+        match c.offset {
+            Some(offset) => writeln!(out, "{:} at offset {:}: {:}", c.file, offset, c.message),
+            None => writeln!(out, "{:}: {:}", c.file, c.message),
+        }
+        .map_err(write_err)?;
+    }
+    writeln!(
+        out,
+        "checked {:} tables and {:} logs",
+        report.tables, report.logs
+    )
+    .map_err(write_err)?;
+
+    if report.is_ok() {
+        Ok(())
+    } else {
+        Err(format!("found {:} corruptions", report.corruptions.len()))
+    }
+}
+
+fn approximate_size(dir: &str, args: &[String], out: &mut dyn Write) -> Result<(), String> {
+    let args = positional(args, 2)?;
+    let db = open_read_only(dir)?;
+    let range = Range {
+        start: args[0].as_bytes(),
+        limit: args[1].as_bytes(),
+    };
+    let size = db.approximate_sizes(slice::from_ref(&range), true)[0];
+    writeln!(out, "approximate size: {:} bytes", size).map_err(write_err)?;
+    match db.approximate_key_count(&range) {
+        Some(count) => writeln!(out, "approximate key count: {:}", count),
+        None => writeln!(out, "approximate key count: unknown"),
+    }
+    .map_err(write_err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    fn test_dir(name: &str) -> String {
+        let path = env::temp_dir().join(format!("leveldb_cli_test_{:}", name));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn run_command(dir: &str, args: &[&str], input: &str) -> Result<String, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let mut out = vec![];
+        run(dir, &args[0], &args[1..], &mut input.as_bytes(), &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn commands_need_existing_db() {
+        let dir = test_dir("missing");
+        for args in &[
+            &["get", "k"][..],
+            &["put", "k", "v"],
+            &["scan"],
+            &["checksum"],
+        ] {
+            let err = run_command(&dir, args, "").unwrap_err();
+            assert!(err.contains("no DB is found"), "{}", err);
+        }
+        assert!(!Path::new(&dir).exists());
+        let err = run_command(&dir, &["frobnicate"], "").unwrap_err();
+        assert_eq!(err, "unknown command: frobnicate");
+    }
+
+    #[test]
+    fn commands_read_and_write() {
+        let dir = test_dir("read_and_write");
+        drop(leveldb::open(&dir, Options::default()).unwrap());
+
+        run_command(&dir, &["put", "key1", "value1"], "").unwrap();
+        let out = run_command(&dir, &["batch-load"], "put\tkey2\tvalue2\ndelete\tkey1\n").unwrap();
+        assert_eq!(out, "applied 2 operations\n");
+
+        // Reads do not take the lock which the open DB holds
+        let db = leveldb::open(&dir, Options::default()).unwrap();
+        db.set("key3", "value3").unwrap();
+        assert_eq!(run_command(&dir, &["get", "key2"], "").unwrap(), "value2\n");
+        let out = run_command(&dir, &["scan", "--from", "key0"], "").unwrap();
+        assert_eq!(out, "key2 => value2\nkey3 => value3\n");
+        let out = run_command(&dir, &["checksum"], "").unwrap();
+        assert!(out.starts_with("checked "), "{}", out);
+        let err = run_command(&dir, &["put", "key4", "value4"], "").unwrap_err();
+        assert!(err.contains("lock"), "{}", err);
+    }
+}
@@ -0,0 +1,52 @@
+extern crate leveldb;
+
+use leveldb::DumpFormat;
+use std::env;
+use std::io::{self, Write};
+use std::process;
+
+fn usage() -> ! {
+    eprintln!("Usage: leveldb-dump [--hex | --escaped] <file>...");
+    eprintln!("  Prints the contents of log (*.log), MANIFEST-* and table (*.ldb) files");
+    eprintln!("  --hex      print keys and values as hex");
+    eprintln!("  --escaped  print keys and values as escaped strings (default)");
+    process::exit(1);
+}
+
+fn main() {
+    let mut format = DumpFormat::Escaped;
+    let mut files = vec![];
+    for arg in env::args().skip(1) {
+        match arg.as_ref() {
+            "--hex" => format = DumpFormat::Hex,
+            "--escaped" => format = DumpFormat::Escaped,
+            "-h" | "--help" => usage(),
+            _ if arg.starts_with("--") => {
+                eprintln!("unknown option: {:}", arg);
+                usage();
+            }
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        usage();
+    }
+
+    let env = leveldb::default_env();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut ok = true;
+    for fname in &files {
+        if files.len() > 1 {
+            let _ = writeln!(out, "=== {:}", fname);
+        }
+        if let Err(msg) = leveldb::dump_file(&*env, fname, format, &mut out) {
+            let _ = out.flush();
+            eprintln!("{:}: {:}", fname, msg);
+            ok = false;
+        }
+    }
+    if !ok {
+        process::exit(1);
+    }
+}
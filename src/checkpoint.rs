@@ -0,0 +1,168 @@
+use env::{self, Env};
+use filename::{self, FileType};
+
+// Files which make up the state of a DB at some point. The MANIFEST and the logs may be
+// appended after that, so only their first bytes up to the recorded sizes belong to it.
+#[derive(Clone, Debug)]
+pub struct LiveFiles {
+    pub dbname: String,
+    // Numbers and sizes of the tables in every level
+    pub tables: Vec<(u64, u64)>,
+    pub manifest_number: u64,
+    pub manifest_size: u64,
+    // Numbers and sizes of the logs which are not flushed yet, from older to newer
+    pub logs: Vec<(u64, u64)>,
+    // Sequence number of the last write in the files
+    pub last_sequence: u64,
+}
+
+// Makes target_dir a DB of the files. Tables are hard linked, or copied if they can
+// not be linked, and the MANIFEST and the logs are copied up to their recorded sizes.
+// Nothing is left in target_dir on failure.
+pub fn write_checkpoint(env: &dyn Env, files: &LiveFiles, target_dir: &str) -> Result<(), String> {
+    if env.file_exists(target_dir) {
+        return Err(format!("{:}: already exists", target_dir));
+    }
+    env.create_dir(target_dir)?;
+
+    let mut created = vec![];
+    let result = copy_live_files(env, files, target_dir, &mut created);
+    if result.is_err() {
+        for fname in created {
+            let _ = env.remove_file(&fname);
+        }
+        let _ = env.remove_dir(target_dir);
+    }
+    result
+}
+
+fn copy_live_files(
+    env: &dyn Env,
+    files: &LiveFiles,
+    target_dir: &str,
+    created: &mut Vec<String>,
+) -> Result<(), String> {
+    let dbname = &files.dbname;
+    for &(num, size) in &files.tables {
+        let src = FileType::Table(dbname, num).filename();
+        let target = FileType::Table(target_dir, num).filename();
+        env.link_file(&src, &target)
+            .or_else(|_| env::copy_file(env, &src, &target, size))?;
+        created.push(target);
+    }
+
+    let manifest_number = files.manifest_number as usize;
+    let src = FileType::Manifest(dbname, manifest_number).filename();
+    let target = FileType::Manifest(target_dir, manifest_number).filename();
+    env::copy_file(env, &src, &target, files.manifest_size)?;
+    created.push(target);
+
+    for &(num, size) in &files.logs {
+        let src = FileType::Log(dbname, num).filename();
+        let target = FileType::Log(target_dir, num).filename();
+        env::copy_file(env, &src, &target, size)?;
+        created.push(target);
+    }
+
+    created.push(FileType::Current(target_dir).filename());
+    filename::set_current_file(env, target_dir, manifest_number)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use env::{Logger, MemEnv};
+    use leveldb::open;
+    use std::fmt;
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use test_util::{mem_options, set_all};
+
+    use super::*;
+
+    #[test]
+    fn checkpoint_is_independent_db() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let db = open("/checkpoint_db", mem_options(&env)).unwrap();
+        set_all(&db, 0..100, 0);
+        db.compact_range(None, None).unwrap();
+        // Left in the log
+        set_all(&db, 50..150, 1);
+
+        db.checkpoint("/checkpoint").unwrap();
+        assert!(db.checkpoint("/checkpoint").is_err());
+
+        // Later writes and compactions of the DB do not change the checkpoint
+        set_all(&db, 0..200, 2);
+        db.compact_range(None, None).unwrap();
+
+        let copy = open("/checkpoint", mem_options(&env)).unwrap();
+        for i in 0..150 {
+            let round = if i < 50 { 0 } else { 1 };
+            assert_eq!(
+                copy.get(&format!("key{:04}", i)),
+                Some(Bytes::from(format!("value{:}-{:}", i, round)))
+            );
+        }
+        assert_eq!(copy.get("key0150"), None);
+
+        // Writes to the checkpoint do not change the DB
+        copy.set("key0000", "new").unwrap();
+        copy.compact_range(None, None).unwrap();
+        assert_eq!(db.get("key0000"), Some(Bytes::from("value0-2")));
+        assert!(db.verify_checksums().is_ok());
+    }
+
+    // Blocks the first flush after its table is written, until resumed
+    struct FlushPauser {
+        channels: Mutex<Option<(Sender<()>, Receiver<()>)>>,
+    }
+
+    impl Logger for FlushPauser {
+        fn logv(&self, args: fmt::Arguments) {
+            let message = format!("{:}", args);
+            if !message.starts_with("Level-0 table") || !message.ends_with("bytes OK") {
+                return;
+            }
+            if let Some((paused, resume)) = self.channels.lock().unwrap().take() {
+                paused.send(()).unwrap();
+                resume.recv().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn checkpoint_during_flush() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let (paused_tx, paused_rx) = mpsc::channel();
+        let (resume_tx, resume_rx) = mpsc::channel();
+        let mut options = mem_options(&env);
+        options.info_log = Some(Arc::new(FlushPauser {
+            channels: Mutex::new(Some((paused_tx, resume_rx))),
+        }));
+        let db = Arc::new(open("/checkpoint_flush_db", options).unwrap());
+        set_all(&db, 0..100, 0);
+
+        let flush = {
+            let db = db.clone();
+            thread::spawn(move || db.compact_range(None, None))
+        };
+        // The table is written but not installed yet, so it must not be deleted as obsolete
+        paused_rx.recv().unwrap();
+        db.checkpoint("/checkpoint_flush").unwrap();
+        resume_tx.send(()).unwrap();
+        flush.join().unwrap().unwrap();
+
+        assert!(db.verify_checksums().is_ok());
+        let copy = open("/checkpoint_flush", mem_options(&env)).unwrap();
+        for db in &[&*db, &copy] {
+            for i in 0..100 {
+                assert_eq!(
+                    db.get(&format!("key{:04}", i)),
+                    Some(Bytes::from(format!("value{:}-0", i)))
+                );
+            }
+        }
+    }
+}
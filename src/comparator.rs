@@ -1,17 +1,25 @@
 use byteorder::{ByteOrder, LittleEndian};
+use slice::U32_BYTE_SIZE;
 use std::cmp::Ordering;
 
-use slice::Bytes;
+pub trait Comparator: Send + Sync {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+// Lexicographic order of raw bytes
+pub struct BytewiseComparator;
 
-pub trait Comparator {
-    fn compare(&self, a: &Bytes, b: &Bytes) -> Ordering;
+impl Comparator for BytewiseComparator {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
 }
 
 pub struct InternalKeyComparator;
 
 // user key is asc. seq is desc.
 impl Comparator for InternalKeyComparator {
-    fn compare(&self, a: &Bytes, b: &Bytes) -> Ordering {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
         match extract_user_key(a).cmp(extract_user_key(b)) {
             Ordering::Equal => {
                 let a_s = a.len();
@@ -24,15 +32,37 @@ impl Comparator for InternalKeyComparator {
     }
 }
 
-fn extract_user_key<'a>(key: &'a Bytes) -> &'a [u8] {
+fn extract_user_key<'a>(key: &'a [u8]) -> &'a [u8] {
     let size = key.len();
     &key[0..size - 8]
 }
 
+// Compares length prefixed internal keys, which are used as memtable entries and table keys.
+pub struct KeyComparator(InternalKeyComparator);
+
+impl KeyComparator {
+    pub fn new() -> Self {
+        KeyComparator(InternalKeyComparator)
+    }
+}
+
+impl Comparator for KeyComparator {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.0
+            .compare(length_prefixed_slice(a), length_prefixed_slice(b))
+    }
+}
+
+fn length_prefixed_slice(v: &[u8]) -> &[u8] {
+    let size = LittleEndian::read_u32(v) as usize;
+    &v[U32_BYTE_SIZE..U32_BYTE_SIZE + size]
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use ikey::InternalKey;
+    use slice::Bytes;
 
     #[test]
     fn internal_key_comparator() {
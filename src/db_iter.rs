@@ -0,0 +1,75 @@
+use std::sync::Arc;
+
+use comparator::{Comparator, KeyComparator};
+use ikey::{InternalKey, KeyKind};
+use slice::Bytes;
+use version::{MergeingIterator, Version};
+
+pub type EntryIterator = Box<dyn Iterator<Item = (Bytes, Bytes)> + Send>;
+
+// DBIterator yields user keys and values in ascending order of keys as of a sequence number.
+// It holds the version it reads from, so its files are not deleted while it is alive.
+pub struct DBIterator {
+    inner: MergeingIterator<EntryIterator>,
+    sequence: u64,
+    last_user_key: Option<Bytes>,
+    error: Option<String>,
+    _version: Arc<Version>,
+}
+
+impl DBIterator {
+    // Each of iters yields length prefixed internal keys and values sorted by KeyComparator
+    pub fn new(iters: Vec<EntryIterator>, sequence: u64, version: Arc<Version>) -> Self {
+        DBIterator {
+            inner: MergeingIterator::with_comparator(iters, |a, b| {
+                KeyComparator::new().compare(&a.0, &b.0)
+            }),
+            sequence,
+            last_user_key: None,
+            error: None,
+            _version: version,
+        }
+    }
+
+    // Returns an iterator which yields nothing, for a version whose files can not be read
+    pub fn with_error(msg: String, version: Arc<Version>) -> Self {
+        let mut iter = DBIterator::new(vec![], 0, version);
+        iter.error = Some(msg);
+        iter
+    }
+
+    // Returns the error which stopped the iteration, if any
+    pub fn status(&self) -> Result<(), String> {
+        match self.error {
+            Some(ref msg) => Err(msg.clone()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Iterator for DBIterator {
+    type Item = (Bytes, Bytes);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (key, value) in self.inner.by_ref() {
+            let ikey = InternalKey::from(key);
+            if ikey.seq_number() as u64 > self.sequence {
+                // Written after this iterator was created
+                continue;
+            }
+
+            // Newer entries of a user key come first, so the rest are hidden by it
+            let user_key = ikey.user_key();
+            if self.last_user_key.as_ref() == Some(&user_key) {
+                continue;
+            }
+            self.last_user_key = Some(user_key.clone());
+
+            if ikey.kind() == KeyKind::Value {
+                return Some((user_key, value));
+            }
+        }
+
+        None
+    }
+}
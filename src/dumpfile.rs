@@ -0,0 +1,367 @@
+use bytes::Bytes;
+use std::io::Write;
+use std::sync::Arc;
+
+use batch::{Handler, WriteBatch};
+use comparator::KeyComparator;
+use env::{Env, EnvOptions};
+use filename::FileType;
+use ikey::{InternalKey, KeyKind};
+use log_record::LogReader;
+use table::{BlockHandle, Footer, Table};
+use version::{FileMetaData, VersionEdit};
+
+// How keys and values are printed by dump_file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DumpFormat {
+    // Quoted, with printable ASCII as it is and other bytes as \xNN
+    Escaped,
+    // Two hex digits per byte
+    Hex,
+}
+
+// Prints the records of a log or a manifest, or the entries of a table to dst in a human
+// readable form. The type of the file is detected by its name.
+pub fn dump_file(
+    env: &dyn Env,
+    fname: &str,
+    format: DumpFormat,
+    dst: &mut dyn Write,
+) -> Result<(), String> {
+    // parse_name takes a path in a DB directory, while only the base name tells the type
+    let base = fname.rsplit('/').next().unwrap_or(fname);
+    let path = format!("db/{:}", base);
+    let dumper = Dumper { format, dst };
+    match FileType::parse_name(&path) {
+        Some(FileType::Log(_, _)) => dumper.dump_log(env, fname),
+        Some(FileType::Manifest(_, _)) => dumper.dump_manifest(env, fname),
+        Some(FileType::Table(_, _)) => dumper.dump_table(env, fname),
+        _ => Err("unknown file type, expected a log, a manifest or a table".to_owned()),
+    }
+}
+
+struct Dumper<'a> {
+    format: DumpFormat,
+    dst: &'a mut dyn Write,
+}
+
+impl<'a> Dumper<'a> {
+    fn dump_log(mut self, env: &dyn Env, fname: &str) -> Result<(), String> {
+        let reader = env.new_sequential_file(fname).map(LogReader::new)?;
+        for (i, record) in reader.enumerate() {
+            let mut s = format!("--- record {:}; ", i);
+            match WriteBatch::load_data(record) {
+                Ok(batch) => {
+                    s.push_str(&format!(
+                        "sequence {:}; count {:}\n",
+                        batch.seq(),
+                        batch.count()
+                    ));
+                    let result = {
+                        let mut printer = BatchPrinter {
+                            format: self.format,
+                            seq: batch.seq() as u64,
+                            out: &mut s,
+                        };
+                        batch.iterate(&mut printer)
+                    };
+                    if let Err(msg) = result {
+                        s.push_str(&format!("  error: {:}\n", msg));
+                    }
+                }
+                Err(msg) => s.push_str(&format!("error: {:}\n", msg)),
+            }
+            self.write(&s)?;
+        }
+        Ok(())
+    }
+
+    fn dump_manifest(mut self, env: &dyn Env, fname: &str) -> Result<(), String> {
+        let reader = env.new_sequential_file(fname).map(LogReader::new)?;
+        for (i, record) in reader.enumerate() {
+            let mut edit = VersionEdit::new(0);
+            if let Err(msg) = edit.try_decode_from(record) {
+                self.write(&format!("--- corrupted record {:}: {:}\n", i, msg))?;
+                continue;
+            }
+
+            let mut s = format!("--- record {:}\n", i);
+            if edit.log_number != 0 {
+                s.push_str(&format!("  log number: {:}\n", edit.log_number));
+            }
+            if edit.prev_log_number != 0 {
+                s.push_str(&format!("  prev log number: {:}\n", edit.prev_log_number));
+            }
+            if edit.next_file_number != 0 {
+                s.push_str(&format!("  next file number: {:}\n", edit.next_file_number));
+            }
+            if edit.last_sequence != 0 {
+                s.push_str(&format!("  last sequence: {:}\n", edit.last_sequence));
+            }
+            for f in &edit.deleted_files {
+                s.push_str(&format!("  delete file: {:}\n", self.file_string(f)));
+            }
+            for f in &edit.files {
+                s.push_str(&format!("  add file: {:}\n", self.file_string(f)));
+            }
+            self.write(&s)?;
+        }
+        Ok(())
+    }
+
+    fn dump_table(mut self, env: &dyn Env, fname: &str) -> Result<(), String> {
+        let size = env.get_file_size(fname)?;
+        let file = env.new_random_access_file(fname, &EnvOptions::default())?;
+        let table = Table::open(size as usize, file, Arc::new(KeyComparator::new()))?;
+
+        let mut s = footer_string(table.footer());
+        if let Some(n) = table.num_entries() {
+            s.push_str(&format!("entries: {:}\n", n));
+        }
+        s.push_str("--- index\n");
+        for (k, handle) in table.index_entries() {
+            s.push_str(&format!(
+                "  {:} => {:}\n",
+                self.ikey_string(&k),
+                handle_string(&handle)
+            ));
+        }
+        s.push_str("--- data\n");
+        self.write(&s)?;
+
+        let mut result = Ok(());
+        let scanned = table.scan_checked(|k, v| {
+            if result.is_ok() {
+                let line = format!(
+                    "  {:} => {:}\n",
+                    self.ikey_string(&k),
+                    self.bytes_string(&v)
+                );
+                result = self.write(&line);
+            }
+        });
+        result?;
+        scanned
+    }
+
+    fn write(&mut self, s: &str) -> Result<(), String> {
+        self.dst
+            .write_all(s.as_bytes())
+            .map_err(|e| format!("failed to write: {:}", e))
+    }
+
+    fn bytes_string(&self, b: &[u8]) -> String {
+        format_bytes(self.format, b)
+    }
+
+    // Formats an internal key as user_key @ seq : kind, or as raw bytes if it is malformed
+    fn ikey_string(&self, key: &Bytes) -> String {
+        let ikey = InternalKey::from(key.clone());
+        if !ikey.is_valid() {
+            return format!("{:} (malformed key)", self.bytes_string(key));
+        }
+        format!(
+            "{:} @ {:} : {:}",
+            self.bytes_string(&ikey.user_key()),
+            ikey.seq_number(),
+            kind_string(ikey.kind())
+        )
+    }
+
+    fn file_string(&self, f: &FileMetaData) -> String {
+        format!(
+            "level {:} #{:} {:} bytes [{:} .. {:}]",
+            f.level,
+            f.file_num,
+            f.file_size,
+            self.ikey_string(&f.smallest.inner()),
+            self.ikey_string(&f.largest.inner())
+        )
+    }
+}
+
+struct BatchPrinter<'a> {
+    format: DumpFormat,
+    seq: u64,
+    out: &'a mut String,
+}
+
+impl<'a> Handler for BatchPrinter<'a> {
+    fn put(&mut self, key: &Bytes, value: &Bytes) {
+        self.out.push_str(&format!(
+            "  put @ {:} {:} => {:}\n",
+            self.seq,
+            format_bytes(self.format, key),
+            format_bytes(self.format, value)
+        ));
+        self.seq += 1;
+    }
+
+    fn delete(&mut self, key: &Bytes) {
+        self.out.push_str(&format!(
+            "  del @ {:} {:}\n",
+            self.seq,
+            format_bytes(self.format, key)
+        ));
+        self.seq += 1;
+    }
+}
+
+fn format_bytes(format: DumpFormat, b: &[u8]) -> String {
+    match format {
+        DumpFormat::Escaped => {
+            let mut s = String::from("'");
+            for &c in b {
+                if (b' '..=b'~').contains(&c) && c != b'\'' && c != b'\\' {
+                    s.push(c as char);
+                } else {
+                    s.push_str(&format!("\\x{:02x}", c));
+                }
+            }
+            s.push('\'');
+            s
+        }
+        DumpFormat::Hex => b.iter().map(|c| format!("{:02x}", c)).collect(),
+    }
+}
+
+fn kind_string(kind: KeyKind) -> &'static str {
+    match kind {
+        KeyKind::Value => "put",
+        KeyKind::Delete => "del",
+    }
+}
+
+fn footer_string(footer: &Footer) -> String {
+    format!(
+        "footer: index block {:}; metaindex block {:}\n",
+        handle_string(&footer.index_block_handle),
+        handle_string(&footer.metaindex_block_handle)
+    )
+}
+
+fn handle_string(handle: &BlockHandle) -> String {
+    format!("offset={:} size={:}", handle.offset(), handle.size())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use env::MemEnv;
+    use leveldb::open;
+    use log_record::LogWriter;
+    use options::Options;
+
+    fn dump(env: &dyn Env, fname: &str, format: DumpFormat) -> String {
+        let mut out = vec![];
+        dump_file(env, fname, format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn dump_file_of_each_type() {
+        let dbname = "/dumpfile_test";
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let mut options = Options::default();
+        options.env = env.clone();
+        {
+            let db = open(dbname, options).unwrap();
+            db.set("key1", "value1").unwrap();
+            db.set("key2", "value\n2").unwrap();
+            db.compact_range(None, None).unwrap();
+            db.set("key3", "value3").unwrap();
+            db.delete("key1").unwrap();
+        }
+
+        let mut names = env.get_children(dbname).unwrap();
+        names.sort();
+        let path = |suffix: &str| {
+            let name = names.iter().find(|name| name.contains(suffix)).unwrap();
+            format!("{:}/{:}", dbname, name)
+        };
+
+        let log = dump(&*env, &path(".log"), DumpFormat::Escaped);
+        assert!(
+            log.contains("--- record 0; sequence 3; count 1\n  put @ 3 'key3' => 'value3'\n"),
+            "{}",
+            log
+        );
+        assert!(
+            log.contains("--- record 1; sequence 4; count 1\n  del @ 4 'key1'\n"),
+            "{}",
+            log
+        );
+
+        let table = dump(&*env, &path(".ldb"), DumpFormat::Escaped);
+        assert!(
+            table.starts_with("footer: index block offset="),
+            "{}",
+            table
+        );
+        assert!(table.contains("entries: 2\n"), "{}", table);
+        assert!(
+            table.contains("--- index\n  'key2' @ 2 : put => offset=0 size="),
+            "{}",
+            table
+        );
+        assert!(
+            table.contains(
+                "--- data\n  'key1' @ 1 : put => 'value1'\n  'key2' @ 2 : put => 'value\\x0a2'\n"
+            ),
+            "{}",
+            table
+        );
+
+        let manifest = dump(&*env, &path("MANIFEST-"), DumpFormat::Escaped);
+        assert!(manifest.contains("  add file: level "), "{}", manifest);
+        assert!(
+            manifest.contains(" bytes ['key1' @ 1 : put .. 'key2' @ 2 : put]\n"),
+            "{}",
+            manifest
+        );
+
+        let hex = dump(&*env, &path(".log"), DumpFormat::Hex);
+        assert!(
+            hex.contains("  put @ 3 6b657933 => 76616c756533\n"),
+            "{}",
+            hex
+        );
+
+        let mut out = vec![];
+        let err = dump_file(&*env, &path("CURRENT"), DumpFormat::Hex, &mut out).unwrap_err();
+        assert!(err.contains("unknown file type"), "{}", err);
+    }
+
+    #[test]
+    fn dump_corrupted_manifest() {
+        let fname = "/dumpfile_corrupted/MANIFEST-0000002";
+        let env = MemEnv::new();
+        env.create_dir("/dumpfile_corrupted").unwrap();
+        {
+            let mut writer = LogWriter::new(env.new_writable_file(fname).unwrap());
+            writer.add_record(Bytes::from(vec![2, 1, 0])).unwrap();
+            // A key shorter than a sequence number and a kind
+            let mut edit = VersionEdit::new(5);
+            edit.add_file(FileMetaData {
+                file_num: 4,
+                file_size: 100,
+                smallest: InternalKey::from(Bytes::from("abc")),
+                largest: InternalKey::new(b"xyz", 3),
+                level: 0,
+            });
+            edit.encode_to(&mut writer).unwrap();
+        }
+
+        let manifest = dump(&env, fname, DumpFormat::Escaped);
+        assert!(
+            manifest.contains("--- corrupted record 0: record is truncated\n"),
+            "{}",
+            manifest
+        );
+        assert!(
+            manifest.contains(" bytes ['abc' (malformed key) .. 'xyz' @ 3 : put]\n"),
+            "{}",
+            manifest
+        );
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use super::{Env, EnvOptions, FileLock, RandomAccessFile, SequentialFile, WritableFile};
+use slice::Bytes;
+
+// Operations which FaultInjectionEnv can make fail
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FaultOp {
+    Write,
+    Sync,
+    Rename,
+    Read,
+}
+
+// Length of a file written through FaultInjectionEnv, and how much of it is durable
+#[derive(Clone, Copy, Debug, Default)]
+struct FileState {
+    pos: u64,
+    pos_at_last_sync: u64,
+}
+
+#[derive(Default)]
+struct FaultState {
+    files: HashMap<String, FileState>,
+    failing: HashSet<FaultOp>,
+    // Number of failing operations which still succeed. None means they fail right away.
+    ops_before_failure: Option<usize>,
+}
+
+impl FaultState {
+    fn check(&mut self, op: FaultOp, fname: &str) -> Result<(), String> {
+        if !self.failing.contains(&op) {
+            return Ok(());
+        }
+
+        match self.ops_before_failure {
+            Some(ref mut n) if *n > 0 => {
+                *n -= 1;
+                Ok(())
+            }
+            _ => Err(format!("{:?} {:}: injected failure", op, fname)),
+        }
+    }
+}
+
+// FaultInjectionEnv wraps another Env to test crash consistency. It remembers how much of
+// each file it wrote has been synced, so drop_unsynced_data can simulate a power loss.
+// It also makes chosen operations fail, right away or after some of them succeed.
+// Directory entries, such as created, renamed and removed files, are regarded as durable.
+pub struct FaultInjectionEnv {
+    target: Arc<dyn Env>,
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl FaultInjectionEnv {
+    pub fn new(target: Arc<dyn Env>) -> Self {
+        FaultInjectionEnv {
+            target,
+            state: Arc::new(Mutex::new(FaultState::default())),
+        }
+    }
+
+    // Makes every op fail from now on if fail is true
+    pub fn set_failure(&self, op: FaultOp, fail: bool) {
+        let mut state = self.state.lock().unwrap();
+        if fail {
+            state.failing.insert(op);
+        } else {
+            state.failing.remove(&op);
+        }
+    }
+
+    // Lets n more operations of the kinds set by set_failure succeed before they fail
+    pub fn fail_after(&self, n: usize) {
+        self.state.lock().unwrap().ops_before_failure = Some(n);
+    }
+
+    // Makes every operation succeed again
+    pub fn reset_failures(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failing.clear();
+        state.ops_before_failure = None;
+    }
+
+    // Truncates every file to the length at its last sync, as a power loss does.
+    // Files must not be written meanwhile, so close the DB beforehand.
+    pub fn drop_unsynced_data(&self) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        for (fname, file) in state.files.iter_mut() {
+            if file.pos_at_last_sync < file.pos {
+                truncate(&*self.target, fname, file.pos_at_last_sync)?;
+                file.pos = file.pos_at_last_sync;
+            }
+        }
+        Ok(())
+    }
+
+    fn check(&self, op: FaultOp, fname: &str) -> Result<(), String> {
+        self.state.lock().unwrap().check(op, fname)
+    }
+
+    fn wrap_writable_file(
+        &self,
+        fname: &str,
+        inner: Box<dyn WritableFile>,
+    ) -> Box<dyn WritableFile> {
+        Box::new(FaultWritableFile {
+            fname: fname.to_owned(),
+            inner,
+            state: self.state.clone(),
+        })
+    }
+}
+
+// Env has no way to truncate a file, so the kept part is written to a new file
+fn truncate(env: &dyn Env, fname: &str, len: u64) -> Result<(), String> {
+    let mut data = vec![];
+    env.new_sequential_file(fname)?
+        .read_to_end(&mut data)
+        .map_err(|e| format!("failed to read {:}: {:}", fname, e))?;
+    data.truncate(len as usize);
+
+    let mut file = env.new_writable_file(fname)?;
+    file.write_all(&data)
+        .map_err(|e| format!("failed to write {:}: {:}", fname, e))?;
+    file.sync()
+}
+
+impl Env for FaultInjectionEnv {
+    fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, String> {
+        self.check(FaultOp::Read, fname)?;
+        let inner = self.target.new_sequential_file(fname)?;
+        Ok(Box::new(FaultSequentialFile {
+            fname: fname.to_owned(),
+            inner,
+            state: self.state.clone(),
+        }))
+    }
+
+    fn new_random_access_file(
+        &self,
+        fname: &str,
+        options: &EnvOptions,
+    ) -> Result<Box<dyn RandomAccessFile>, String> {
+        self.check(FaultOp::Read, fname)?;
+        let inner = self.target.new_random_access_file(fname, options)?;
+        Ok(Box::new(FaultRandomAccessFile {
+            fname: fname.to_owned(),
+            inner,
+            state: self.state.clone(),
+        }))
+    }
+
+    fn new_writable_file(&self, fname: &str) -> Result<Box<dyn WritableFile>, String> {
+        let inner = self.target.new_writable_file(fname)?;
+        self.state
+            .lock()
+            .unwrap()
+            .files
+            .insert(fname.to_owned(), FileState::default());
+        Ok(self.wrap_writable_file(fname, inner))
+    }
+
+    fn new_appendable_file(&self, fname: &str) -> Result<Box<dyn WritableFile>, String> {
+        let inner = self.target.new_appendable_file(fname)?;
+        let size = self.target.get_file_size(fname)?;
+        // Data which already exists is regarded as synced
+        self.state
+            .lock()
+            .unwrap()
+            .files
+            .entry(fname.to_owned())
+            .or_insert(FileState {
+                pos: size,
+                pos_at_last_sync: size,
+            });
+        Ok(self.wrap_writable_file(fname, inner))
+    }
+
+    fn file_exists(&self, fname: &str) -> bool {
+        self.target.file_exists(fname)
+    }
+
+    fn get_children(&self, dir: &str) -> Result<Vec<String>, String> {
+        self.target.get_children(dir)
+    }
+
+    fn remove_file(&self, fname: &str) -> Result<(), String> {
+        self.target.remove_file(fname)?;
+        self.state.lock().unwrap().files.remove(fname);
+        Ok(())
+    }
+
+    fn create_dir(&self, dirname: &str) -> Result<(), String> {
+        self.target.create_dir(dirname)
+    }
+
+    fn remove_dir(&self, dirname: &str) -> Result<(), String> {
+        self.target.remove_dir(dirname)
+    }
+
+    fn get_file_size(&self, fname: &str) -> Result<u64, String> {
+        self.target.get_file_size(fname)
+    }
+
+    fn get_file_modification_time(&self, fname: &str) -> Result<u64, String> {
+        self.target.get_file_modification_time(fname)
+    }
+
+    fn rename_file(&self, src: &str, target: &str) -> Result<(), String> {
+        self.check(FaultOp::Rename, src)?;
+        self.target.rename_file(src, target)?;
+
+        let mut state = self.state.lock().unwrap();
+        match state.files.remove(src) {
+            Some(file) => state.files.insert(target.to_owned(), file),
+            None => state.files.remove(target),
+        };
+        Ok(())
+    }
+
+    // The link is treated as synced, so it should be made to a synced file
+    fn link_file(&self, src: &str, target: &str) -> Result<(), String> {
+        self.target.link_file(src, target)
+    }
+
+    fn lock_file(&self, fname: &str) -> Result<Box<dyn FileLock>, String> {
+        self.target.lock_file(fname)
+    }
+
+    fn unlock_file(&self, lock: Box<dyn FileLock>) -> Result<(), String> {
+        self.target.unlock_file(lock)
+    }
+
+    fn schedule(&self, f: Box<dyn FnOnce() + Send>) {
+        self.target.schedule(f)
+    }
+
+    fn start_thread(&self, f: Box<dyn FnOnce() + Send>) {
+        self.target.start_thread(f)
+    }
+
+    fn now_micros(&self) -> u64 {
+        self.target.now_micros()
+    }
+
+    fn sleep_for_microseconds(&self, micros: u64) {
+        self.target.sleep_for_microseconds(micros)
+    }
+}
+
+fn injected_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+struct FaultSequentialFile {
+    fname: String,
+    inner: Box<dyn SequentialFile>,
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl Read for FaultSequentialFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.state
+            .lock()
+            .unwrap()
+            .check(FaultOp::Read, &self.fname)
+            .map_err(injected_error)?;
+        self.inner.read(buf)
+    }
+}
+
+struct FaultRandomAccessFile {
+    fname: String,
+    inner: Box<dyn RandomAccessFile>,
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl RandomAccessFile for FaultRandomAccessFile {
+    fn read(&self, offset: usize, size: usize) -> Result<Bytes, String> {
+        self.state
+            .lock()
+            .unwrap()
+            .check(FaultOp::Read, &self.fname)?;
+        self.inner.read(offset, size)
+    }
+}
+
+struct FaultWritableFile {
+    fname: String,
+    inner: Box<dyn WritableFile>,
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl Write for FaultWritableFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .check(FaultOp::Write, &self.fname)
+            .map_err(injected_error)?;
+
+        let n = self.inner.write(buf)?;
+        if let Some(file) = state.files.get_mut(&self.fname) {
+            file.pos += n as u64;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl WritableFile for FaultWritableFile {
+    fn sync(&mut self) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        state.check(FaultOp::Sync, &self.fname)?;
+
+        self.inner.sync()?;
+        if let Some(file) = state.files.get_mut(&self.fname) {
+            file.pos_at_last_sync = file.pos;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{read_file_to_string, write_string_to_file, MemEnv};
+    use super::*;
+
+    fn fault_env() -> FaultInjectionEnv {
+        FaultInjectionEnv::new(Arc::new(MemEnv::new()))
+    }
+
+    #[test]
+    fn fault_injection_env_drop_unsynced_data() {
+        let env = fault_env();
+        env.create_dir("/dir").unwrap();
+
+        let mut f = env.new_writable_file("/dir/a").unwrap();
+        f.write_all(b"synced").unwrap();
+        f.sync().unwrap();
+        f.write_all(b" unsynced").unwrap();
+        f.flush().unwrap();
+        drop(f);
+        write_string_to_file(&env, b"tmp", "/dir/b", false).unwrap();
+        write_string_to_file(&env, b"current", "/dir/c.tmp", true).unwrap();
+        env.rename_file("/dir/c.tmp", "/dir/c").unwrap();
+        assert_eq!(read_file_to_string(&env, "/dir/a").unwrap(), "synced unsynced");
+
+        env.drop_unsynced_data().unwrap();
+        assert_eq!(read_file_to_string(&env, "/dir/a").unwrap(), "synced");
+        assert_eq!(read_file_to_string(&env, "/dir/b").unwrap(), "");
+        assert_eq!(read_file_to_string(&env, "/dir/c").unwrap(), "current");
+
+        // Existing data of an appended file is kept
+        let mut f = env.new_appendable_file("/dir/a").unwrap();
+        f.write_all(b" appended").unwrap();
+        drop(f);
+        env.drop_unsynced_data().unwrap();
+        assert_eq!(read_file_to_string(&env, "/dir/a").unwrap(), "synced");
+    }
+
+    #[test]
+    fn fault_injection_env_failures() {
+        let env = fault_env();
+        let mut f = env.new_writable_file("/a").unwrap();
+
+        env.set_failure(FaultOp::Write, true);
+        assert!(f.write_all(b"data").is_err());
+        env.set_failure(FaultOp::Write, false);
+        f.write_all(b"data").unwrap();
+
+        env.set_failure(FaultOp::Sync, true);
+        assert!(f.sync().is_err());
+        env.set_failure(FaultOp::Rename, true);
+        assert!(env.rename_file("/a", "/b").is_err());
+        env.set_failure(FaultOp::Read, true);
+        assert!(env.new_sequential_file("/a").is_err());
+        assert!(env.new_random_access_file("/a", &EnvOptions::default())
+            .is_err());
+
+        env.reset_failures();
+        f.sync().unwrap();
+        let r = env.new_random_access_file("/a", &EnvOptions::default())
+            .unwrap();
+        env.set_failure(FaultOp::Read, true);
+        assert!(r.read(0, 4).is_err());
+        env.reset_failures();
+        assert_eq!(r.read(0, 4).unwrap(), Bytes::from("data"));
+    }
+
+    #[test]
+    fn fault_injection_env_fail_after() {
+        let env = fault_env();
+        let mut f = env.new_writable_file("/a").unwrap();
+
+        env.set_failure(FaultOp::Write, true);
+        env.fail_after(2);
+        f.write_all(b"1").unwrap();
+        // Operations which are not chosen are not counted
+        f.sync().unwrap();
+        f.write_all(b"2").unwrap();
+        assert!(f.write_all(b"3").is_err());
+        assert!(f.write_all(b"4").is_err());
+
+        env.reset_failures();
+        f.write_all(b"5").unwrap();
+        assert_eq!(read_file_to_string(&env, "/a").unwrap(), "125");
+    }
+}
@@ -0,0 +1,114 @@
+use std::fmt;
+use std::io::Write;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{Logger, WritableFile};
+
+// FileLogger writes each message in a line with the time and the thread
+pub struct FileLogger {
+    file: Mutex<Box<dyn WritableFile>>,
+}
+
+impl FileLogger {
+    pub fn new(file: Box<dyn WritableFile>) -> Self {
+        FileLogger {
+            file: Mutex::new(file),
+        }
+    }
+}
+
+impl Logger for FileLogger {
+    fn logv(&self, args: fmt::Arguments) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_else(|_| Duration::from_secs(0));
+        let line = format!(
+            "{:} {:?} {:}\n",
+            format_time(now),
+            thread::current().id(),
+            args
+        );
+
+        // A failure of logging must not fail the DB, so it is ignored
+        let mut file = self.file.lock().unwrap();
+        let _ = file.write_all(line.as_bytes()).and_then(|_| file.flush());
+    }
+}
+
+// NullLogger drops every message
+pub struct NullLogger;
+
+impl Logger for NullLogger {
+    fn logv(&self, _: fmt::Arguments) {}
+}
+
+// Formats d since the epoch as "YYYY/MM/DD-hh:mm:ss.uuuuuu" in UTC
+fn format_time(d: Duration) -> String {
+    let secs = d.as_secs();
+    let (year, month, day) = civil_from_days(secs / 86400);
+    let rem = secs % 86400;
+    format!(
+        "{:04}/{:02}/{:02}-{:02}:{:02}:{:02}.{:06}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        d.subsec_micros()
+    )
+}
+
+// Converts days since 1970-01-01 to a date of the Gregorian calendar.
+// See http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Count from 0000-03-01 so that a leap day is the last day of a year
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{read_file_to_string, Env, MemEnv};
+    use super::*;
+
+    #[test]
+    fn file_logger_format_time() {
+        assert_eq!(
+            format_time(Duration::from_secs(0)),
+            "1970/01/01-00:00:00.000000"
+        );
+        assert_eq!(
+            format_time(Duration::from_secs(951_782_400)),
+            "2000/02/29-00:00:00.000000"
+        );
+        assert_eq!(
+            format_time(Duration::new(1_000_000_000, 500_000_000)),
+            "2001/09/09-01:46:40.500000"
+        );
+    }
+
+    #[test]
+    fn file_logger_writes_lines() {
+        let env = MemEnv::new();
+        let logger = env.new_logger("/LOG").unwrap();
+        info_log!(logger, "first {:}", 1);
+        info_log!(logger, "second");
+
+        let content = read_file_to_string(&env, "/LOG").unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" first 1"));
+        assert!(lines[1].ends_with(" second"));
+    }
+}
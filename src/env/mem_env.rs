@@ -0,0 +1,398 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use super::{default_env, Env, EnvOptions, FileLock, RandomAccessFile, SequentialFile,
+            WritableFile};
+use slice::Bytes;
+
+type FileState = Arc<Mutex<MemFile>>;
+
+struct MemFile {
+    data: Vec<u8>,
+    // Time of the last write in microseconds since the epoch
+    modified_micros: u64,
+}
+
+// MemEnv keeps all files in memory. Threads and the clock are delegated to base.
+// A removed or renamed file is still readable by handles which were opened before.
+// Directories behave as on disk: files are created only in existing directories, and only
+// empty directories are removed.
+pub struct MemEnv {
+    files: Mutex<HashMap<String, FileState>>,
+    dirs: Mutex<HashSet<String>>,
+    locks: Arc<Mutex<HashSet<String>>>,
+    base: Arc<dyn Env>,
+}
+
+impl MemEnv {
+    pub fn new() -> Self {
+        MemEnv::with_base(default_env())
+    }
+
+    pub fn with_base(base: Arc<dyn Env>) -> Self {
+        MemEnv {
+            files: Mutex::new(HashMap::new()),
+            dirs: Mutex::new(HashSet::new()),
+            locks: Arc::new(Mutex::new(HashSet::new())),
+            base,
+        }
+    }
+
+    fn find_file(&self, fname: &str) -> Result<FileState, String> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(fname)
+            .cloned()
+            .ok_or_else(|| format!("{:}: file not found", fname))
+    }
+
+    fn new_file(&self) -> FileState {
+        Arc::new(Mutex::new(MemFile {
+            data: vec![],
+            modified_micros: self.base.now_micros(),
+        }))
+    }
+
+    // Fails unless the directory of fname exists. The root and the current directory always
+    // exist.
+    fn check_parent_dir(&self, fname: &str) -> Result<(), String> {
+        match fname.rfind('/') {
+            Some(0) | None => Ok(()),
+            Some(i) if self.dirs.lock().unwrap().contains(&fname[..i]) => Ok(()),
+            Some(i) => Err(format!("{:}: directory not found", &fname[..i])),
+        }
+    }
+
+    fn writable_file(&self, file: FileState) -> Box<dyn WritableFile> {
+        Box::new(MemWritableFile {
+            file,
+            clock: self.base.clone(),
+        })
+    }
+}
+
+impl Default for MemEnv {
+    fn default() -> Self {
+        MemEnv::new()
+    }
+}
+
+impl Env for MemEnv {
+    fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, String> {
+        let file = self.find_file(fname)?;
+        Ok(Box::new(MemSequentialFile { file, pos: 0 }))
+    }
+
+    fn new_random_access_file(
+        &self,
+        fname: &str,
+        _: &EnvOptions,
+    ) -> Result<Box<dyn RandomAccessFile>, String> {
+        let file = self.find_file(fname)?;
+        Ok(Box::new(MemRandomAccessFile { file }))
+    }
+
+    fn new_writable_file(&self, fname: &str) -> Result<Box<dyn WritableFile>, String> {
+        self.check_parent_dir(fname)?;
+        let file = self.new_file();
+        self.files
+            .lock()
+            .unwrap()
+            .insert(fname.to_owned(), file.clone());
+        Ok(self.writable_file(file))
+    }
+
+    fn new_appendable_file(&self, fname: &str) -> Result<Box<dyn WritableFile>, String> {
+        self.check_parent_dir(fname)?;
+        let file = self.files
+            .lock()
+            .unwrap()
+            .entry(fname.to_owned())
+            .or_insert_with(|| self.new_file())
+            .clone();
+        Ok(self.writable_file(file))
+    }
+
+    fn file_exists(&self, fname: &str) -> bool {
+        self.files.lock().unwrap().contains_key(fname) || self.dirs.lock().unwrap().contains(fname)
+    }
+
+    fn get_children(&self, dir: &str) -> Result<Vec<String>, String> {
+        // Locked in the same order as file_exists
+        let files = self.files.lock().unwrap();
+        let dirs = self.dirs.lock().unwrap();
+        if !dirs.contains(dir) {
+            return Err(format!("{:}: directory not found", dir));
+        }
+        let prefix = format!("{:}/", dir);
+        Ok(files
+            .keys()
+            .chain(dirs.iter())
+            .filter(|name| name.starts_with(&prefix) && !name[prefix.len()..].contains('/'))
+            .map(|name| name[prefix.len()..].to_owned())
+            .collect())
+    }
+
+    fn remove_file(&self, fname: &str) -> Result<(), String> {
+        self.files
+            .lock()
+            .unwrap()
+            .remove(fname)
+            .map(|_| ())
+            .ok_or_else(|| format!("{:}: file not found", fname))
+    }
+
+    fn create_dir(&self, dirname: &str) -> Result<(), String> {
+        self.check_parent_dir(dirname)?;
+        if self.file_exists(dirname) {
+            return Err(format!("{:}: file exists", dirname));
+        }
+        self.dirs.lock().unwrap().insert(dirname.to_owned());
+        Ok(())
+    }
+
+    fn remove_dir(&self, dirname: &str) -> Result<(), String> {
+        if !self.get_children(dirname)?.is_empty() {
+            return Err(format!("{:}: directory not empty", dirname));
+        }
+        self.dirs.lock().unwrap().remove(dirname);
+        Ok(())
+    }
+
+    fn get_file_size(&self, fname: &str) -> Result<u64, String> {
+        self.find_file(fname)
+            .map(|f| f.lock().unwrap().data.len() as u64)
+    }
+
+    fn get_file_modification_time(&self, fname: &str) -> Result<u64, String> {
+        self.find_file(fname)
+            .map(|f| f.lock().unwrap().modified_micros)
+    }
+
+    fn rename_file(&self, src: &str, target: &str) -> Result<(), String> {
+        self.check_parent_dir(target)?;
+        let mut files = self.files.lock().unwrap();
+        let file = files
+            .remove(src)
+            .ok_or_else(|| format!("{:}: file not found", src))?;
+        files.insert(target.to_owned(), file);
+        Ok(())
+    }
+
+    fn link_file(&self, src: &str, target: &str) -> Result<(), String> {
+        self.check_parent_dir(target)?;
+        let mut files = self.files.lock().unwrap();
+        if files.contains_key(target) {
+            return Err(format!("{:}: file exists", target));
+        }
+        let file = files
+            .get(src)
+            .cloned()
+            .ok_or_else(|| format!("{:}: file not found", src))?;
+        files.insert(target.to_owned(), file);
+        Ok(())
+    }
+
+    fn lock_file(&self, fname: &str) -> Result<Box<dyn FileLock>, String> {
+        if !self.locks.lock().unwrap().insert(fname.to_owned()) {
+            return Err(format!("lock {:}: already held", fname));
+        }
+        Ok(Box::new(MemFileLock {
+            fname: fname.to_owned(),
+            locks: self.locks.clone(),
+        }))
+    }
+
+    fn unlock_file(&self, lock: Box<dyn FileLock>) -> Result<(), String> {
+        drop(lock);
+        Ok(())
+    }
+
+    fn schedule(&self, f: Box<dyn FnOnce() + Send>) {
+        self.base.schedule(f)
+    }
+
+    fn start_thread(&self, f: Box<dyn FnOnce() + Send>) {
+        self.base.start_thread(f)
+    }
+
+    fn now_micros(&self) -> u64 {
+        self.base.now_micros()
+    }
+
+    fn sleep_for_microseconds(&self, micros: u64) {
+        self.base.sleep_for_microseconds(micros)
+    }
+}
+
+struct MemSequentialFile {
+    file: FileState,
+    pos: usize,
+}
+
+impl Read for MemSequentialFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = &self.file.lock().unwrap().data;
+        let n = buf.len().min(data.len().saturating_sub(self.pos));
+        buf[..n].copy_from_slice(&data[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+struct MemRandomAccessFile {
+    file: FileState,
+}
+
+impl RandomAccessFile for MemRandomAccessFile {
+    fn read(&self, offset: usize, size: usize) -> Result<Bytes, String> {
+        let data = &self.file.lock().unwrap().data;
+        if offset + size > data.len() {
+            Err("invalid index".to_owned())
+        } else {
+            Ok(Bytes::from(&data[offset..offset + size]))
+        }
+    }
+}
+
+struct MemWritableFile {
+    file: FileState,
+    clock: Arc<dyn Env>,
+}
+
+impl Write for MemWritableFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut file = self.file.lock().unwrap();
+        file.data.extend_from_slice(buf);
+        file.modified_micros = self.clock.now_micros();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WritableFile for MemWritableFile {
+    fn sync(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+struct MemFileLock {
+    fname: String,
+    locks: Arc<Mutex<HashSet<String>>>,
+}
+
+impl FileLock for MemFileLock {}
+
+impl Drop for MemFileLock {
+    fn drop(&mut self) {
+        self.locks.lock().unwrap().remove(&self.fname);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{copy_file, read_file_to_string, write_string_to_file};
+    use super::*;
+
+    #[test]
+    fn mem_env_files() {
+        let env = MemEnv::new();
+        assert!(!env.file_exists("/dir"));
+        env.create_dir("/dir").unwrap();
+        assert!(env.file_exists("/dir"));
+        assert!(env.get_children("/dir").unwrap().is_empty());
+        assert!(env.new_sequential_file("/dir/a").is_err());
+        assert!(env.get_file_size("/dir/a").is_err());
+
+        write_string_to_file(&env, b"hello", "/dir/a", true).unwrap();
+        {
+            let mut f = env.new_appendable_file("/dir/a").unwrap();
+            f.write_all(b" world").unwrap();
+        }
+        assert_eq!(env.get_file_size("/dir/a").unwrap(), 11);
+        let modified = env.get_file_modification_time("/dir/a").unwrap();
+        assert!(modified > 0 && modified <= env.now_micros());
+        assert!(env.get_file_modification_time("/dir/x").is_err());
+        assert_eq!(read_file_to_string(&env, "/dir/a").unwrap(), "hello world");
+
+        let f = env.new_random_access_file("/dir/a", &EnvOptions::default())
+            .unwrap();
+        assert_eq!(f.read(6, 5).unwrap(), Bytes::from("world"));
+        assert!(f.read(6, 6).is_err());
+
+        // A writable file truncates the old content
+        write_string_to_file(&env, b"new", "/dir/b", false).unwrap();
+        write_string_to_file(&env, b"newer", "/dir/b", false).unwrap();
+        assert_eq!(read_file_to_string(&env, "/dir/b").unwrap(), "newer");
+
+        let mut children = env.get_children("/dir").unwrap();
+        children.sort();
+        assert_eq!(children, vec!["a", "b"]);
+
+        // Rename replaces the target, while opened handles keep the old data
+        env.rename_file("/dir/a", "/dir/b").unwrap();
+        assert!(!env.file_exists("/dir/a"));
+        assert_eq!(read_file_to_string(&env, "/dir/b").unwrap(), "hello world");
+        assert!(env.rename_file("/dir/a", "/dir/c").is_err());
+
+        // A link shares the data, and a copy takes a prefix of it
+        env.link_file("/dir/b", "/dir/c").unwrap();
+        assert!(env.link_file("/dir/b", "/dir/c").is_err());
+        copy_file(&env, "/dir/b", "/dir/d", 5).unwrap();
+        assert!(copy_file(&env, "/dir/b", "/dir/e", 12).is_err());
+        env.remove_file("/dir/b").unwrap();
+        assert_eq!(read_file_to_string(&env, "/dir/c").unwrap(), "hello world");
+        assert_eq!(read_file_to_string(&env, "/dir/d").unwrap(), "hello");
+        env.remove_file("/dir/c").unwrap();
+        env.remove_file("/dir/d").unwrap();
+
+        assert!(env.remove_file("/dir/b").is_err());
+        assert!(env.get_children("/dir").unwrap().is_empty());
+        assert_eq!(f.read(0, 5).unwrap(), Bytes::from("hello"));
+    }
+
+    #[test]
+    fn mem_env_dirs() {
+        let env = MemEnv::new();
+        assert!(env.new_writable_file("/dir/a").is_err());
+        assert!(env.new_appendable_file("/dir/a").is_err());
+        assert!(env.create_dir("/dir/sub").is_err());
+        assert!(env.get_children("/dir").is_err());
+        assert!(env.remove_dir("/dir").is_err());
+
+        env.create_dir("/dir").unwrap();
+        assert!(env.create_dir("/dir").is_err());
+        env.create_dir("/dir/sub").unwrap();
+        write_string_to_file(&env, b"a", "/dir/a", false).unwrap();
+        write_string_to_file(&env, b"b", "/dir/sub/b", false).unwrap();
+        assert!(env.rename_file("/dir/a", "/other/a").is_err());
+        let mut children = env.get_children("/dir").unwrap();
+        children.sort();
+        assert_eq!(children, vec!["a", "sub"]);
+
+        // Only empty directories are removed
+        assert!(env.remove_dir("/dir/sub").is_err());
+        env.remove_file("/dir/sub/b").unwrap();
+        env.remove_dir("/dir/sub").unwrap();
+        assert!(env.remove_dir("/dir").is_err());
+        env.remove_file("/dir/a").unwrap();
+        env.remove_dir("/dir").unwrap();
+        assert!(!env.file_exists("/dir"));
+    }
+
+    #[test]
+    fn mem_env_lock_file() {
+        let env = MemEnv::new();
+        let lock = env.lock_file("/dir/LOCK").unwrap();
+        assert!(env.lock_file("/dir/LOCK").is_err());
+        env.unlock_file(lock).unwrap();
+
+        let lock = env.lock_file("/dir/LOCK").unwrap();
+        env.unlock_file(lock).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+mod fault_injection_env;
+mod logger;
+mod mem_env;
+mod posix;
+
+pub use self::fault_injection_env::{FaultInjectionEnv, FaultOp};
+pub use self::logger::{FileLogger, NullLogger};
+pub use self::mem_env::MemEnv;
+pub use self::posix::PosixEnv;
+
+use slice::Bytes;
+use std::fmt;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+// A file read from the beginning to the end, such as a log or a manifest
+pub trait SequentialFile: Read + Send {}
+
+impl<T: Read + Send> SequentialFile for T {}
+
+// How files are opened, which Env may or may not follow
+#[derive(Clone, Copy, Debug)]
+pub struct EnvOptions {
+    // Reads files through mmap rather than pread
+    pub use_mmap_reads: bool,
+}
+
+impl Default for EnvOptions {
+    fn default() -> Self {
+        EnvOptions {
+            use_mmap_reads: true,
+        }
+    }
+}
+
+// A file read at arbitrary offsets, such as a table. It is shared by readers.
+pub trait RandomAccessFile: Send + Sync {
+    fn read(&self, offset: usize, size: usize) -> Result<Bytes, String>;
+}
+
+// A file written sequentially. Data may be buffered until flush or sync is called.
+pub trait WritableFile: Write + Send {
+    // Flushes buffered data and makes it durable
+    fn sync(&mut self) -> Result<(), String>;
+}
+
+// Destination of human readable messages about what a DB is doing, such as the info LOG
+pub trait Logger: Send + Sync {
+    fn logv(&self, args: fmt::Arguments);
+}
+
+// A lock acquired by Env::lock_file. It is released by Env::unlock_file.
+pub trait FileLock: Send {}
+
+// Env is the interface to the operating system. Every access to files, the clock and
+// background threads goes through it, so callers can replace it by Options::env.
+pub trait Env: Send + Sync {
+    fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, String>;
+
+    fn new_random_access_file(
+        &self,
+        fname: &str,
+        options: &EnvOptions,
+    ) -> Result<Box<dyn RandomAccessFile>, String>;
+
+    // Creates a new file. An existing file is truncated.
+    fn new_writable_file(&self, fname: &str) -> Result<Box<dyn WritableFile>, String>;
+
+    // Opens a file to append data to its end. The file is created if it does not exist.
+    fn new_appendable_file(&self, fname: &str) -> Result<Box<dyn WritableFile>, String>;
+
+    fn file_exists(&self, fname: &str) -> bool;
+
+    // Returns names of the entries in dir, not their paths
+    fn get_children(&self, dir: &str) -> Result<Vec<String>, String>;
+
+    fn remove_file(&self, fname: &str) -> Result<(), String>;
+
+    fn create_dir(&self, dirname: &str) -> Result<(), String>;
+
+    fn remove_dir(&self, dirname: &str) -> Result<(), String>;
+
+    fn get_file_size(&self, fname: &str) -> Result<u64, String>;
+
+    // Returns the time of the last write to the file in microseconds since the epoch
+    fn get_file_modification_time(&self, fname: &str) -> Result<u64, String>;
+
+    // Replaces target atomically if it exists
+    fn rename_file(&self, src: &str, target: &str) -> Result<(), String>;
+
+    // Creates target as a hard link to src. Fails if target exists, or if src is on another
+    // file system. Env which can not link files fails always.
+    fn link_file(&self, src: &str, _target: &str) -> Result<(), String> {
+        Err(format!("{:}: hard links are not supported", src))
+    }
+
+    // Fails if the file is already locked, by this process or by another one
+    fn lock_file(&self, fname: &str) -> Result<Box<dyn FileLock>, String>;
+
+    fn unlock_file(&self, lock: Box<dyn FileLock>) -> Result<(), String>;
+
+    // Runs f in a background thread. Jobs are run one by one in the order of scheduling.
+    fn schedule(&self, f: Box<dyn FnOnce() + Send>);
+
+    fn start_thread(&self, f: Box<dyn FnOnce() + Send>);
+
+    fn now_micros(&self) -> u64;
+
+    fn sleep_for_microseconds(&self, micros: u64);
+
+    // Creates a logger which writes to fname. An existing file is truncated.
+    fn new_logger(&self, fname: &str) -> Result<Arc<dyn Logger>, String> {
+        let file = self.new_writable_file(fname)?;
+        Ok(Arc::new(FileLogger::new(file)))
+    }
+}
+
+lazy_static! {
+    static ref DEFAULT_ENV: Arc<dyn Env> = Arc::new(PosixEnv::new());
+}
+
+// Returns the Env of the operating system, which is shared by all DBs in this process
+pub fn default_env() -> Arc<dyn Env> {
+    DEFAULT_ENV.clone()
+}
+
+pub fn write_string_to_file(
+    env: &dyn Env,
+    data: &[u8],
+    fname: &str,
+    should_sync: bool,
+) -> Result<(), String> {
+    let result = env.new_writable_file(fname).and_then(|mut file| {
+        file.write_all(data)
+            .map_err(|e| format!("failed to write {:}: {:}", fname, e))?;
+        if should_sync {
+            file.sync()
+        } else {
+            file.flush()
+                .map_err(|e| format!("failed to write {:}: {:}", fname, e))
+        }
+    });
+
+    if result.is_err() {
+        let _ = env.remove_file(fname);
+    }
+    result
+}
+
+// Copies the first size bytes of src to target, which is replaced if it exists
+pub fn copy_file(env: &dyn Env, src: &str, target: &str, size: u64) -> Result<(), String> {
+    let data = read_file_prefix(env, src, size)?;
+    write_string_to_file(env, &data, target, true)
+}
+
+// Reads the first size bytes of fname. Fails if the file is shorter.
+pub fn read_file_prefix(env: &dyn Env, fname: &str, size: u64) -> Result<Vec<u8>, String> {
+    let mut data = vec![];
+    env.new_sequential_file(fname)?
+        .take(size)
+        .read_to_end(&mut data)
+        .map_err(|e| format!("failed to read {:}: {:}", fname, e))?;
+    if (data.len() as u64) < size {
+        return Err(format!(
+            "{:} is {:} bytes, shorter than {:}",
+            fname,
+            data.len(),
+            size
+        ));
+    }
+    Ok(data)
+}
+
+pub fn read_file_to_string(env: &dyn Env, fname: &str) -> Result<String, String> {
+    let mut file = env.new_sequential_file(fname)?;
+    let mut data = String::new();
+    file.read_to_string(&mut data)
+        .map_err(|e| format!("failed to read {:}: {:}", fname, e))?;
+    Ok(data)
+}
@@ -0,0 +1,425 @@
+use libc;
+use memmap::{Mmap, MmapOptions};
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{Env, EnvOptions, FileLock, RandomAccessFile, SequentialFile, WritableFile};
+use slice::Bytes;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Up to 1000 files are mmapped on 64-bit platforms. 32-bit platforms have too little
+// address space to mmap tables.
+#[cfg(target_pointer_width = "64")]
+const DEFAULT_MMAP_LIMIT: usize = 1000;
+#[cfg(not(target_pointer_width = "64"))]
+const DEFAULT_MMAP_LIMIT: usize = 0;
+
+pub struct PosixEnv {
+    // Jobs are sent to a single background thread which is started on the first schedule
+    background: Mutex<Option<Sender<Job>>>,
+    mmap_limiter: Limiter,
+}
+
+impl PosixEnv {
+    pub fn new() -> Self {
+        PosixEnv::with_mmap_limit(DEFAULT_MMAP_LIMIT)
+    }
+
+    // Files opened for random access beyond mmap_limit are read by pread
+    pub fn with_mmap_limit(mmap_limit: usize) -> Self {
+        PosixEnv {
+            background: Mutex::new(None),
+            mmap_limiter: Limiter::new(mmap_limit),
+        }
+    }
+}
+
+impl Default for PosixEnv {
+    fn default() -> Self {
+        PosixEnv::new()
+    }
+}
+
+fn io_error(op: &str, fname: &str, e: io::Error) -> String {
+    format!("{:} {:}: {:}", op, fname, e)
+}
+
+impl Env for PosixEnv {
+    fn new_sequential_file(&self, fname: &str) -> Result<Box<dyn SequentialFile>, String> {
+        fs::File::open(fname)
+            .map(|f| Box::new(BufReader::new(f)) as Box<dyn SequentialFile>)
+            .map_err(|e| io_error("failed to open", fname, e))
+    }
+
+    fn new_random_access_file(
+        &self,
+        fname: &str,
+        options: &EnvOptions,
+    ) -> Result<Box<dyn RandomAccessFile>, String> {
+        let file = fs::File::open(fname).map_err(|e| io_error("failed to open", fname, e))?;
+        if options.use_mmap_reads && self.mmap_limiter.acquire() {
+            let limiter = self.mmap_limiter.clone();
+            MmapRandomAccessFile::new(fname, &file, limiter)
+                .map(|f| Box::new(f) as Box<dyn RandomAccessFile>)
+        } else {
+            Ok(Box::new(PreadRandomAccessFile {
+                fname: fname.to_owned(),
+                file,
+            }))
+        }
+    }
+
+    fn new_writable_file(&self, fname: &str) -> Result<Box<dyn WritableFile>, String> {
+        fs::File::create(fname)
+            .map(|f| Box::new(PosixWritableFile::new(f)) as Box<dyn WritableFile>)
+            .map_err(|e| io_error("failed to create", fname, e))
+    }
+
+    fn new_appendable_file(&self, fname: &str) -> Result<Box<dyn WritableFile>, String> {
+        fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(fname)
+            .map(|f| Box::new(PosixWritableFile::new(f)) as Box<dyn WritableFile>)
+            .map_err(|e| io_error("failed to open", fname, e))
+    }
+
+    fn file_exists(&self, fname: &str) -> bool {
+        fs::metadata(fname).is_ok()
+    }
+
+    fn get_children(&self, dir: &str) -> Result<Vec<String>, String> {
+        let entries = fs::read_dir(dir).map_err(|e| io_error("failed to read", dir, e))?;
+        let mut ret = vec![];
+        for entry in entries {
+            let entry = entry.map_err(|e| io_error("failed to read", dir, e))?;
+            if let Some(name) = entry.file_name().to_str() {
+                ret.push(name.to_owned());
+            }
+        }
+        Ok(ret)
+    }
+
+    fn remove_file(&self, fname: &str) -> Result<(), String> {
+        fs::remove_file(fname).map_err(|e| io_error("failed to remove", fname, e))
+    }
+
+    fn create_dir(&self, dirname: &str) -> Result<(), String> {
+        fs::create_dir(dirname).map_err(|e| io_error("failed to create", dirname, e))
+    }
+
+    fn remove_dir(&self, dirname: &str) -> Result<(), String> {
+        fs::remove_dir(dirname).map_err(|e| io_error("failed to remove", dirname, e))
+    }
+
+    fn get_file_size(&self, fname: &str) -> Result<u64, String> {
+        fs::metadata(fname)
+            .map(|m| m.len())
+            .map_err(|e| io_error("failed to stat", fname, e))
+    }
+
+    fn get_file_modification_time(&self, fname: &str) -> Result<u64, String> {
+        let modified = fs::metadata(fname)
+            .and_then(|m| m.modified())
+            .map_err(|e| io_error("failed to stat", fname, e))?;
+        let d = modified
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| format!("{:}: modification time is before the epoch", fname))?;
+        Ok(d.as_secs() * 1_000_000 + u64::from(d.subsec_micros()))
+    }
+
+    fn rename_file(&self, src: &str, target: &str) -> Result<(), String> {
+        fs::rename(src, target).map_err(|e| io_error("failed to rename", src, e))
+    }
+
+    fn link_file(&self, src: &str, target: &str) -> Result<(), String> {
+        fs::hard_link(src, target).map_err(|e| io_error("failed to link", src, e))
+    }
+
+    fn lock_file(&self, fname: &str) -> Result<Box<dyn FileLock>, String> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(fname)
+            .map_err(|e| io_error("failed to open", fname, e))?;
+
+        // flock locks belong to an open file, so a second lock fails even in the same process
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            return Err(io_error("failed to lock", fname, io::Error::last_os_error()));
+        }
+        Ok(Box::new(PosixFileLock { file }))
+    }
+
+    fn unlock_file(&self, lock: Box<dyn FileLock>) -> Result<(), String> {
+        // Closing the file releases the lock
+        drop(lock);
+        Ok(())
+    }
+
+    fn schedule(&self, f: Box<dyn FnOnce() + Send>) {
+        let mut background = self.background.lock().unwrap();
+        if background.is_none() {
+            let (tx, rx) = mpsc::channel::<Job>();
+            thread::spawn(move || {
+                for job in rx {
+                    job();
+                }
+            });
+            *background = Some(tx);
+        }
+
+        background
+            .as_ref()
+            .unwrap()
+            .send(f)
+            .expect("background thread has gone");
+    }
+
+    fn start_thread(&self, f: Box<dyn FnOnce() + Send>) {
+        thread::spawn(f);
+    }
+
+    fn now_micros(&self) -> u64 {
+        let d = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("clock is before the epoch");
+        d.as_secs() * 1_000_000 + u64::from(d.subsec_micros())
+    }
+
+    fn sleep_for_microseconds(&self, micros: u64) {
+        thread::sleep(Duration::from_micros(micros));
+    }
+}
+
+// Limits the number of resources, such as mmapped files, which are used at once
+#[derive(Clone)]
+struct Limiter {
+    available: Arc<AtomicIsize>,
+}
+
+impl Limiter {
+    fn new(n: usize) -> Self {
+        Limiter {
+            available: Arc::new(AtomicIsize::new(n as isize)),
+        }
+    }
+
+    // Returns false if no resource is available
+    fn acquire(&self) -> bool {
+        if self.available.fetch_sub(1, Ordering::SeqCst) > 0 {
+            true
+        } else {
+            self.available.fetch_add(1, Ordering::SeqCst);
+            false
+        }
+    }
+
+    fn release(&self) {
+        self.available.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+pub struct MmapRandomAccessFile {
+    inner: Mmap,
+    limiter: Limiter,
+}
+
+impl MmapRandomAccessFile {
+    // Takes over a resource acquired from limiter, which is released on drop
+    fn new(fname: &str, file: &fs::File, limiter: Limiter) -> Result<Self, String> {
+        match unsafe { MmapOptions::new().map(file) } {
+            Ok(inner) => Ok(MmapRandomAccessFile { inner, limiter }),
+            Err(e) => {
+                limiter.release();
+                Err(io_error("failed to map", fname, e))
+            }
+        }
+    }
+}
+
+impl Drop for MmapRandomAccessFile {
+    fn drop(&mut self) {
+        self.limiter.release();
+    }
+}
+
+impl RandomAccessFile for MmapRandomAccessFile {
+    fn read(&self, offset: usize, size: usize) -> Result<Bytes, String> {
+        let lim = offset + size;
+        if lim > self.inner.len() {
+            Err("invalid index".to_owned())
+        } else {
+            Ok(Bytes::from(&self.inner[offset..lim]))
+        }
+    }
+}
+
+// Reads by pread into buffers of its own. It has no file position, so it can be shared.
+struct PreadRandomAccessFile {
+    fname: String,
+    file: fs::File,
+}
+
+impl RandomAccessFile for PreadRandomAccessFile {
+    fn read(&self, offset: usize, size: usize) -> Result<Bytes, String> {
+        let mut buf = vec![0; size];
+        self.file
+            .read_exact_at(&mut buf, offset as u64)
+            .map_err(|e| io_error("failed to read", &self.fname, e))?;
+        Ok(Bytes::from(buf))
+    }
+}
+
+struct PosixWritableFile {
+    inner: BufWriter<fs::File>,
+}
+
+impl PosixWritableFile {
+    fn new(file: fs::File) -> Self {
+        PosixWritableFile {
+            inner: BufWriter::new(file),
+        }
+    }
+}
+
+impl Write for PosixWritableFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl WritableFile for PosixWritableFile {
+    fn sync(&mut self) -> Result<(), String> {
+        self.inner
+            .flush()
+            .and_then(|_| self.inner.get_ref().sync_data())
+            .map_err(|e| format!("failed to sync: {:}", e))
+    }
+}
+
+struct PosixFileLock {
+    file: fs::File,
+}
+
+impl FileLock for PosixFileLock {}
+
+impl Drop for PosixFileLock {
+    fn drop(&mut self) {
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{read_file_to_string, write_string_to_file};
+    use super::*;
+    use std::env;
+    use std::sync::mpsc;
+
+    fn test_dir(name: &str) -> String {
+        let path = env::temp_dir().join(format!("leveldb_test_{:}", name));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        fs::create_dir(&path).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn posix_env_files() {
+        let env = PosixEnv::new();
+        let dir = test_dir("posix_env_files");
+        let a = format!("{:}/a", dir);
+        let b = format!("{:}/b", dir);
+
+        write_string_to_file(&env, b"hello", &a, true).unwrap();
+        {
+            let mut f = env.new_appendable_file(&a).unwrap();
+            f.write_all(b" world").unwrap();
+            f.sync().unwrap();
+        }
+        assert_eq!(env.get_file_size(&a).unwrap(), 11);
+        for &use_mmap_reads in &[true, false] {
+            let options = EnvOptions { use_mmap_reads };
+            let f = env.new_random_access_file(&a, &options).unwrap();
+            assert_eq!(f.read(6, 5).unwrap(), Bytes::from("world"));
+            assert!(f.read(6, 6).is_err());
+        }
+
+        env.rename_file(&a, &b).unwrap();
+        assert!(!env.file_exists(&a));
+        assert_eq!(read_file_to_string(&env, &b).unwrap(), "hello world");
+        assert_eq!(env.get_children(&dir).unwrap(), vec!["b"]);
+
+        env.link_file(&b, &a).unwrap();
+        assert!(env.link_file(&b, &a).is_err());
+        assert_eq!(read_file_to_string(&env, &a).unwrap(), "hello world");
+        env.remove_file(&a).unwrap();
+
+        env.remove_file(&b).unwrap();
+        assert!(env.get_children(&dir).unwrap().is_empty());
+        assert!(env.new_sequential_file(&b).is_err());
+    }
+
+    #[test]
+    fn posix_env_mmap_limit() {
+        let env = PosixEnv::with_mmap_limit(1);
+        let dir = test_dir("posix_env_mmap_limit");
+        let a = format!("{:}/a", dir);
+        write_string_to_file(&env, b"hello", &a, true).unwrap();
+
+        let options = EnvOptions::default();
+        let mmapped = env.new_random_access_file(&a, &options).unwrap();
+        assert!(!env.mmap_limiter.acquire());
+
+        // Files beyond the limit are read by pread
+        let f = env.new_random_access_file(&a, &options).unwrap();
+        assert_eq!(f.read(0, 5).unwrap(), Bytes::from("hello"));
+        assert_eq!(mmapped.read(0, 5).unwrap(), Bytes::from("hello"));
+
+        drop(mmapped);
+        assert!(env.mmap_limiter.acquire());
+        assert!(!env.mmap_limiter.acquire());
+    }
+
+    #[test]
+    fn posix_env_lock_file() {
+        let env = PosixEnv::new();
+        let dir = test_dir("posix_env_lock_file");
+        let fname = format!("{:}/LOCK", dir);
+
+        let lock = env.lock_file(&fname).unwrap();
+        assert!(env.lock_file(&fname).is_err());
+        env.unlock_file(lock).unwrap();
+
+        let lock = env.lock_file(&fname).unwrap();
+        env.unlock_file(lock).unwrap();
+    }
+
+    #[test]
+    fn posix_env_schedule_in_order() {
+        let env = PosixEnv::new();
+        let (tx, rx) = mpsc::channel();
+        for i in 0..10 {
+            let tx = tx.clone();
+            env.schedule(Box::new(move || tx.send(i).unwrap()));
+        }
+
+        let received: Vec<_> = rx.iter().take(10).collect();
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+    }
+}
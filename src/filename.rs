@@ -1,7 +1,6 @@
+use env::{self, Env};
 use regex;
 use std::cmp;
-use std::fs;
-use std::io::Write;
 
 pub enum FileType<'a> {
     Log(&'a str, u64),
@@ -9,18 +8,24 @@ pub enum FileType<'a> {
     Table(&'a str, u64),
     Manifest(&'a str, usize),
     TempFileName(&'a str, usize),
+    DBLock(&'a str),
+    InfoLog(&'a str),
+    OldInfoLog(&'a str),
 }
 
 lazy_static! {
-    static ref CURRENT_FILE_REGEX: regex::Regex = { regex::Regex::new(r"([\w]+)/CURRENT").unwrap() };
+    static ref CURRENT_FILE_REGEX: regex::Regex = { regex::Regex::new(r"([\w]+)/CURRENT$").unwrap() };
     static ref LOG_FILE_REGEX: regex::Regex =
-        { regex::Regex::new(r"([\w]+)/([\d]{7})\.log").unwrap() };
+        { regex::Regex::new(r"([\w]+)/([\d]{7})\.log$").unwrap() };
     static ref TABLE_FILE_REGEX: regex::Regex =
-        { regex::Regex::new(r"([\w]+)/([\d]{7})\.ldb").unwrap() };
+        { regex::Regex::new(r"([\w]+)/([\d]{7})\.ldb$").unwrap() };
     static ref MANIFEST_FILE_REGEX: regex::Regex =
-        { regex::Regex::new(r"([\w]+)/MANIFEST-([\d]{7})").unwrap() };
+        { regex::Regex::new(r"([\w]+)/MANIFEST-([\d]{7})$").unwrap() };
     static ref CURRENT_TMP_REGEX: regex::Regex =
-        { regex::Regex::new(r"([\w]+)/CURRENT.([\d]{7})").unwrap() };
+        { regex::Regex::new(r"([\w]+)/CURRENT\.([\d]{7})$").unwrap() };
+    static ref LOCK_FILE_REGEX: regex::Regex = { regex::Regex::new(r"([\w]+)/LOCK$").unwrap() };
+    static ref INFO_LOG_FILE_REGEX: regex::Regex =
+        { regex::Regex::new(r"([\w]+)/LOG(\.old)?$").unwrap() };
 }
 
 #[derive(Eq, Ord, Debug)]
@@ -50,22 +55,40 @@ impl PartialEq for SimpleName {
     }
 }
 
-pub fn set_current_file(dbname: &str, num: usize) {
+// Directory in the DB directory which obsolete logs are moved to if Options::archive_wal is set
+pub fn archive_dir(dbname: &str) -> String {
+    format!("{:}/archive", dbname)
+}
+
+// Makes CURRENT point to the manifest file of num. CURRENT is replaced by rename, so readers
+// see either the old or the new content.
+pub fn set_current_file(env: &dyn Env, dbname: &str, num: usize) -> Result<(), String> {
     let current_name = FileType::Current(dbname).filename();
     let tmp_name = FileType::TempFileName(dbname, num).filename();
 
-    fs::File::create(&tmp_name)
-        .and_then(|mut file| {
-            let content = format!("MANIFEST-{:07}", num);
-            debug!("Set current manifest {:?} to current file", content);
-            file.write_all(content.as_bytes())
-        })
-        .and_then(|_| fs::rename(&tmp_name, &current_name))
-        .expect("Failed to set current file");
+    let content = format!("MANIFEST-{:07}", num);
+    debug!("Set current manifest {:?} to current file", content);
+    let result = env::write_string_to_file(env, content.as_bytes(), &tmp_name, true)
+        .and_then(|_| env.rename_file(&tmp_name, &current_name));
+    if result.is_err() {
+        let _ = env.remove_file(&tmp_name);
+    }
+    result
+}
+
+// Returns the number of the manifest file which CURRENT points to
+pub fn current_manifest_number(env: &dyn Env, dbname: &str) -> Result<usize, String> {
+    let name = env::read_file_to_string(env, &FileType::Current(dbname).filename())?;
+    let path = format!("{:}/{:}", dbname, name.trim_end());
+    match FileType::parse_name(&path) {
+        Some(FileType::Manifest(_, num)) => Ok(num),
+        _ => Err(format!("CURRENT has an invalid manifest name: {:?}", name)),
+    }
 }
 
 impl<'a> FileType<'a> {
-    pub fn parse_name(filename: &'a str) -> Self {
+    // Returns None if filename is not a file of leveldb
+    pub fn parse_name(filename: &'a str) -> Option<Self> {
         if CURRENT_FILE_REGEX.is_match(filename) {
             let v = CURRENT_FILE_REGEX
                 .captures(filename)
@@ -73,14 +96,14 @@ impl<'a> FileType<'a> {
             let name = v.get(1)
                 .map(|v| v.as_str())
                 .expect("current file regex name");
-            FileType::Current(name)
+            Some(FileType::Current(name))
         } else if LOG_FILE_REGEX.is_match(filename) {
             if let Some(v) = LOG_FILE_REGEX.captures(filename) {
                 let name = v.get(1).map(|v| v.as_str()).expect("log file regex name");
                 let num = v.get(2)
                     .and_then(|v| v.as_str().parse().ok())
                     .expect("log file regex num");
-                FileType::Log(name, num)
+                Some(FileType::Log(name, num))
             } else {
                 panic!("log file name is invalid")
             }
@@ -90,7 +113,7 @@ impl<'a> FileType<'a> {
                 let num = v.get(2)
                     .and_then(|v| v.as_str().parse().ok())
                     .expect("log file regex num");
-                FileType::Table(name, num)
+                Some(FileType::Table(name, num))
             } else {
                 panic!("table file name is invalid")
             }
@@ -102,12 +125,28 @@ impl<'a> FileType<'a> {
                 let num = v.get(2)
                     .and_then(|v| v.as_str().parse().ok())
                     .expect("manifest file regex num");
-                FileType::Manifest(name, num)
+                Some(FileType::Manifest(name, num))
             } else {
                 panic!("manifest file name is invalid")
             }
+        } else if let Some(v) = CURRENT_TMP_REGEX.captures(filename) {
+            let num = v.get(2).and_then(|num| num.as_str().parse().ok());
+            match (v.get(1), num) {
+                (Some(name), Some(num)) => Some(FileType::TempFileName(name.as_str(), num)),
+                _ => None,
+            }
+        } else if let Some(v) = LOCK_FILE_REGEX.captures(filename) {
+            v.get(1).map(|v| FileType::DBLock(v.as_str()))
+        } else if let Some(v) = INFO_LOG_FILE_REGEX.captures(filename) {
+            v.get(1).map(|name| {
+                if v.get(2).is_some() {
+                    FileType::OldInfoLog(name.as_str())
+                } else {
+                    FileType::InfoLog(name.as_str())
+                }
+            })
         } else {
-            unimplemented!()
+            None
         }
     }
 
@@ -125,6 +164,9 @@ impl<'a> FileType<'a> {
             &FileType::Table(name, num) => format!("{:}/{:07}.ldb", name, num),
             &FileType::Manifest(name, num) => format!("{:}/MANIFEST-{:07}", name, num),
             &FileType::TempFileName(name, num) => format!("{:}/CURRENT.{:07}", name, num),
+            &FileType::DBLock(name) => format!("{:}/LOCK", name),
+            &FileType::InfoLog(name) => format!("{:}/LOG", name),
+            &FileType::OldInfoLog(name) => format!("{:}/LOG.old", name),
         }
     }
 }
@@ -6,6 +6,7 @@ const SEQ_LENGTH: usize = 8;
 const UKEY_INDEX: usize = 4;
 const SEQ_MAX_NUMBER: usize = (1 << (64 - 8));
 
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum KeyKind {
     Value,
     Delete,
@@ -21,6 +22,31 @@ impl From<u8> for KeyKind {
     }
 }
 
+// Result of looking up a user key in a memtable or a table.
+// Deleted means a deletion marker was found, so older data must not be searched.
+#[derive(Debug, PartialEq)]
+pub enum LookupResult {
+    Found(Bytes),
+    Deleted,
+    NotFound,
+}
+
+impl LookupResult {
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            &LookupResult::NotFound => true,
+            _ => false,
+        }
+    }
+
+    pub fn value(self) -> Option<Bytes> {
+        match self {
+            LookupResult::Found(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
 // key = | value_length(4 bytes) | value (n bytes) | seq + kind (8 bytes: seq(63 bits), kind(1 bit))
 #[derive(Clone, Eq, Ord, Debug)]
 pub struct InternalKey {
@@ -86,6 +112,11 @@ impl InternalKey {
         }
     }
 
+    // Key which sorts before every entry of user_key
+    pub fn seek_key(user_key: &[u8]) -> Self {
+        InternalKey::new_with_kind(user_key, (SEQ_MAX_NUMBER - 1) as u64, KeyKind::Delete)
+    }
+
     pub fn new_delete_key(user_key: &[u8], seq: u64) -> Self {
         InternalKey {
             inner: InternalKey::make_key(user_key, seq, KeyKind::Delete),
@@ -108,10 +139,34 @@ impl InternalKey {
         self.inner.gets(0, self.key_size() + UKEY_LENGTH)
     }
 
+    // Key to seek the newest entry of the user key visible at this sequence number.
+    // Delete is the largest kind, so it sorts before any entry with the same sequence number.
+    pub fn lookup_key(&self) -> Bytes {
+        InternalKey::make_key(&self.user_key(), self.seq_number() as u64, KeyKind::Delete)
+    }
+
+    // Whether the key has a size prefix which matches its length, and a known kind
+    pub fn is_valid(&self) -> bool {
+        self.inner.len() >= UKEY_LENGTH + SEQ_LENGTH
+            && self.key_size() + UKEY_LENGTH == self.inner.len()
+            && self.key_size() >= SEQ_LENGTH
+            && self.compacted_seq_kind() & 0xff <= 1
+    }
+
     pub fn seq_number(&self) -> usize {
         (self.compacted_seq_kind() >> 8) as usize
     }
 
+    // Formats as 'user_key' @ seq : kind
+    pub fn debug_string(&self) -> String {
+        format!(
+            "'{:}' @ {:} : {:}",
+            String::from_utf8_lossy(&self.user_key()),
+            self.seq_number(),
+            self.kind() as u8
+        )
+    }
+
     pub fn kind(&self) -> KeyKind {
         match self.compacted_seq_kind() & 0xff {
             0 => KeyKind::Value,
@@ -1,217 +1,1105 @@
 use bytes::Bytes;
-use env_logger;
-use std::io::{BufReader, BufWriter};
-use std::{fs, mem, path, str};
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+use std::{mem, str};
 
 use batch::WriteBatch;
-use version::{VersionEdit, VersionSet};
-use configure;
+use checkpoint::{self, LiveFiles};
+use comparator::{Comparator, KeyComparator};
+use db_iter::{DBIterator, EntryIterator};
+use env::{Env, FileLock, Logger, NullLogger, WritableFile};
 use filename;
-use ikey::{InternalKey, KeyKind, SEQ_MAX_NUMBER};
+use ikey::{InternalKey, KeyKind};
 use log_record::{LogReader, LogWriter};
 use memdb::{MemDB, MemDBIterator};
-use random_access_file::MmapRandomAccessFile;
+use options::{Options, WriteOptions};
 use table;
+use table::table_builder::{self, TableBuilder};
+use updates::UpdatesIterator;
+use verify::{self, ChecksumReport};
+use version::{Compaction, FileMetaData, FileMetaDataBuilder, MergeingIterator, Version,
+              VersionEdit, VersionSet, total_file_size, LEVEL};
+use write_controller::WriteController;
 
-pub fn open(dir: &str) -> LevelDB {
-    env_logger::init();
-    setup_level_db(dir);
+// Opens the DB in dir, creating it if missing. Fails if the DB is already opened by another
+// process or by another handle in this process.
+pub fn open(dir: &str, options: Options) -> Result<LevelDB, String> {
+    options
+        .validate()
+        .map_err(|msg| format!("invalid options: {:}", msg))?;
 
-    let mut db = LevelDB::new(dir);
-    db.recover();
-    db
+    let env = options.env.clone();
+    if !env.file_exists(dir) {
+        debug!("Create directory {:?}", dir);
+        env.create_dir(dir)?;
+    }
+
+    // The lock is held until the DB is dropped
+    let db_lock = lock_db(&*env, dir)?;
+
+    let result = open_info_log(&options, dir).and_then(|info_log| {
+        setup_level_db(&*env, dir)?;
+        let archive = filename::archive_dir(dir);
+        if options.archive_wal && !env.file_exists(&archive) {
+            env.create_dir(&archive)?;
+        }
+        let db = LevelDB {
+            inner: Arc::new(DBImpl::new(dir, options, info_log, false)),
+        };
+
+        {
+            let mut state = db.inner.state.lock().unwrap();
+            db.inner.recover(&mut state)?;
+            maybe_schedule_compaction(&db.inner, &mut state);
+        }
+        Ok(db)
+    });
+
+    match result {
+        Ok(db) => {
+            *db.inner.db_lock.lock().unwrap() = Some(db_lock);
+            Ok(db)
+        }
+        Err(msg) => {
+            env.unlock_file(db_lock)?;
+            Err(msg)
+        }
+    }
+}
+
+// Opens the DB in dir for reads, such as a copy on a read-only mount. Nothing is written to
+// dir: the logs are replayed into memtables, no LOCK is taken and obsolete files are not
+// deleted. Writes and compactions fail. Messages are written only to Options::info_log.
+pub fn open_read_only(dir: &str, options: Options) -> Result<LevelDB, String> {
+    options
+        .validate()
+        .map_err(|msg| format!("invalid options: {:}", msg))?;
+
+    let current = filename::FileType::Current(dir).filename();
+    if !options.env.file_exists(&current) {
+        return Err(format!("{:}: no DB is found", dir));
+    }
+
+    let info_log = match options.info_log {
+        Some(ref info_log) => info_log.clone(),
+        None => Arc::new(NullLogger),
+    };
+    let db = LevelDB {
+        inner: Arc::new(DBImpl::new(dir, options, info_log, true)),
+    };
+    db.inner.recover(&mut db.inner.state.lock().unwrap())?;
+    Ok(db)
 }
 
-// Create directory and files which are used by leveldb
-fn setup_level_db(dbname: &str) {
-    if path::Path::new(dbname).exists() {
-        return;
+// Deletes the files of the DB in dir. Files which leveldb does not know are left alone, and
+// dir is removed only if nothing is left in it. Fails if the DB is in use.
+pub fn destroy(dir: &str, options: Options) -> Result<(), String> {
+    let env = options.env.clone();
+    if !env.file_exists(dir) {
+        return Ok(());
+    }
+    let names = env.get_children(dir)?;
+
+    let db_lock = lock_db(&*env, dir)?;
+    let mut result = Ok(());
+    for name in names {
+        let path = format!("{:}/{:}", dir, name);
+        match filename::FileType::parse_name(&path) {
+            // The lock file is deleted after it is released
+            None | Some(filename::FileType::DBLock(_)) => {}
+            Some(_) => {
+                if let Err(msg) = env.remove_file(&path) {
+                    result = result.and(Err(msg));
+                }
+            }
+        }
+    }
+    let archive = filename::archive_dir(dir);
+    if env.file_exists(&archive) {
+        for name in env.get_children(&archive)? {
+            let path = format!("{:}/{:}", archive, name);
+            if let Some(filename::FileType::Log(_, _)) = filename::FileType::parse_name(&path) {
+                if let Err(msg) = env.remove_file(&path) {
+                    result = result.and(Err(msg));
+                }
+            }
+        }
+        let _ = env.remove_dir(&archive);
+    }
+    env.unlock_file(db_lock)?;
+
+    // Errors are ignored since another process may have taken the lock meanwhile
+    let _ = env.remove_file(&filename::FileType::DBLock(dir).filename());
+    if env.get_children(dir).map(|names| names.is_empty()).unwrap_or(false) {
+        let _ = env.remove_dir(dir);
     }
+    result
+}
 
-    debug!("Create directory {:?}", dbname);
-    fs::create_dir(dbname).expect("failed to create directory");
+// Locks the LOCK file of the DB, so that no other process or handle uses the DB
+pub fn lock_db(env: &dyn Env, dir: &str) -> Result<Box<dyn FileLock>, String> {
+    let lock_name = filename::FileType::DBLock(dir).filename();
+    env.lock_file(&lock_name).map_err(|msg| {
+        format!(
+            "failed to lock {:}, the DB may be in use by another process: {:}",
+            dir, msg
+        )
+    })
+}
 
+// Returns info_log of options, or a new LOG in the DB directory after renaming the old one
+pub fn open_info_log(options: &Options, dbname: &str) -> Result<Arc<dyn Logger>, String> {
+    if let Some(ref info_log) = options.info_log {
+        return Ok(info_log.clone());
+    }
+
+    let env = &options.env;
+    let fname = filename::FileType::InfoLog(dbname).filename();
+    if env.file_exists(&fname) {
+        let old = filename::FileType::OldInfoLog(dbname).filename();
+        env.rename_file(&fname, &old)?;
+    }
+    env.new_logger(&fname)
+}
+
+// Create files which are used by leveldb unless the DB exists.
+// REQUIRES: the DB is locked
+fn setup_level_db(env: &dyn Env, dbname: &str) -> Result<(), String> {
     let manifest_file_num: usize = 1;
     let current = filename::FileType::Current(dbname).filename();
 
-    if !path::Path::new(&current).exists() {
+    if !env.file_exists(&current) {
         debug!("Create current file {:?}", current);
         let edit = VersionEdit::new((manifest_file_num + 1) as u64);
         let manifest = filename::FileType::Manifest(dbname, manifest_file_num).filename();
-        let mut writer = fs::File::create(manifest)
-            .map(|fs| LogWriter::new(BufWriter::new(fs)))
-            .expect("Failed to create writer for manifest file");
-        edit.encode_to(&mut writer);
+        let mut writer = env.new_writable_file(&manifest).map(LogWriter::new)?;
+        edit.encode_to(&mut writer)?;
+        writer.sync()?;
 
-        filename::set_current_file(dbname, manifest_file_num);
+        filename::set_current_file(env, dbname, manifest_file_num)?;
     }
+    Ok(())
 }
 
+// Memtables are flushed and level files are compacted by a background thread.
+// Writers wait for it only when they are throttled by WriteController.
 pub struct LevelDB {
-    log: Option<LogWriter<BufWriter<fs::File>>>,
+    inner: Arc<DBImpl>,
+}
+
+struct DBImpl {
     dbname: String,
+    options: Options,
+    env: Arc<dyn Env>,
+    info_log: Arc<dyn Logger>,
+    state: Mutex<DBState>,
+    table_cache: table::TableCache,
+    // Signaled when a background work finishes
+    background_work_finished_signal: Condvar,
+    shutting_down: AtomicBool,
+    // Lock of the LOCK file, which is released on drop
+    db_lock: Mutex<Option<Box<dyn FileLock>>>,
+    // Held while a manual compaction runs, so that only one of them is in DBState
+    manual_compaction_lock: Mutex<()>,
+    // Opened by open_read_only
+    read_only: bool,
+}
+
+struct DBState {
+    log: Option<LogWriter<Box<dyn WritableFile>>>,
+    log_number: u64,
     versions: VersionSet,
-    mem: MemDB,
-    imm: Option<MemDB>,
-    log_nubmer: u64,
-    table_cache: table::TableCache<MmapRandomAccessFile>,
-    configure: configure::Configure,
-    // Should have log file?
+    mem: Arc<MemDB>,
+    // Memtables waiting to be flushed, from older to newer
+    imm: VecDeque<ImmutableMemTable>,
+    write_controller: WriteController,
+    background_compaction_scheduled: bool,
+    // Once a background work fails, every write fails with it
+    bg_error: Option<String>,
+    // Per level stats of flushes and compactions which output to the level
+    stats: Vec<CompactionStats>,
+    manual_compaction: Option<ManualCompaction>,
+    // Number of copies of live files in progress. Obsolete files are not deleted while it is
+    // not 0, since the MANIFEST and logs being copied may become obsolete.
+    pending_file_copies: usize,
+    // Numbers of the tables being written without the lock. They are not in any version
+    // until they are installed, but must not be deleted as obsolete.
+    pending_outputs: HashSet<u64>,
+}
+
+// A request of compact_range for a level. The background thread compacts the range piece by
+// piece, advancing begin, until no file of the level overlaps it.
+struct ManualCompaction {
+    level: usize,
+    done: bool,
+    // None means before all keys
+    begin: Option<InternalKey>,
+    // None means after all keys
+    end: Option<InternalKey>,
+}
+
+#[derive(Clone, Default)]
+struct CompactionStats {
+    micros: u64,
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+// A range of user keys from start, inclusive, to limit, exclusive
+pub struct Range<'a> {
+    pub start: &'a [u8],
+    pub limit: &'a [u8],
+}
+
+struct ImmutableMemTable {
+    mem: Arc<MemDB>,
+    // Log file which has the contents of mem
+    log_number: u64,
+}
+
+impl DBState {
+    // Returns the numbers of the logs of the memtables, from older to newer. A DB opened
+    // read-only without any log has none.
+    fn log_numbers(&self) -> Vec<u64> {
+        let mut log_numbers: Vec<u64> = self.imm.iter().map(|imm| imm.log_number).collect();
+        log_numbers.push(self.log_number);
+        log_numbers.dedup();
+        log_numbers.retain(|&num| num != 0);
+        log_numbers
+    }
 }
 
 impl LevelDB {
-    fn new(dir: &str) -> Self {
-        Self {
-            dbname: dir.to_owned(),
-            log: None,
-            versions: VersionSet::new(dir),
-            mem: MemDB::new(),
-            imm: None,
-            log_nubmer: 0,
-            table_cache: table::TableCache::new(dir),
-            configure: Default::default(),
+    // Returns None if key is not found. A table which can not be read is logged to the info
+    // log and regarded as not having key.
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        let (snapshot, mems, version) = self.inner.read_sources();
+        let ikey = InternalKey::new(key.as_bytes(), snapshot);
+        debug!("snapshot id: {:}", snapshot);
+
+        for mem in mems {
+            let ret = mem.lookup(&ikey);
+            if !ret.is_not_found() {
+                return ret.value();
+            }
+        }
+
+        match version.get(&ikey, &self.inner.table_cache) {
+            Ok(result) => result.value(),
+            Err(msg) => {
+                info_log!(self.inner.info_log, "Failed to read {:}: {:}", key, msg);
+                None
+            }
         }
     }
 
-    pub fn get(&mut self, key: &str) -> Option<Bytes> {
-        let snapshot = self.versions.last_sequence;
-        let ikey = InternalKey::new(key.as_bytes(), snapshot);
+    // Returns an iterator over all keys and values in the DB at the time of the call. If a
+    // table can not be opened, the iterator yields nothing and its status has the error.
+    pub fn iter(&self) -> DBIterator {
+        let (snapshot, mems, version) = self.inner.read_sources();
 
-        debug!("snapshot id: {:}", snapshot);
-        let ret = self.mem
-            .get(&ikey)
-            .or_else(|| self.imm.as_ref().and_then(|v| v.get(&ikey)));
+        // Memtables are small, so their entries are copied instead of being kept alive
+        let mut iters: Vec<EntryIterator> = mems.iter()
+            .map(|mem| Box::new(mem.iter().collect::<Vec<_>>().into_iter()) as EntryIterator)
+            .collect();
+        if let Err(msg) = version.add_iterators(&self.inner.table_cache, &mut iters) {
+            return DBIterator::with_error(msg, version);
+        }
+
+        DBIterator::new(iters, snapshot, version)
+    }
 
-        if ret.is_none() {
-            let mut cache = &mut self.table_cache;
-            self.versions.current().and_then(|v| v.get(&ikey, cache))
+    // Returns a property of the DB, or None if name is unknown. Properties are:
+    //  leveldb.num-files-at-level<N>: the number of files at level N
+    //  leveldb.stats: statistics of each level and of write stalls
+    //  leveldb.sstables: files of each level with their key ranges
+    //  leveldb.approximate-memory-usage: bytes used by memtables
+    //  leveldb.total-bytes: total size of table files
+    pub fn property(&self, name: &str) -> Option<String> {
+        let name = name.strip_prefix("leveldb.")?;
+        let state = self.inner.state.lock().unwrap();
+        if let Some(level) = name.strip_prefix("num-files-at-level") {
+            match level.parse::<usize>() {
+                Ok(level) if level < LEVEL => {
+                    Some(state.versions.num_level_files(level).to_string())
+                }
+                _ => None,
+            }
+        } else if name == "stats" {
+            Some(stats_string(&state))
+        } else if name == "sstables" {
+            Some(state.versions.current().debug_string())
+        } else if name == "approximate-memory-usage" {
+            let usage: usize = state.mem.approximately_size()
+                + state
+                    .imm
+                    .iter()
+                    .map(|imm| imm.mem.approximately_size())
+                    .sum::<usize>();
+            Some(usage.to_string())
+        } else if name == "total-bytes" {
+            let current = state.versions.current();
+            let total: u64 = (0..LEVEL)
+                .map(|level| total_file_size(current.files(level)))
+                .sum();
+            Some(total.to_string())
         } else {
-            ret
+            None
         }
     }
 
-    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+    // Returns the approximate bytes which each range uses in table files. Memtables are
+    // counted too if include_memtable is true, otherwise recent writes may be missing.
+    // Data blocks are not read, so a range smaller than a block may be estimated as 0.
+    pub fn approximate_sizes(&self, ranges: &[Range], include_memtable: bool) -> Vec<u64> {
+        let (_, mems, version) = self.inner.read_sources();
+        let cache = &self.inner.table_cache;
+        ranges
+            .iter()
+            .map(|range| {
+                let start = InternalKey::seek_key(range.start);
+                let limit = InternalKey::seek_key(range.limit);
+                let mut size = version
+                    .approximate_offset_of(&limit, cache)
+                    .saturating_sub(version.approximate_offset_of(&start, cache));
+                if include_memtable {
+                    size += mems.iter().map(|mem| memtable_stats(mem, range).1).sum::<u64>();
+                }
+                size
+            })
+            .collect()
+    }
+
+    // Returns the approximate number of entries in range, counting overwritten and deleted
+    // ones which are not compacted away yet. None if a table file in the range was written
+    // without recording its number of entries, or can not be opened.
+    pub fn approximate_key_count(&self, range: &Range) -> Option<u64> {
+        let (_, mems, version) = self.inner.read_sources();
+        let start = InternalKey::seek_key(range.start);
+        let limit = InternalKey::seek_key(range.limit);
+        let count = version.approximate_num_entries(&start, &limit, &self.inner.table_cache)?;
+        Some(count + mems.iter().map(|mem| memtable_stats(mem, range).0).sum::<u64>())
+    }
+
+    // Reads every table in the current version and the logs which are not flushed yet, and
+    // checks their CRCs and the order of keys. Corruptions are listed in the report rather
+    // than failing, so that all of them are found at once.
+    pub fn verify_checksums(&self) -> ChecksumReport {
+        let (version, log_numbers) = {
+            let state = self.inner.state.lock().unwrap();
+            (state.versions.current(), state.log_numbers())
+        };
+        // version keeps its files from being deleted until the check finishes
+        verify::verify_checksums(&*self.inner.env, &self.inner.dbname, &version, &log_numbers)
+    }
+
+    // Writes a copy of the DB to target_dir, which must not exist, without stopping writes.
+    // Tables are shared by hard links if possible. The copy can be opened as another DB, and
+    // has every write which finished before the call.
+    pub fn checkpoint(&self, target_dir: &str) -> Result<(), String> {
+        let env = &self.inner.env;
+        self.with_live_files(|files| checkpoint::write_checkpoint(&**env, files, target_dir))
+    }
+
+    // Calls f with the files which make up the current state of the DB, so that f can copy
+    // them while writes go on. They are not deleted until f returns.
+    pub fn with_live_files<T, F>(&self, f: F) -> Result<T, String>
+    where
+        F: FnOnce(&LiveFiles) -> Result<T, String>,
+    {
+        let db = &self.inner;
+        let files = {
+            let mut state = db.state.lock().unwrap();
+            // Writers append to the MANIFEST and the log with the lock, so their sizes now
+            // end at a record boundary
+            let manifest_number = state.versions.manifest_file_number;
+            let manifest_size = db.env.get_file_size(
+                &filename::FileType::Manifest(&db.dbname, manifest_number as usize).filename(),
+            )?;
+            let mut logs = vec![];
+            for num in state.log_numbers() {
+                let size = db.env
+                    .get_file_size(&filename::FileType::Log(&db.dbname, num).filename())?;
+                logs.push((num, size));
+            }
+
+            let current = state.versions.current();
+            state.pending_file_copies += 1;
+            LiveFiles {
+                dbname: db.dbname.clone(),
+                tables: (0..LEVEL)
+                    .flat_map(|level| current.files(level).iter())
+                    .map(|f| (f.file_num, f.file_size))
+                    .collect(),
+                manifest_number,
+                manifest_size,
+                logs,
+                last_sequence: state.versions.last_sequence,
+            }
+        };
+
+        let result = f(&files);
+
+        let mut state = db.state.lock().unwrap();
+        state.pending_file_copies -= 1;
+        db.delete_obsolete_file(&mut state);
+        result
+    }
+
+    // Returns the sequence number of the last write
+    pub fn last_sequence(&self) -> u64 {
+        self.inner.state.lock().unwrap().versions.last_sequence
+    }
+
+    // Returns the write batches which finished before the call, starting from the one which
+    // has sequence number seq, with the sequence number of each batch. They are read from the
+    // archived logs and the logs in the DB directory, so older writes are found only with
+    // Options::archive_wal. The iterator stops with an error if some of them are not there.
+    pub fn updates_since(&self, seq: u64) -> Result<UpdatesIterator, String> {
+        let last_sequence = self.inner.state.lock().unwrap().versions.last_sequence;
+        UpdatesIterator::new(
+            self.inner.env.clone(),
+            &self.inner.dbname,
+            seq,
+            last_sequence,
+        )
+    }
+
+    // Compacts files which overlap [begin, end] in user key order, so that overwritten and
+    // deleted entries in the range are dropped. The memtable is flushed first, then each
+    // level is compacted into the next one down to the deepest level which overlaps the
+    // range. None for begin means before all keys, and for end after all keys.
+    // Blocks until the compaction finishes.
+    pub fn compact_range(&self, begin: Option<&[u8]>, end: Option<&[u8]>) -> Result<(), String> {
+        self.inner.check_writable()?;
+        let max_level_with_files = {
+            let current = self.inner.state.lock().unwrap().versions.current();
+            (1..LEVEL)
+                .filter(|&level| current.overlap_in_level(level, begin, end))
+                .max()
+                .unwrap_or(1)
+        };
+
+        flush_memtable(&self.inner)?;
+        for level in 0..max_level_with_files {
+            compact_level_range(&self.inner, level, begin, end)?;
+        }
+        Ok(())
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> Result<(), String> {
         let mut b = WriteBatch::new();
         b.put(key, value);
         self.apply(b)
     }
 
-    fn recover(&mut self) {
+    pub fn delete(&self, key: &str) -> Result<(), String> {
+        let mut b = WriteBatch::new();
+        b.delete(key);
+        self.apply(b)
+    }
+
+    pub fn apply(&self, batch: WriteBatch) -> Result<(), String> {
+        self.write(&WriteOptions::default(), batch)
+    }
+
+    pub fn write(&self, options: &WriteOptions, mut batch: WriteBatch) -> Result<(), String> {
+        self.inner.check_writable()?;
+        let state = self.inner.state.lock().unwrap();
+        let mut state = make_room_for_write(&self.inner, state, false)?;
+
+        let seq = state.versions.last_sequence;
+        batch.set_seq(seq + 1);
+
+        let result = match state.log.as_mut() {
+            Some(l) => l.add_record(batch.data()).and_then(|_| {
+                if options.sync {
+                    l.sync()
+                } else {
+                    Ok(())
+                }
+            }),
+            None => Ok(()),
+        };
+        if let Err(msg) = result {
+            // The record may or may not be in the log after a crash,
+            // so fail every later write instead of guessing.
+            info_log!(self.inner.info_log, "Failed to write log: {:}", msg);
+            state.bg_error = Some(msg.clone());
+            return Err(msg);
+        }
+
+        state.versions.set_last_sequence(seq + batch.count() as u64);
+        batch.insert_into(&state.mem)
+    }
+}
+
+impl Drop for LevelDB {
+    fn drop(&mut self) {
+        // Wait for background work to finish
+        self.inner
+            .shutting_down
+            .store(true, atomic::Ordering::Release);
+        let mut state = self.inner.state.lock().unwrap();
+        while state.background_compaction_scheduled {
+            state = self.inner
+                .background_work_finished_signal
+                .wait(state)
+                .unwrap();
+        }
+        drop(state);
+
+        // Background work may still refer the DB for a moment, so the lock is released here
+        // rather than when the DB is freed
+        if let Some(lock) = self.inner.db_lock.lock().unwrap().take() {
+            if let Err(msg) = self.inner.env.unlock_file(lock) {
+                info_log!(self.inner.info_log, "Failed to unlock DB: {:}", msg);
+            }
+        }
+    }
+}
+
+fn stats_string(state: &DBState) -> String {
+    let mut s = String::new();
+    s.push_str("                               Compactions\n");
+    s.push_str("Level  Files Size(MB) Time(sec) Read(MB) Write(MB)\n");
+    s.push_str("--------------------------------------------------\n");
+
+    let mb = 1048576.0;
+    let current = state.versions.current();
+    for (level, stats) in state.stats.iter().enumerate() {
+        let files = current.files(level);
+        if files.is_empty() && stats.micros == 0 {
+            continue;
+        }
+        s.push_str(&format!(
+            "{:3} {:8} {:8.0} {:9.0} {:8.0} {:9.0}\n",
+            level,
+            files.len(),
+            total_file_size(files) as f64 / mb,
+            stats.micros as f64 / 1e6,
+            stats.bytes_read as f64 / mb,
+            stats.bytes_written as f64 / mb
+        ));
+    }
+
+    let wc = &state.write_controller;
+    s.push_str(&format!(
+        "Delayed writes: {:}, stopped writes: {:}, stall time(sec): {:.3}\n",
+        wc.delayed_writes(),
+        wc.stopped_writes(),
+        wc.stall_micros() as f64 / 1e6
+    ));
+    s
+}
+
+// Returns the number of entries of mem in range and their bytes
+fn memtable_stats(mem: &MemDB, range: &Range) -> (u64, u64) {
+    mem.iter()
+        .filter(|(k, _)| {
+            let ukey = InternalKey::from(k.clone()).user_key();
+            range.start <= ukey.as_ref() && ukey.as_ref() < range.limit
+        })
+        .fold((0, 0), |(count, bytes), (k, v)| {
+            (count + 1, bytes + (k.len() + v.len()) as u64)
+        })
+}
+
+// Waits until current memtable has room for a write. If force is true, switches to a new
+// memtable even if current one has room.
+fn make_room_for_write<'a>(
+    db: &'a Arc<DBImpl>,
+    mut state: MutexGuard<'a, DBState>,
+    mut force: bool,
+) -> Result<MutexGuard<'a, DBState>, String> {
+    let mut allow_delay = !force;
+
+    loop {
+        if let Some(ref msg) = state.bg_error {
+            return Err(msg.clone());
+        }
+
+        let l0_files = state.versions.num_level_files(0);
+        let delay = if allow_delay {
+            state.write_controller.delay(l0_files)
+        } else {
+            None
+        };
+
+        if let Some(d) = delay {
+            // We are getting close to hitting a hard limit on the number of L0 files. Rather
+            // than delaying a single write by several seconds when we hit the hard limit,
+            // start delaying each individual write to reduce latency variance. Also this
+            // delay hands over some CPU to the compaction thread.
+            drop(state);
+            db.env.sleep_for_microseconds(duration_micros(d));
+            state = db.state.lock().unwrap();
+            state.write_controller.record_delay(d);
+            allow_delay = false; // Do not delay a single write more than once
+        } else if !force && state.mem.approximately_size() <= db.options.write_buffer_size {
+            // There is room in current memtable
+            break;
+        } else if let Some(reason) = state.write_controller.stall(l0_files, state.imm.len()) {
+            info_log!(
+                db.info_log,
+                "Stop writes until background work finishes: {:?}",
+                reason
+            );
+            let start = db.env.now_micros();
+            state = db.background_work_finished_signal.wait(state).unwrap();
+            let elapsed = db.env.now_micros().saturating_sub(start);
+            state
+                .write_controller
+                .record_stop(Duration::from_micros(elapsed));
+        } else {
+            // Attempt to switch to a new memtable and trigger flush of old memtable
+            let log_number = state.versions.next_file_num();
+            state.log = Some(open_log_file(&*db.env, &db.dbname, log_number)?);
+
+            let new_mem = Arc::new(MemDB::with_rep(&db.options.memtable_rep));
+            let old_mem = mem::replace(&mut state.mem, new_mem);
+            let old_log_number = mem::replace(&mut state.log_number, log_number);
+            state.imm.push_back(ImmutableMemTable {
+                mem: old_mem,
+                log_number: old_log_number,
+            });
+            force = false; // Do not force another compaction if have room
+            maybe_schedule_compaction(db, &mut state);
+        }
+    }
+
+    Ok(state)
+}
+
+// Switches to a new memtable and waits until the old ones are flushed
+fn flush_memtable(db: &Arc<DBImpl>) -> Result<(), String> {
+    let mut state = db.state.lock().unwrap();
+    if !state.mem.empty() {
+        state = make_room_for_write(db, state, true)?;
+    }
+
+    while !state.imm.is_empty() && state.bg_error.is_none() {
+        state = db.background_work_finished_signal.wait(state).unwrap();
+    }
+    match state.bg_error {
+        Some(ref msg) => Err(msg.clone()),
+        None => Ok(()),
+    }
+}
+
+// Compacts files of level which overlap [begin, end] into level + 1 by the background
+// thread, and waits until it finishes
+fn compact_level_range(
+    db: &Arc<DBImpl>,
+    level: usize,
+    begin: Option<&[u8]>,
+    end: Option<&[u8]>,
+) -> Result<(), String> {
+    let _manual_compaction_lock = db.manual_compaction_lock.lock().unwrap();
+    let mut state = db.state.lock().unwrap();
+    state.manual_compaction = Some(ManualCompaction {
+        level,
+        done: false,
+        begin: begin.map(InternalKey::seek_key),
+        end: end.map(|end| InternalKey::new(end, 0)),
+    });
+
+    while !state.manual_compaction.as_ref().unwrap().done && state.bg_error.is_none()
+        && !db.shutting_down.load(atomic::Ordering::Acquire)
+    {
+        maybe_schedule_compaction(db, &mut state);
+        state = db.background_work_finished_signal.wait(state).unwrap();
+    }
+    state.manual_compaction = None;
+
+    match state.bg_error {
+        Some(ref msg) => Err(msg.clone()),
+        None => Ok(()),
+    }
+}
+
+fn maybe_schedule_compaction(db: &Arc<DBImpl>, state: &mut DBState) {
+    if state.background_compaction_scheduled {
+        // Already scheduled
+    } else if db.shutting_down.load(atomic::Ordering::Acquire) {
+        // DB is being deleted; no more background compactions
+    } else if db.read_only {
+        // Nothing is written to a read-only DB
+    } else if state.bg_error.is_some() {
+        // Already got an error; no more changes
+    } else if state.imm.is_empty() && state.manual_compaction.as_ref().map_or(true, |m| m.done)
+        && !state.versions.needs_compaction()
+    {
+        // No work to be done
+    } else {
+        state.background_compaction_scheduled = true;
+        let db = db.clone();
+        let env = db.env.clone();
+        env.schedule(Box::new(move || background_call(&db)));
+    }
+}
+
+fn background_call(db: &Arc<DBImpl>) {
+    let mut state = db.state.lock().unwrap();
+    if !db.shutting_down.load(atomic::Ordering::Acquire) && state.bg_error.is_none() {
+        state = db.background_compaction(state);
+    }
+    state.background_compaction_scheduled = false;
+
+    // Previous compaction may have produced too many files in a level,
+    // so reschedule another compaction if needed.
+    maybe_schedule_compaction(db, &mut state);
+    db.background_work_finished_signal.notify_all();
+}
+
+fn open_log_file(
+    env: &dyn Env,
+    dbname: &str,
+    num: u64,
+) -> Result<LogWriter<Box<dyn WritableFile>>, String> {
+    let fname = filename::FileType::Log(dbname, num).filename();
+    debug!("Use log file {:?}", fname);
+    env.new_writable_file(&fname).map(LogWriter::new)
+}
+
+fn duration_micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + u64::from(d.subsec_micros())
+}
+
+impl DBImpl {
+    fn new(dir: &str, options: Options, info_log: Arc<dyn Logger>, read_only: bool) -> Self {
+        let state = DBState {
+            log: None,
+            log_number: 0,
+            versions: VersionSet::new(dir, &options),
+            mem: Arc::new(MemDB::with_rep(&options.memtable_rep)),
+            imm: VecDeque::new(),
+            write_controller: WriteController::new(&options),
+            background_compaction_scheduled: false,
+            bg_error: None,
+            stats: vec![CompactionStats::default(); LEVEL],
+            manual_compaction: None,
+            pending_file_copies: 0,
+            pending_outputs: HashSet::new(),
+        };
+
+        Self {
+            dbname: dir.to_owned(),
+            env: options.env.clone(),
+            info_log,
+            table_cache: table::TableCache::new(dir, &options),
+            options: options,
+            state: Mutex::new(state),
+            background_work_finished_signal: Condvar::new(),
+            shutting_down: AtomicBool::new(false),
+            db_lock: Mutex::new(None),
+            manual_compaction_lock: Mutex::new(()),
+            read_only,
+        }
+    }
+
+    fn check_writable(&self) -> Result<(), String> {
+        if self.read_only {
+            Err(format!("{:}: the DB is opened read-only", self.dbname))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Returns the last sequence number, the memtables from newer to older and the current
+    // version. Holding references to them, reads can go on without the lock.
+    fn read_sources(&self) -> (u64, Vec<Arc<MemDB>>, Arc<Version>) {
+        let state = self.state.lock().unwrap();
+        let mut mems = vec![state.mem.clone()];
+        mems.extend(state.imm.iter().rev().map(|imm| imm.mem.clone()));
+        (state.versions.last_sequence, mems, state.versions.current())
+    }
+
+    fn recover(&self, state: &mut DBState) -> Result<(), String> {
         debug!("Start recovering phase");
-        self.versions.recover();
+        state.versions.recover()?;
+        info_log!(
+            self.info_log,
+            "Recovered manifest #{:}: log #{:}, next file #{:}, last sequence {:}",
+            state.versions.manifest_file_number,
+            state.versions.log_number,
+            state.versions.next_file_number,
+            state.versions.last_sequence
+        );
 
         let mut edit = VersionEdit::new(0);
-        let paths = fs::read_dir(&self.dbname).expect("Failed to read directory");
         let mut log_paths = vec![];
-        let min_log = self.log_nubmer;
-        for p in paths {
-            if let Some(path) = p.unwrap().path().to_str() {
-                match filename::FileType::parse_name(path) {
-                    filename::FileType::Log(_, num) => {
-                        if num >= min_log {
-                            log_paths.push(filename::SimpleName::new(num, path))
-                        }
-                    }
-                    _ => (), // nothing
+        let min_log = state.versions.log_number;
+        let prev_log = state.versions.prev_log_number;
+        for name in self.env.get_children(&self.dbname)? {
+            let path = format!("{:}/{:}", self.dbname, name);
+            let file_type = filename::FileType::parse_name(&path);
+            if let Some(filename::FileType::Log(_, num)) = file_type {
+                if num >= min_log || num == prev_log {
+                    log_paths.push(filename::SimpleName::new(num, &path))
                 }
             }
         }
 
         log_paths.sort();
-        for path in log_paths {
-            let m = self.replay_logfile(&path.name, &mut edit) as u64;
-            self.versions.mark_file_num_used(path.num);
-            if self.versions.last_sequence < m {
-                debug!("max_seq_num is {:?}", m);
-                self.versions.last_sequence = m;
-            }
-        }
-
-        edit.log_number = self.versions.next_file_num();
-        self.log_nubmer = edit.log_number;
-        let fname = filename::FileType::Log(&self.dbname, self.log_nubmer).filename();
-        debug!("Use log file {:?}", fname);
-        self.log = Some(
-            fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(fname)
-                .map(|fd| LogWriter::new(BufWriter::new(fd)))
-                .expect("failed to create LogWriter"), // should be validate here
-        );
+        let max_sequence = self.options.recover_to_sequence.unwrap_or(u64::MAX);
+        let mut first_logged = None;
+        let mut max_replayed = 0;
+        for path in &log_paths {
+            let logged = Arc::new(MemDB::with_rep(&self.options.memtable_rep));
+            let (first, m) = self.replay_logfile(&logged, &path.name, max_sequence)?;
+            if self.read_only {
+                // Each log keeps its memtable as if it were waiting for a flush
+                let older = mem::replace(&mut state.mem, logged);
+                if state.log_number != 0 {
+                    state.imm.push_back(ImmutableMemTable {
+                        mem: older,
+                        log_number: state.log_number,
+                    });
+                }
+                state.log_number = path.num;
+            } else if !logged.empty() {
+                self.write_level0_table(state, &mut edit, &mut logged.iter())?;
+            }
+            state.versions.mark_file_num_used(path.num);
+            first_logged = first_logged.or(first);
+            if max_replayed < m {
+                max_replayed = m;
+            }
+        }
+
+        match self.options.recover_to_sequence {
+            Some(target) => {
+                // Writes before the first one in the logs are in the tables
+                let in_tables = first_logged
+                    .map_or(state.versions.last_sequence, |seq| seq.saturating_sub(1));
+                if target < in_tables {
+                    return Err(format!(
+                        "can not recover to sequence {:}, tables have writes up to {:}",
+                        target, in_tables
+                    ));
+                }
+                state.versions.last_sequence = in_tables.max(max_replayed);
+                info_log!(
+                    self.info_log,
+                    "Recovered to sequence {:}",
+                    state.versions.last_sequence
+                );
+            }
+            None => {
+                if state.versions.last_sequence < max_replayed {
+                    debug!("max_seq_num is {:?}", max_replayed);
+                    state.versions.last_sequence = max_replayed;
+                }
+            }
+        }
+        if self.read_only {
+            // No new MANIFEST is written, so the one in CURRENT is still live
+            let num = filename::current_manifest_number(&*self.env, &self.dbname)?;
+            state.versions.manifest_file_number = num as u64;
+            return Ok(());
+        }
 
-        self.versions.log_and_apply(&mut edit);
+        let log_number = state.versions.next_file_num();
+        state.log = Some(open_log_file(&*self.env, &self.dbname, log_number)?);
+        state.log_number = log_number;
+        edit.log_number = log_number;
+        state.versions.log_and_apply(&mut edit)?;
 
-        self.delete_obsolete_file()
+        // Sequence numbers of the dropped writes are reused, so the logs are deleted rather
+        // than archived for updates_since
+        if self.options.recover_to_sequence.is_some() {
+            for path in &log_paths {
+                if let Err(msg) = self.env.remove_file(&path.name) {
+                    info_log!(self.info_log, "Failed to delete {:}: {:}", path.name, msg);
+                }
+            }
+        }
+        self.delete_obsolete_file(state);
+        Ok(())
     }
 
-    fn delete_obsolete_file(&self) {
-        let live_files = self.versions.live_files();
+    fn delete_obsolete_file(&self, state: &mut DBState) {
+        if self.read_only || state.pending_file_copies > 0 {
+            return;
+        }
+        let live_files = state.versions.live_files();
+        let names = match self.env.get_children(&self.dbname) {
+            Ok(names) => names,
+            Err(msg) => {
+                info_log!(self.info_log, "Failed to list files: {:}", msg);
+                return;
+            }
+        };
 
-        let paths = fs::read_dir(&self.dbname).expect("Failed to read directory");
-        for p in paths {
-            if let Some(path) = p.unwrap().path().to_str() {
-                let keep = match filename::FileType::parse_name(path) {
-                    filename::FileType::Log(_, num) => num >= self.versions.log_number, // XXX
-                    filename::FileType::Manifest(_, num) => {
-                        num >= (self.versions.manifest_file_number as usize)
-                    }
-                    filename::FileType::Table(_, num) => {
-                        live_files.iter().find(|&&v| v == num).is_some()
+        for name in names {
+            let path = format!("{:}/{:}", self.dbname, name);
+            let file_type = filename::FileType::parse_name(&path);
+            let is_log = file_type.as_ref().map_or(false, |t| t.is_logfile());
+            let keep = match file_type {
+                Some(filename::FileType::Log(_, num)) => {
+                    num >= state.versions.log_number || num == state.versions.prev_log_number
+                }
+                Some(filename::FileType::Manifest(_, num)) => {
+                    num >= (state.versions.manifest_file_number as usize)
+                }
+                Some(filename::FileType::Table(_, num)) => {
+                    let live = live_files.iter().find(|&&v| v == num).is_some()
+                        || state.pending_outputs.contains(&num);
+                    if !live {
+                        self.table_cache.evict(num);
                     }
-                    _ => true,
-                };
+                    live
+                }
+                _ => true,
+            };
 
-                if !keep {
-                    debug!("Delete obsolete file {:?}", path);
-                    fs::remove_file(path).unwrap() // TODO: error mssage
+            if keep {
+                continue;
+            }
+            if is_log && self.options.archive_wal {
+                info_log!(self.info_log, "Archive {:}", name);
+                let target = format!("{:}/{:}", filename::archive_dir(&self.dbname), name);
+                if let Err(msg) = self.env.rename_file(&path, &target) {
+                    info_log!(self.info_log, "Failed to archive {:}: {:}", name, msg);
+                }
+            } else {
+                info_log!(self.info_log, "Delete {:}", name);
+                if let Err(msg) = self.env.remove_file(&path) {
+                    info_log!(self.info_log, "Failed to delete {:}: {:}", name, msg);
                 }
             }
         }
+
+        if self.options.archive_wal {
+            self.purge_archived_logs();
+        }
     }
 
-    fn replay_logfile(&mut self, path: &str, edit: &mut VersionEdit) -> usize {
-        debug!("Replay data from log file {:?}", path);
-        let reader = fs::File::open(path)
-            .map(|fs| LogReader::new(BufReader::new(fs)))
-            .expect("failed to read log file");
+    // Deletes archived logs older than wal_ttl_seconds, and then the oldest ones while the
+    // archive is larger than wal_size_limit
+    fn purge_archived_logs(&self) {
+        let dir = filename::archive_dir(&self.dbname);
+        let names = match self.env.get_children(&dir) {
+            Ok(names) => names,
+            Err(msg) => {
+                info_log!(self.info_log, "Failed to list archived logs: {:}", msg);
+                return;
+            }
+        };
+        let mut logs = vec![];
+        for name in names {
+            let path = format!("{:}/{:}", dir, name);
+            if let Some(filename::FileType::Log(_, num)) = filename::FileType::parse_name(&path) {
+                logs.push(filename::SimpleName::new(num, &path));
+            }
+        }
+        logs.sort();
 
+        let mut sizes = Vec::with_capacity(logs.len());
+        for log in &logs {
+            sizes.push(self.env.get_file_size(&log.name).unwrap_or(0));
+        }
+        let mut total: u64 = sizes.iter().sum();
+        let now = self.env.now_micros();
+        for (log, size) in logs.iter().zip(sizes) {
+            let expired = self.options.wal_ttl_seconds.map_or(false, |ttl| {
+                self.env
+                    .get_file_modification_time(&log.name)
+                    .map(|modified| modified.saturating_add(ttl * 1_000_000) <= now)
+                    .unwrap_or(false)
+            });
+            let oversize = self.options.wal_size_limit.map_or(false, |limit| total > limit);
+            if !expired && !oversize {
+                continue;
+            }
+            info_log!(self.info_log, "Delete archived log {:}", log.name);
+            match self.env.remove_file(&log.name) {
+                Ok(()) => total -= size,
+                Err(msg) => info_log!(
+                    self.info_log,
+                    "Failed to delete archived log {:}: {:}",
+                    log.name,
+                    msg
+                ),
+            }
+        }
+    }
+
+    // Inserts the records in the log file into mem. Returns the sequence number of the first
+    // record, and the last sequence number which is replayed.
+    fn replay_logfile(
+        &self,
+        mem: &MemDB,
+        path: &str,
+        max_sequence: u64,
+    ) -> Result<(Option<u64>, u64), String> {
+        info_log!(self.info_log, "Recovering log {:}", path);
+        let reader = self.env.new_sequential_file(path).map(LogReader::new)?;
+
+        let mut first_seq = None;
         let mut max_seq = 0;
-        let mut mem = MemDB::new();
+        let mut dropped = 0;
 
         for r in reader.into_iter() {
-            let batch = WriteBatch::load_data(r);
+            let batch = match WriteBatch::load_data(r) {
+                Ok(batch) => batch,
+                Err(msg) => {
+                    info_log!(self.info_log, "Ignore corrupted record in {:}: {:}", path, msg);
+                    continue;
+                }
+            };
 
-            let nseq = batch.seq();
-            let num_seq = nseq + batch.count();
-            if max_seq < num_seq {
-                max_seq = num_seq;
+            if first_seq.is_none() {
+                first_seq = Some(batch.seq() as u64);
+            }
+            let last_seq = (batch.seq() + batch.count()) as u64 - 1;
+            // A batch is dropped as a whole if it ends after max_sequence
+            if last_seq > max_sequence {
+                dropped += 1;
+                continue;
+            }
+            if max_seq < last_seq {
+                max_seq = last_seq;
             }
 
-            let mut iter = batch.into_iter();
-            for i in nseq..num_seq {
-                let (key_kind, ukey, value) = iter.next().expect("batch size is invalid");
-                let ikey = InternalKey::new_with_kind(&ukey, i as u64, key_kind);
-                mem.add(&ikey, &value);
+            if let Err(msg) = batch.insert_into(mem) {
+                info_log!(self.info_log, "Ignore corrupted record in {:}: {:}", path, msg);
             }
         }
 
-        if !mem.empty() {
-            self.write_level0_table(edit, &mut mem.iter())
-                .expect("failed to write write level 0 table")
+        if dropped > 0 {
+            info_log!(
+                self.info_log,
+                "Dropped {:} records after sequence {:} in {:}",
+                dropped,
+                max_sequence,
+                path
+            );
         }
-
-        return max_seq;
+        Ok((first_seq, max_seq))
     }
 
     fn write_level0_table(
-        &mut self,
+        &self,
+        state: &mut DBState,
         edit: &mut VersionEdit,
         mem: &mut MemDBIterator,
-    ) -> Result<(), &'static str> {
-        debug!("Write to level0 talble");
-        let num = self.versions.next_file_num();
-        let meta = table::bulid(&self.dbname, mem, num)?;
+    ) -> Result<(), String> {
+        let num = state.versions.next_file_num();
+        info_log!(self.info_log, "Level-0 table #{:}: started", num);
+        let start = self.env.now_micros();
+        let meta = table::bulid(&*self.env, &self.dbname, mem, num)?;
+        info_log!(
+            self.info_log,
+            "Level-0 table #{:}: {:} bytes OK",
+            num,
+            meta.file_size
+        );
+        state.stats[0].micros += self.env.now_micros().saturating_sub(start);
+        state.stats[0].bytes_written += meta.file_size;
         if meta.file_size == 0 {
             debug!("Skip adding table file to edit version, because file size is 0");
         } else {
@@ -221,71 +1109,1051 @@ impl LevelDB {
         Ok(())
     }
 
-    pub fn apply(&mut self, mut batch: WriteBatch) -> Result<(), String> {
-        self.make_room_for_write(true)?; // for debug
+    fn background_compaction<'a>(
+        &'a self,
+        state: MutexGuard<'a, DBState>,
+    ) -> MutexGuard<'a, DBState> {
+        if !state.imm.is_empty() {
+            return self.compact_memtable(state);
+        }
 
-        let seq = self.versions.last_sequence;
-        batch.set_seq(seq + 1);
-        self.versions.set_last_sequence(seq + batch.count() as u64);
+        let mut state = state;
+        let is_manual = state.manual_compaction.as_ref().map_or(false, |m| !m.done);
+        let c = if is_manual {
+            let state = &mut *state;
+            let m = state.manual_compaction.as_mut().unwrap();
+            let c = state
+                .versions
+                .compact_range(m.level, m.begin.as_ref(), m.end.as_ref());
+            match c {
+                Some(ref c) => {
+                    // The next round starts after the inputs of this one
+                    let cmp = KeyComparator::new();
+                    m.begin = c.inputs[0]
+                        .iter()
+                        .map(|f| &f.largest)
+                        .max_by(|a, b| cmp.compare(a.as_ref(), b.as_ref()))
+                        .cloned();
+                    info_log!(
+                        self.info_log,
+                        "Manual compaction at level-{:} up to {:}",
+                        m.level,
+                        m.begin.as_ref().unwrap().debug_string()
+                    );
+                }
+                None => m.done = true,
+            }
+            c
+        } else {
+            state.versions.pick_compaction()
+        };
+        let mut c = match c {
+            Some(c) => c,
+            None => return state,
+        };
+
+        // A manual compaction rewrites files even if they can be moved, to drop
+        // overwritten and deleted entries
+        if !is_manual && c.is_trivial_move() {
+            // Move file to next level
+            let mut f = c.inputs[0][0].clone();
+            c.add_input_deletions();
+            f.level = (c.level + 1) as u64;
+            info_log!(
+                self.info_log,
+                "Moved #{:} to level-{:} {:} bytes",
+                f.file_num,
+                f.level,
+                f.file_size
+            );
+            c.edit.add_file(f);
+            if let Err(msg) = state.versions.log_and_apply(&mut c.edit) {
+                info_log!(self.info_log, "Failed to move file: {:}", msg);
+                state.bg_error = Some(msg);
+            }
+            state
+        } else {
+            let mut state = self.do_compaction_work(state, c);
+            self.delete_obsolete_file(&mut state);
+            state
+        }
+    }
+
+    // Flushes the oldest immutable memtable to a level 0 table
+    fn compact_memtable<'a>(&'a self, mut state: MutexGuard<'a, DBState>) -> MutexGuard<'a, DBState> {
+        let (mem, log_number) = {
+            let imm = state.imm.front().expect("no immutable memtable");
+            // Logs of newer memtables are still needed after mem is flushed
+            let log_number = state
+                .imm
+                .get(1)
+                .map(|m| m.log_number)
+                .unwrap_or(state.log_number);
+            (imm.mem.clone(), log_number)
+        };
+        let num = state.versions.next_file_num();
+        state.pending_outputs.insert(num);
+
+        // Building a table takes a while. Let writers and readers go on meanwhile.
+        drop(state);
+        let start = self.env.now_micros();
+        let result = if mem.empty() {
+            Ok(None)
+        } else {
+            info_log!(self.info_log, "Level-0 table #{:}: started", num);
+            table::bulid(&*self.env, &self.dbname, &mut mem.iter(), num).map(|meta| {
+                info_log!(
+                    self.info_log,
+                    "Level-0 table #{:}: {:} bytes OK",
+                    num,
+                    meta.file_size
+                );
+                Some(meta)
+            })
+        };
+        let mut state = self.state.lock().unwrap();
+
+        let result = result.and_then(|meta| {
+            let mut edit = VersionEdit::new(0);
+            state.stats[0].micros += self.env.now_micros().saturating_sub(start);
+            if let Some(meta) = meta {
+                state.stats[0].bytes_written += meta.file_size;
+                edit.add_file(meta);
+            }
+            edit.log_number = log_number;
+            state.versions.log_and_apply(&mut edit)
+        });
+        state.pending_outputs.remove(&num);
+
+        match result {
+            Ok(()) => {
+                state.imm.pop_front();
+                self.delete_obsolete_file(&mut state);
+            }
+            Err(msg) => {
+                info_log!(self.info_log, "Failed to flush memtable: {:}", msg);
+                state.bg_error = Some(msg);
+            }
+        }
+        state
+    }
 
-        self.log.as_mut().map(|l| l.add_record(batch.data()));
+    fn do_compaction_work<'a>(
+        &'a self,
+        state: MutexGuard<'a, DBState>,
+        mut c: Compaction,
+    ) -> MutexGuard<'a, DBState> {
+        info_log!(
+            self.info_log,
+            "Compacting {:} files at level-{:} and {:} files at level-{:}",
+            c.inputs[0].len(),
+            c.level,
+            c.inputs[1].len(),
+            c.level + 1
+        );
+
+        // No snapshots exist, so entries hidden by newer ones can be dropped
+        let smallest_snapshot = state.versions.last_sequence;
+        drop(state);
+        let start = self.env.now_micros();
+        let mut pending = vec![];
+        let result = self.write_compaction_outputs(&c, smallest_snapshot, &mut pending);
+        let mut state = self.state.lock().unwrap();
 
-        for (key_kind, ukey, value) in batch.into_iter() {
-            let ikey = InternalKey::new_with_kind(&ukey, seq as u64, key_kind);
-            self.mem.add(&ikey, &value);
+        let result = result.and_then(|outputs| {
+            let stats = &mut state.stats[c.level + 1];
+            stats.micros += self.env.now_micros().saturating_sub(start);
+            stats.bytes_read += c.inputs.iter().map(|f| total_file_size(f)).sum::<u64>();
+            stats.bytes_written += total_file_size(&outputs);
+            info_log!(
+                self.info_log,
+                "Compacted to {:} files at level-{:}, {:} bytes",
+                outputs.len(),
+                c.level + 1,
+                outputs.iter().map(|f| f.file_size).sum::<u64>()
+            );
+            c.add_input_deletions();
+            for f in outputs {
+                c.edit.add_file(f);
+            }
+            state.versions.log_and_apply(&mut c.edit)
+        });
+        for num in &pending {
+            state.pending_outputs.remove(num);
         }
 
-        Ok(())
+        if let Err(msg) = result {
+            info_log!(self.info_log, "Compaction error: {:}", msg);
+            state.bg_error = Some(msg);
+        }
+        state
     }
 
-    // For now, single thread model
-    fn make_room_for_write(&mut self, force: bool) -> Result<(), String> {
-        if !force && self.configure.write_buffer_size > self.mem.approximately_size() {
-            return Ok(());
+    // Merges inputs of c into new files of level + 1. Numbers of the files are added to
+    // pending, and to pending_outputs of state, which the caller removes them from.
+    // REQUIRES: state is not locked since it takes a while
+    fn write_compaction_outputs(
+        &self,
+        c: &Compaction,
+        smallest_snapshot: u64,
+        pending: &mut Vec<u64>,
+    ) -> Result<Vec<FileMetaData>, String> {
+        let mut iters = vec![];
+        for files in c.inputs.iter() {
+            for f in files {
+                iters.push(self.table_cache.inner_iter(f.file_num, f.file_size)?);
+            }
         }
-        debug!("Make rom for write!");
-
-        self.log_nubmer = self.versions.next_file_num();
-        let fname = filename::FileType::Log(&self.dbname, self.log_nubmer).filename();
-        debug!("Use log file {:?}", fname);
-        self.log = Some(fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(fname)
-            .map(|fd| LogWriter::new(BufWriter::new(fd)))
-            .map_err(|_| "failed to open file")?);
-
-        let old = mem::replace(&mut self.mem, MemDB::new());
-        self.imm = Some(old);
-        self.maybe_compaction();
+        let input = MergeingIterator::with_comparator(iters, |a, b| {
+            KeyComparator::new().compare(&a.0, &b.0)
+        });
+
+        let mut outputs = vec![];
+        let mut output: Option<CompactionOutput> = None;
+        let mut current_user_key: Option<Bytes> = None;
+        let mut last_sequence_for_key = u64::max_value();
+
+        for (key, value) in input {
+            let ikey = InternalKey::from(key.clone());
+            let user_key = ikey.user_key();
+            if current_user_key.as_ref() != Some(&user_key) {
+                // First occurrence of this user key. Entries of a user key are kept in one
+                // file, so this is the only place to switch output files.
+                let full = output
+                    .as_ref()
+                    .map(|o| o.builder.size() as u64 >= c.max_output_file_size)
+                    .unwrap_or(false);
+                if full {
+                    outputs.push(output.take().unwrap().finish()?);
+                }
+
+                current_user_key = Some(user_key.clone());
+                last_sequence_for_key = u64::max_value();
+            }
+
+            let seq = ikey.seq_number() as u64;
+            let drop = if last_sequence_for_key <= smallest_snapshot {
+                // Hidden by a newer entry for same user key
+                true
+            } else {
+                // For this user key:
+                // (1) there is no data in higher levels
+                // (2) data in lower levels will have larger sequence numbers
+                // (3) data in layers that are being compacted here and have smaller sequence
+                //     numbers will be dropped in the next few iterations of this loop
+                // Therefore this deletion marker is obsolete and can be dropped.
+                ikey.kind() == KeyKind::Delete && seq <= smallest_snapshot
+                    && c.is_base_level_for_key(&user_key)
+            };
+            last_sequence_for_key = seq;
+
+            if drop {
+                continue;
+            }
+
+            if output.is_none() {
+                let num = {
+                    let mut state = self.state.lock().unwrap();
+                    let num = state.versions.next_file_num();
+                    state.pending_outputs.insert(num);
+                    num
+                };
+                pending.push(num);
+                output = Some(CompactionOutput::new(
+                    &*self.env,
+                    &self.dbname,
+                    num,
+                    c.level + 1,
+                    &key,
+                )?);
+            }
+            output.as_mut().unwrap().add(&key, &value)?;
+        }
+
+        if let Some(o) = output {
+            outputs.push(o.finish()?);
+        }
+        Ok(outputs)
+    }
+}
+
+// A table file being written by compaction
+struct CompactionOutput {
+    builder: TableBuilder<Box<dyn WritableFile>>,
+    meta: FileMetaDataBuilder,
+    largest: Bytes,
+}
+
+impl CompactionOutput {
+    fn new(
+        env: &dyn Env,
+        dbname: &str,
+        num: u64,
+        level: usize,
+        smallest: &Bytes,
+    ) -> Result<Self, String> {
+        let fname = filename::FileType::Table(dbname, num).filename();
+        let mut meta = FileMetaDataBuilder::new();
+        meta.file_num(num);
+        meta.level(level as u64);
+        meta.smallest(InternalKey::from(smallest.clone()));
+
+        Ok(CompactionOutput {
+            builder: table_builder::new(env, &fname)?,
+            meta: meta,
+            largest: smallest.clone(),
+        })
+    }
+
+    fn add(&mut self, key: &Bytes, value: &Bytes) -> Result<(), String> {
+        self.builder.add(key, value)?;
+        self.largest = key.clone();
         Ok(())
     }
 
-    fn maybe_compaction(&mut self) {
-        if self.imm.is_some() {
-            self.compact_memtable()
+    fn finish(mut self) -> Result<FileMetaData, String> {
+        self.builder.build()?;
+        self.builder.sync()?;
+        self.meta.file_size(self.builder.size() as u64);
+        self.meta.largest(InternalKey::from(self.largest));
+        self.meta.build().map_err(|e| e.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use env::{self, FaultInjectionEnv, FaultOp, MemEnv};
+    use std::thread;
+
+    // Every test has its own MemEnv, so nothing is left on disk
+    fn test_db_path(name: &str) -> String {
+        format!("/leveldb_test/{:}", name)
+    }
+
+    fn mem_env() -> Arc<dyn Env> {
+        let env = MemEnv::new();
+        env.create_dir("/leveldb_test").unwrap();
+        Arc::new(env)
+    }
+
+    fn default_options(env: &Arc<dyn Env>) -> Options {
+        let mut options = Options::default();
+        options.env = env.clone();
+        options
+    }
+
+    fn small_options(env: &Arc<dyn Env>) -> Options {
+        let mut options = default_options(env);
+        options.write_buffer_size = 64 << 10;
+        options.max_write_buffer_number = 3;
+        options.l0_compaction_trigger = 2;
+        options.l0_slowdown_writes_trigger = 3;
+        options.l0_stop_writes_trigger = 4;
+        options
+    }
+
+    fn value(i: usize, round: usize) -> String {
+        format!("{:}-{:}-{:}", i, round, "v".repeat(100))
+    }
+
+    fn wait_for_background_work(db: &LevelDB) {
+        loop {
+            {
+                let state = db.inner.state.lock().unwrap();
+                if state.imm.is_empty() && !state.versions.needs_compaction()
+                    && !state.background_compaction_scheduled
+                {
+                    return;
+                }
+            }
+            thread::sleep(Duration::from_millis(1));
         }
     }
 
-    fn compact_memtable(&mut self) {
-        if let Some(mem) = mem::replace(&mut self.imm, None) {
-            if mem.empty() {
-                debug!("Skip to compact memtable since memtable is empty");
-                return;
+    #[test]
+    fn leveldb_get_set_and_delete() {
+        let dbname = test_db_path("get_set_and_delete");
+        let env = mem_env();
+        {
+            let db = open(&dbname, default_options(&env)).unwrap();
+            db.set("foo", "v1").unwrap();
+            db.set("bar", "v2").unwrap();
+            db.delete("foo").unwrap();
+            assert_eq!(db.get("foo"), None);
+            assert_eq!(db.get("bar"), Some(Bytes::from("v2")));
+        }
+
+        // Deleted value must not come back from tables after recovery
+        let db = open(&dbname, default_options(&env)).unwrap();
+        assert_eq!(db.get("foo"), None);
+        assert_eq!(db.get("bar"), Some(Bytes::from("v2")));
+    }
+
+    #[test]
+    fn leveldb_reopen_on_mem_env() {
+        let dbname = test_db_path("reopen_on_mem_env");
+        let env = mem_env();
+        for round in 0..3 {
+            let db = open(&dbname, default_options(&env)).unwrap();
+            for r in 0..round {
+                assert_eq!(
+                    db.get(&format!("key{:}", r)),
+                    Some(Bytes::from(value(r, r)))
+                );
             }
+            db.set(&format!("key{:}", round), &value(round, round))
+                .unwrap();
+        }
 
-            debug!("Start memtable compactoin");
-            let mut edit = VersionEdit::new(0);
-            if let Err(msg) = self.write_level0_table(&mut edit, &mut mem.iter()) {
-                self.imm = Some(mem); // put it back
-                error!("during compaction, write_level0_table is failed: {:?}", msg);
-                return;
+        let current = filename::FileType::Current(&dbname).filename();
+        assert!(env.file_exists(&current));
+        assert!(!::std::path::Path::new(&current).exists());
+    }
+
+    #[test]
+    fn leveldb_open_read_only() {
+        let dbname = test_db_path("open_read_only");
+        let env = mem_env();
+        // No LOCK is taken, so the DB may be in use
+        let db = open(&dbname, default_options(&env)).unwrap();
+        db.set("foo", "v1").unwrap();
+        db.compact_range(None, None).unwrap();
+        db.set("bar", "v2").unwrap();
+
+        // A log after a switch of memtables, as if the DB crashed before the flush
+        let (log_number, seq) = {
+            let state = db.inner.state.lock().unwrap();
+            (state.log_number, state.versions.last_sequence)
+        };
+        let fname = filename::FileType::Log(&dbname, log_number + 1).filename();
+        let mut log = env.new_writable_file(&fname).map(LogWriter::new).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put("baz", "v3");
+        batch.delete("foo");
+        batch.set_seq(seq + 1);
+        log.add_record(batch.data()).unwrap();
+
+        let mut files = env.get_children(&dbname).unwrap();
+        files.sort();
+        let read_only = open_read_only(&dbname, default_options(&env)).unwrap();
+        assert_eq!(read_only.get("foo"), None);
+        assert_eq!(read_only.get("bar"), Some(Bytes::from("v2")));
+        assert_eq!(read_only.get("baz"), Some(Bytes::from("v3")));
+        assert_eq!(read_only.last_sequence(), seq + 2);
+        assert_eq!(read_only.inner.state.lock().unwrap().imm.len(), 1);
+        assert!(read_only.verify_checksums().is_ok());
+
+        let err = read_only.set("foo", "v4").unwrap_err();
+        assert!(err.contains("read-only"), "{}", err);
+        assert!(read_only.delete("bar").is_err());
+        assert!(read_only.compact_range(None, None).is_err());
+        assert_eq!(read_only.get("foo"), None);
+
+        let copy = test_db_path("open_read_only_copy");
+        read_only.checkpoint(&copy).unwrap();
+        drop(read_only);
+        let mut files_after = env.get_children(&dbname).unwrap();
+        files_after.sort();
+        assert_eq!(files, files_after);
+
+        let copy = open(&copy, default_options(&env)).unwrap();
+        assert_eq!(copy.get("baz"), Some(Bytes::from("v3")));
+        assert!(open_read_only(&test_db_path("missing"), default_options(&env)).is_err());
+
+        // Without any log, as if every log is deleted after the memtables are flushed
+        copy.compact_range(None, None).unwrap();
+        drop(copy);
+        let copy = test_db_path("open_read_only_copy");
+        for name in env.get_children(&copy).unwrap() {
+            if name.ends_with(".log") {
+                env.remove_file(&format!("{:}/{:}", copy, name)).unwrap();
+            }
+        }
+        let read_only = open_read_only(&copy, default_options(&env)).unwrap();
+        assert_eq!(read_only.get("baz"), Some(Bytes::from("v3")));
+        assert!(read_only.verify_checksums().is_ok());
+        let copy_of_copy = test_db_path("open_read_only_copy_of_copy");
+        read_only.checkpoint(&copy_of_copy).unwrap();
+        let copy_of_copy = open(&copy_of_copy, default_options(&env)).unwrap();
+        assert_eq!(copy_of_copy.get("baz"), Some(Bytes::from("v3")));
+    }
+
+    #[test]
+    fn leveldb_missing_table() {
+        let dbname = test_db_path("missing_table");
+        let env = mem_env();
+        {
+            let db = open(&dbname, default_options(&env)).unwrap();
+            db.set("foo", "v1").unwrap();
+            db.compact_range(None, None).unwrap();
+        }
+        for name in env.get_children(&dbname).unwrap() {
+            if name.ends_with(".ldb") {
+                env.remove_file(&format!("{:}/{:}", dbname, name)).unwrap();
+            }
+        }
+
+        // Reads and compactions fail without panicking
+        let db = open(&dbname, default_options(&env)).unwrap();
+        assert_eq!(db.get("foo"), None);
+        let mut iter = db.iter();
+        assert!(iter.next().is_none());
+        let err = iter.status().unwrap_err();
+        assert!(err.contains("not found"), "{}", err);
+        // Compacted with the missing table, which it overlaps
+        db.set("foo", "v2").unwrap();
+        assert!(db.compact_range(None, None).is_err());
+        assert!(db.set("baz", "v3").is_err());
+    }
+
+    #[test]
+    fn leveldb_recover_to_sequence() {
+        let dbname = test_db_path("recover_to_sequence");
+        let env = mem_env();
+        {
+            let db = open(&dbname, default_options(&env)).unwrap();
+            for i in 1..11 {
+                db.set(&format!("key{:}", i), &value(i, 0)).unwrap();
+            }
+            // Sequences 11 and 12
+            let mut batch = WriteBatch::new();
+            batch.put("key11", &value(11, 0));
+            batch.put("key12", &value(12, 0));
+            db.apply(batch).unwrap();
+        }
+
+        let mut options = default_options(&env);
+        // The batch is dropped as a whole since it ends after 11
+        options.recover_to_sequence = Some(11);
+        {
+            let db = open(&dbname, options).unwrap();
+            assert_eq!(db.get("key10"), Some(Bytes::from(value(10, 0))));
+            assert_eq!(db.get("key11"), None);
+            assert_eq!(db.get("key12"), None);
+            assert_eq!(db.inner.state.lock().unwrap().versions.last_sequence, 10);
+            db.set("key11", &value(11, 1)).unwrap();
+        }
+
+        // Dropped writes do not come back
+        let db = open(&dbname, default_options(&env)).unwrap();
+        assert_eq!(db.get("key11"), Some(Bytes::from(value(11, 1))));
+        assert_eq!(db.get("key12"), None);
+        db.compact_range(None, None).unwrap();
+        drop(db);
+
+        let mut options = default_options(&env);
+        options.recover_to_sequence = Some(5);
+        let err = open(&dbname, options).err().unwrap();
+        assert!(err.contains("tables have writes up to 11"), "{}", err);
+    }
+
+    #[test]
+    fn leveldb_lock_prevents_concurrent_open() {
+        let dbname = test_db_path("lock_prevents_concurrent_open");
+        let env = mem_env();
+        let db = open(&dbname, default_options(&env)).unwrap();
+        db.set("foo", "v1").unwrap();
+        let err = open(&dbname, default_options(&env)).err().unwrap();
+        assert!(err.contains("failed to lock"), "{}", err);
+
+        // The lock is released on drop
+        drop(db);
+        let db = open(&dbname, default_options(&env)).unwrap();
+        assert_eq!(db.get("foo"), Some(Bytes::from("v1")));
+    }
+
+    #[test]
+    fn leveldb_destroy() {
+        let dbname = test_db_path("destroy");
+        let env = mem_env();
+        let db = open(&dbname, small_options(&env)).unwrap();
+        for i in 0..1000 {
+            db.set(&format!("key{:04}", i), &value(i, 0)).unwrap();
+        }
+        wait_for_background_work(&db);
+        let err = destroy(&dbname, default_options(&env)).unwrap_err();
+        assert!(err.contains("failed to lock"), "{}", err);
+        drop(db);
+
+        let unrelated = ["0000001.log.bak", "notes.txt"];
+        for name in &unrelated {
+            let fname = format!("{:}/{:}", dbname, name);
+            env::write_string_to_file(&*env, b"keep", &fname, false).unwrap();
+        }
+        let tmp = filename::FileType::TempFileName(&dbname, 100).filename();
+        env::write_string_to_file(&*env, b"MANIFEST-0000100", &tmp, false).unwrap();
+
+        destroy(&dbname, default_options(&env)).unwrap();
+        let mut left = env.get_children(&dbname).unwrap();
+        left.sort();
+        assert_eq!(left, unrelated);
+        assert!(env.file_exists(&dbname));
+
+        // The directory is removed once nothing is left in it
+        for name in &unrelated {
+            env.remove_file(&format!("{:}/{:}", dbname, name)).unwrap();
+        }
+        destroy(&dbname, default_options(&env)).unwrap();
+        assert!(!env.file_exists(&dbname));
+        destroy(&dbname, default_options(&env)).unwrap();
+
+        let db = open(&dbname, default_options(&env)).unwrap();
+        assert_eq!(db.get("key0000"), None);
+    }
+
+    #[test]
+    fn leveldb_info_log() {
+        let dbname = test_db_path("info_log");
+        let env = mem_env();
+        let info_log = filename::FileType::InfoLog(&dbname).filename();
+        let old_info_log = filename::FileType::OldInfoLog(&dbname).filename();
+        {
+            let db = open(&dbname, small_options(&env)).unwrap();
+            for i in 0..1000 {
+                db.set(&format!("key{:04}", i), &value(i, 0)).unwrap();
+            }
+            wait_for_background_work(&db);
+        }
+        let content = env::read_file_to_string(&*env, &info_log).unwrap();
+        assert!(content.contains("Level-0 table #"), "{}", content);
+        assert!(content.contains("Delete "), "{}", content);
+        assert!(!env.file_exists(&old_info_log));
+
+        // The log of the previous run is kept as LOG.old
+        drop(open(&dbname, small_options(&env)).unwrap());
+        assert_eq!(
+            env::read_file_to_string(&*env, &old_info_log).unwrap(),
+            content
+        );
+        let content = env::read_file_to_string(&*env, &info_log).unwrap();
+        assert!(content.contains("Recovering log "), "{}", content);
+    }
+
+    struct TestLogger {
+        messages: Mutex<Vec<String>>,
+    }
+
+    impl Logger for TestLogger {
+        fn logv(&self, args: ::std::fmt::Arguments) {
+            self.messages.lock().unwrap().push(format!("{:}", args));
+        }
+    }
+
+    #[test]
+    fn leveldb_user_supplied_info_log() {
+        let dbname = test_db_path("user_supplied_info_log");
+        let env = mem_env();
+        let logger = Arc::new(TestLogger {
+            messages: Mutex::new(vec![]),
+        });
+        let mut options = default_options(&env);
+        options.info_log = Some(logger.clone());
+        drop(open(&dbname, options).unwrap());
+
+        let messages = logger.messages.lock().unwrap();
+        assert!(messages.iter().any(|m| m.starts_with("Recovered manifest")));
+        let info_log = filename::FileType::InfoLog(&dbname).filename();
+        assert!(!env.file_exists(&info_log));
+    }
+
+    #[test]
+    fn leveldb_multiple_immutable_memtables() {
+        let dbname = test_db_path("multiple_immutable_memtables");
+        let env = mem_env();
+        let size = 3000;
+        {
+            let db = open(&dbname, small_options(&env)).unwrap();
+            for i in 0..size {
+                db.set(&format!("key{:05}", i), &value(i, 0)).unwrap();
+            }
+
+            for i in 0..size {
+                assert_eq!(
+                    db.get(&format!("key{:05}", i)),
+                    Some(Bytes::from(value(i, 0)))
+                );
+            }
+        }
+
+        let db = open(&dbname, small_options(&env)).unwrap();
+        for i in 0..size {
+            assert_eq!(
+                db.get(&format!("key{:05}", i)),
+                Some(Bytes::from(value(i, 0)))
+            );
+        }
+    }
+
+    #[test]
+    fn leveldb_compaction_keeps_level0_bounded() {
+        let dbname = test_db_path("compaction_keeps_level0_bounded");
+        let env = mem_env();
+        let options = small_options(&env);
+        let stop_trigger = options.l0_stop_writes_trigger;
+        let db = open(&dbname, options).unwrap();
+
+        for round in 0..5 {
+            for i in 0..1000 {
+                db.set(&format!("key{:04}", i), &value(i, round)).unwrap();
+                assert!(db.inner.state.lock().unwrap().versions.num_level_files(0) <= stop_trigger);
+            }
+        }
+        for i in 0..1000 {
+            if i % 3 == 0 {
+                db.delete(&format!("key{:04}", i)).unwrap();
+            }
+        }
+        wait_for_background_work(&db);
+
+        {
+            let state = db.inner.state.lock().unwrap();
+            assert!(state.versions.num_level_files(0) < 2);
+            assert!(state.versions.num_level_files(1) > 0);
+        }
+
+        for i in 0..1000 {
+            let expected = if i % 3 == 0 {
+                None
+            } else {
+                Some(Bytes::from(value(i, 4)))
             };
+            assert_eq!(db.get(&format!("key{:04}", i)), expected);
+        }
+    }
+
+    fn num_table_files(env: &Arc<dyn Env>, dbname: &str) -> usize {
+        env.get_children(dbname)
+            .unwrap()
+            .iter()
+            .filter(|name| name.ends_with(".ldb"))
+            .count()
+    }
+
+    fn num_live_table_files(db: &LevelDB) -> usize {
+        let state = db.inner.state.lock().unwrap();
+        (0..LEVEL)
+            .map(|level| state.versions.num_level_files(level))
+            .sum()
+    }
+
+    #[test]
+    fn leveldb_properties() {
+        let dbname = test_db_path("properties");
+        let env = mem_env();
+        let db = open(&dbname, small_options(&env)).unwrap();
+        for round in 0..2 {
+            for i in 0..1000 {
+                db.set(&format!("key{:04}", i), &value(i, round)).unwrap();
+            }
+        }
+        wait_for_background_work(&db);
+
+        let num_files: usize = (0..LEVEL)
+            .map(|level| {
+                db.property(&format!("leveldb.num-files-at-level{:}", level))
+                    .unwrap()
+                    .parse::<usize>()
+                    .unwrap()
+            })
+            .sum();
+        assert_eq!(num_files, num_live_table_files(&db));
+        assert!(num_files > 0);
+
+        let stats = db.property("leveldb.stats").unwrap();
+        assert!(stats.starts_with("                               Compactions\n"));
+        assert!(stats.contains("Delayed writes: "));
+
+        let sstables = db.property("leveldb.sstables").unwrap();
+        assert!(sstables.contains("--- level 0 ---\n"));
+        assert!(sstables.contains("'key0000' @ "), "{}", sstables);
+
+        let usage: usize = db.property("leveldb.approximate-memory-usage")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(usage > 0);
+
+        let total_bytes: u64 = db.property("leveldb.total-bytes")
+            .unwrap()
+            .parse()
+            .unwrap();
+        let files_size: u64 = env.get_children(&dbname)
+            .unwrap()
+            .into_iter()
+            .filter(|name| name.ends_with(".ldb"))
+            .map(|name| env.get_file_size(&format!("{:}/{:}", dbname, name)).unwrap())
+            .sum();
+        assert_eq!(total_bytes, files_size);
+
+        assert_eq!(
+            db.property(&format!("leveldb.num-files-at-level{:}", LEVEL)),
+            None
+        );
+        assert_eq!(db.property("leveldb.unknown"), None);
+        assert_eq!(db.property("stats"), None);
+    }
+
+    #[test]
+    fn leveldb_approximate_sizes() {
+        let dbname = test_db_path("approximate_sizes");
+        let env = mem_env();
+        let db = open(&dbname, small_options(&env)).unwrap();
+        for round in 0..2 {
+            for i in 0..1000 {
+                db.set(&format!("key{:04}", i), &value(i, round)).unwrap();
+            }
+        }
+        wait_for_background_work(&db);
+
+        let range = |start: &'static str, limit: &'static str| Range {
+            start: start.as_bytes(),
+            limit: limit.as_bytes(),
+        };
+        let ranges = [
+            range("key0000", "key1000"),
+            range("key0000", "key0500"),
+            range("key0500", "key1000"),
+            range("key0300", "key0300"),
+            range("zzz", "zzzz"),
+        ];
+        let total_bytes: u64 = db.property("leveldb.total-bytes")
+            .unwrap()
+            .parse()
+            .unwrap();
+        let sizes = db.approximate_sizes(&ranges, false);
+        assert!(sizes[0] > total_bytes / 2 && sizes[0] <= total_bytes);
+        assert!(sizes[1] > 0 && sizes[2] > 0);
+        assert!(sizes[1] + sizes[2] <= sizes[0] + total_bytes / 10);
+        assert_eq!(sizes[3], 0);
+        assert_eq!(sizes[4], 0);
+
+        let with_mem = db.approximate_sizes(&ranges, true);
+        assert!(with_mem[0] >= sizes[0]);
+        assert_eq!(with_mem[4], 0);
+
+        let all = db.approximate_key_count(&ranges[0]).unwrap();
+        let half = db.approximate_key_count(&ranges[1]).unwrap();
+        assert!(all >= 900 && all <= 2100, "{:}", all);
+        assert!(half >= all / 4 && half <= all * 3 / 4, "{:} {:}", half, all);
+        assert_eq!(db.approximate_key_count(&ranges[4]), Some(0));
+    }
 
-            edit.log_number = self.log_nubmer;
-            self.versions.log_and_apply(&mut edit);
+    #[test]
+    fn leveldb_compact_range() {
+        let dbname = test_db_path("compact_range");
+        let env = mem_env();
+        let db = open(&dbname, small_options(&env)).unwrap();
+        for round in 0..3 {
+            for i in 0..1000 {
+                db.set(&format!("key{:04}", i), &value(i, round)).unwrap();
+            }
+        }
+        for i in 0..500 {
+            db.delete(&format!("key{:04}", i)).unwrap();
+        }
+        wait_for_background_work(&db);
+
+        let total_bytes = |db: &LevelDB| -> u64 {
+            db.property("leveldb.total-bytes").unwrap().parse().unwrap()
+        };
+        let before = total_bytes(&db);
+        db.compact_range(Some(b"key0100"), Some(b"key0199")).unwrap();
+        for i in 0..1000 {
+            let expected = if i < 500 { None } else { Some(Bytes::from(value(i, 2))) };
+            assert_eq!(db.get(&format!("key{:04}", i)), expected);
+        }
+
+        db.compact_range(None, None).unwrap();
+        assert!(total_bytes(&db) < before);
+        assert_eq!(db.inner.state.lock().unwrap().imm.len(), 0);
+        {
+            // Everything is compacted into the deepest level
+            let state = db.inner.state.lock().unwrap();
+            let levels: Vec<usize> = (0..LEVEL)
+                .filter(|&level| state.versions.num_level_files(level) > 0)
+                .collect();
+            assert_eq!(levels.len(), 1, "{:?}", levels);
+        }
+        for i in 0..1000 {
+            let expected = if i < 500 { None } else { Some(Bytes::from(value(i, 2))) };
+            assert_eq!(db.get(&format!("key{:04}", i)), expected);
+        }
+
+        // Deletions of every key are dropped with the values they hide
+        for i in 500..1000 {
+            db.delete(&format!("key{:04}", i)).unwrap();
+        }
+        db.compact_range(None, None).unwrap();
+        assert_eq!(num_live_table_files(&db), 0);
+        assert_eq!(db.iter().count(), 0);
+    }
+
+    #[test]
+    fn leveldb_iterator() {
+        let dbname = test_db_path("iterator");
+        let env = mem_env();
+        let db = open(&dbname, small_options(&env)).unwrap();
+        for i in 0..3000 {
+            db.set(&format!("key{:05}", i), &value(i, 0)).unwrap();
+        }
+        for i in 0..3000 {
+            if i % 3 == 0 {
+                db.delete(&format!("key{:05}", i)).unwrap();
+            } else if i % 3 == 1 {
+                db.set(&format!("key{:05}", i), &value(i, 1)).unwrap();
+            }
+        }
+
+        let it = db.iter();
+        // Writes after the iterator is created are not visible to it
+        db.set("key00002", "new").unwrap();
+        db.set("key99999", "new").unwrap();
+
+        let expected: Vec<(Bytes, Bytes)> = (0..3000)
+            .filter(|i| i % 3 != 0)
+            .map(|i| {
+                let round = if i % 3 == 1 { 1 } else { 0 };
+                (
+                    Bytes::from(format!("key{:05}", i)),
+                    Bytes::from(value(i, round)),
+                )
+            })
+            .collect();
+        assert_eq!(it.collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn leveldb_iterator_keeps_files_alive() {
+        let dbname = test_db_path("iterator_keeps_files_alive");
+        let env = mem_env();
+        let db = open(&dbname, small_options(&env)).unwrap();
+        for i in 0..1000 {
+            db.set(&format!("key{:04}", i), &value(i, 0)).unwrap();
+        }
+        wait_for_background_work(&db);
+
+        let it = db.iter();
+        for round in 1..4 {
+            for i in 0..1000 {
+                db.set(&format!("key{:04}", i), &value(i, round)).unwrap();
+            }
+        }
+        wait_for_background_work(&db);
+        assert!(num_table_files(&env, &dbname) > num_live_table_files(&db));
+
+        let entries: Vec<(Bytes, Bytes)> = it.collect();
+        assert_eq!(entries.len(), 1000);
+        for (i, (k, v)) in entries.into_iter().enumerate() {
+            assert_eq!(k, Bytes::from(format!("key{:04}", i)));
+            assert_eq!(v, Bytes::from(value(i, 0)));
+        }
+
+        // Files which only the iterator used are deleted by the next compaction
+        for i in 0..1000 {
+            db.set(&format!("key{:04}", i), &value(i, 4)).unwrap();
+        }
+        wait_for_background_work(&db);
+        assert_eq!(num_table_files(&env, &dbname), num_live_table_files(&db));
+    }
+
+    #[test]
+    fn leveldb_concurrent_writers() {
+        let dbname = test_db_path("concurrent_writers");
+        let env = mem_env();
+        let db = Arc::new(open(&dbname, small_options(&env)).unwrap());
+
+        let writers: Vec<_> = (0..4)
+            .map(|t| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        db.set(&format!("{:}-{:04}", t, i), &value(i, t)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for w in writers {
+            w.join().unwrap();
+        }
+
+        for t in 0..4 {
+            for i in 0..1000 {
+                assert_eq!(
+                    db.get(&format!("{:}-{:04}", t, i)),
+                    Some(Bytes::from(value(i, t)))
+                );
+            }
+        }
+    }
+
+    fn synced_write(db: &LevelDB, key: &str, value: &str) -> Result<(), String> {
+        let mut b = WriteBatch::new();
+        b.put(key, value);
+        db.write(&WriteOptions { sync: true }, b)
+    }
+
+    #[test]
+    fn leveldb_synced_writes_survive_crash() {
+        let dbname = test_db_path("synced_writes_survive_crash");
+        let fault = Arc::new(FaultInjectionEnv::new(mem_env()));
+        let env: Arc<dyn Env> = fault.clone();
+        for round in 0..4 {
+            {
+                let db = open(&dbname, small_options(&env)).unwrap();
+                for i in 0..1000 {
+                    if round > 0 {
+                        assert_eq!(
+                            db.get(&format!("key{:04}", i)),
+                            Some(Bytes::from(value(i, round - 1)))
+                        );
+                    }
+                    synced_write(&db, &format!("key{:04}", i), &value(i, round)).unwrap();
+                }
+                assert_eq!(db.get("tail"), None);
+                db.set("tail", "unsynced").unwrap();
+            }
+
+            // Power loss after the DB is closed
+            fault.drop_unsynced_data().unwrap();
+        }
+    }
+
+    #[test]
+    fn leveldb_crash_after_injected_failures() {
+        let dbname = test_db_path("crash_after_injected_failures");
+        let fault = Arc::new(FaultInjectionEnv::new(mem_env()));
+        let env: Arc<dyn Env> = fault.clone();
+        let mut acked = vec![None; 1000];
+        for (round, &n) in [0, 1, 2, 5, 10, 50, 200, 1000, 3000].iter().enumerate() {
+            {
+                let db = open(&dbname, small_options(&env)).unwrap();
+                for (i, v) in acked.iter().enumerate() {
+                    if let Some(round) = *v {
+                        assert_eq!(
+                            db.get(&format!("key{:04}", i)),
+                            Some(Bytes::from(value(i, round)))
+                        );
+                    }
+                }
+
+                fault.set_failure(FaultOp::Write, true);
+                fault.set_failure(FaultOp::Sync, true);
+                fault.set_failure(FaultOp::Rename, true);
+                fault.fail_after(n);
+                for i in 0..1000 {
+                    // A failed write may or may not be kept
+                    acked[i] = None;
+                    if synced_write(&db, &format!("key{:04}", i), &value(i, round)).is_err() {
+                        break;
+                    }
+                    acked[i] = Some(round);
+                }
+            }
 
-            self.delete_obsolete_file()
+            fault.reset_failures();
+            fault.drop_unsynced_data().unwrap();
         }
+        assert!(acked.iter().all(|v| v.is_some()));
     }
 }
@@ -1,6 +1,7 @@
 extern crate byteorder;
 extern crate bytes;
 extern crate crc;
+extern crate libc;
 extern crate memmap;
 extern crate rand;
 extern crate regex;
@@ -8,22 +9,51 @@ extern crate regex;
 #[macro_use]
 extern crate lazy_static;
 
-extern crate env_logger;
 #[macro_use]
 extern crate log;
 
+// Writes a message to a Logger, taking arguments like format!
+macro_rules! info_log {
+    ($logger:expr, $($arg:tt)*) => {
+        $logger.logv(format_args!($($arg)*))
+    };
+}
+
+mod backup;
 mod batch;
+mod checkpoint;
 mod comparator;
-mod configure;
+mod db_iter;
+mod dumpfile;
+mod env;
 mod filename;
 mod ikey;
 mod leveldb;
 mod log_record;
 mod memdb;
-mod random_access_file;
+mod options;
+mod repair;
+mod replication;
 mod slice;
 mod table;
+#[cfg(test)]
+mod test_util;
+mod updates;
+mod verify;
 mod version;
+mod write_controller;
 
+pub use backup::{BackupEngine, BackupInfo};
 pub use batch::WriteBatch;
-pub use leveldb::open;
+pub use checkpoint::LiveFiles;
+pub use db_iter::DBIterator;
+pub use dumpfile::{dump_file, DumpFormat};
+pub use env::{default_env, Env, EnvOptions, FaultInjectionEnv, FaultOp, FileLock, FileLogger,
+              Logger, MemEnv, NullLogger, PosixEnv, RandomAccessFile, SequentialFile,
+              WritableFile};
+pub use leveldb::{destroy, open, open_read_only, LevelDB, Range};
+pub use options::{MemTableRepType, Options, WriteOptions};
+pub use repair::repair;
+pub use replication::{Follower, Primary};
+pub use updates::UpdatesIterator;
+pub use verify::{ChecksumReport, Corruption};
@@ -7,6 +7,12 @@ use std::iter::Iterator;
 pub struct LogReader<T: Read> {
     inner: T,
     buffer: BytesMut,
+    // Offset in the file of the end of buffer
+    end_of_buffer_offset: u64,
+    // Offset in the file of the record returned last
+    last_record_offset: u64,
+    // Offset and reason of the corruption which stopped reading
+    corruption: Option<(u64, &'static str)>,
 }
 
 impl<T: Read> LogReader<T> {
@@ -14,13 +20,31 @@ impl<T: Read> LogReader<T> {
         LogReader {
             inner: reader,
             buffer: BytesMut::new(),
+            end_of_buffer_offset: 0,
+            last_record_offset: 0,
+            corruption: None,
         }
     }
 
+    pub fn last_record_offset(&self) -> u64 {
+        self.last_record_offset
+    }
+
+    // Returns where and why reading stopped before the end of the log, if it did
+    pub fn corruption(&self) -> Option<(u64, &'static str)> {
+        self.corruption
+    }
+
     pub fn read_record(&mut self) -> Option<Bytes> {
         let mut slice = Bytes::with_capacity(BLOCK_SIZE);
-        let record_type = self.read_physical_record(&mut slice)
-            .expect("invalid record type");
+        let record_type = match self.read_physical_record(&mut slice) {
+            Ok(t) => t,
+            Err((offset, msg)) => {
+                error!("Stop reading log at offset {:}: {:}", offset, msg);
+                self.corruption = Some((offset, msg));
+                return None;
+            }
+        };
 
         // TODO fragment
         let record = match record_type {
@@ -38,7 +62,10 @@ impl<T: Read> LogReader<T> {
         Some(slice)
     }
 
-    fn read_physical_record(&mut self, ret: &mut Bytes) -> Result<RecordType, &'static str> {
+    fn read_physical_record(
+        &mut self,
+        ret: &mut Bytes,
+    ) -> Result<RecordType, (u64, &'static str)> {
         if self.buffer.len() < HEADER_SIZE {
             let mut v = [0; BLOCK_SIZE];
             let s = self.inner.read(&mut v).unwrap();
@@ -46,8 +73,15 @@ impl<T: Read> LogReader<T> {
                 return Ok(RecordType::EOF);
             }
             self.buffer = BytesMut::from(&v[0..s]); // ignore size
+            self.end_of_buffer_offset += s as u64;
+        }
+
+        // A record cut off by a crash is treated as the end of the log
+        if self.buffer.len() < HEADER_SIZE {
+            return Ok(RecordType::EOF);
         }
 
+        let offset = self.end_of_buffer_offset - self.buffer.len() as u64;
         let mut header = self.buffer.split_to(HEADER_SIZE);
         let expected_checksum = {
             let c = header.split_to(CHECKSUM_SIZE);
@@ -61,13 +95,21 @@ impl<T: Read> LogReader<T> {
 
         let rtype = {
             let c = header.split_to(TYPE_SIZE);
+            if c[0] > RecordType::LAST as u8 {
+                return Err((offset, "unknown record type"));
+            }
             RecordType::from(c[0])
         };
 
+        if self.buffer.len() < length as usize {
+            return Ok(RecordType::EOF);
+        }
         let record = self.buffer.split_to(length as usize);
         if crc32(&record) != expected_checksum {
-            println!("invalid");
-            return Err("validation failed");
+            return Err((offset, "checksum mismatch"));
+        }
+        if let RecordType::FULL | RecordType::FIRST = rtype {
+            self.last_record_offset = offset;
         }
 
         debug!(
@@ -101,7 +143,7 @@ mod tests {
         {
             let w = BufWriter::new(Cursor::new(&mut value));
             let mut lw = LogWriter::new(w);
-            lw.add_record(b.clone());
+            lw.add_record(b.clone()).unwrap();
         }
 
         let r = BufReader::new(Cursor::new(value));
@@ -121,7 +163,7 @@ mod tests {
             let mut lw = LogWriter::new(w);
 
             for b in &bs {
-                lw.add_record(b.clone());
+                lw.add_record(b.clone()).unwrap();
             }
         }
 
@@ -132,4 +174,41 @@ mod tests {
             assert_eq!(reader.read_record(), Some(b.clone()));
         }
     }
+
+    #[test]
+    fn log_reader_truncated_record() {
+        let mut value: Vec<u8> = vec![];
+        {
+            let mut lw = LogWriter::new(Cursor::new(&mut value));
+            lw.add_record(Bytes::from("key1")).unwrap();
+            lw.add_record(Bytes::from("key2")).unwrap();
+        }
+
+        // Cut off in the middle of the second record, and then of its header
+        for len in &[value.len() - 2, value.len() - 11 + 3] {
+            let mut reader = LogReader::new(Cursor::new(value[..*len].to_vec()));
+            assert_eq!(reader.read_record(), Some(Bytes::from("key1")));
+            assert_eq!(reader.read_record(), None);
+            assert_eq!(reader.corruption(), None);
+        }
+    }
+
+    #[test]
+    fn log_reader_corruption() {
+        let mut value: Vec<u8> = vec![];
+        {
+            let mut lw = LogWriter::new(Cursor::new(&mut value));
+            lw.add_record(Bytes::from("key1")).unwrap();
+            lw.add_record(Bytes::from("key2")).unwrap();
+        }
+
+        // Corrupt the data of the second record
+        let last = value.len() - 1;
+        value[last] ^= 0xff;
+        let mut reader = LogReader::new(Cursor::new(value));
+        assert_eq!(reader.read_record(), Some(Bytes::from("key1")));
+        assert_eq!(reader.last_record_offset(), 0);
+        assert_eq!(reader.read_record(), None);
+        assert_eq!(reader.corruption(), Some((11, "checksum mismatch")));
+    }
 }
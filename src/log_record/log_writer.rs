@@ -1,6 +1,7 @@
 use super::{RecordType, crc32, BLOCK_SIZE, HEADER_SIZE};
 use bytes::{BufMut, Bytes, BytesMut};
-use std::io::Write;
+use env::WritableFile;
+use std::io::{self, Write};
 
 pub struct LogWriter<T: Write> {
     inner: T,
@@ -15,7 +16,12 @@ impl<T: Write> LogWriter<T> {
         }
     }
 
-    pub fn add_record(&mut self, data: Bytes) {
+    pub fn add_record(&mut self, data: Bytes) -> Result<(), String> {
+        self.write_record(data)
+            .map_err(|e| format!("failed to write log record: {:}", e))
+    }
+
+    fn write_record(&mut self, data: Bytes) -> io::Result<()> {
         let mut left = data.len();
         let mut begin = true;
 
@@ -24,7 +30,7 @@ impl<T: Write> LogWriter<T> {
             if leftover < HEADER_SIZE {
                 if leftover > 0 {
                     let trailer: Vec<u8> = vec![0; leftover];
-                    self.inner.write(trailer.as_ref()).unwrap();
+                    self.inner.write_all(trailer.as_ref())?;
                 }
                 self.offset = 0;
             }
@@ -51,13 +57,21 @@ impl<T: Write> LogWriter<T> {
             //     left,
             // );
             let data = data.slice_to(fragment_size);
-            self.emit_record(&data, fragment_size, kind);
+            self.emit_record(&data, fragment_size, kind)?;
             left -= fragment_size;
             begin = false;
         }
+
+        // Hand the record over to the OS so that it survives a process crash
+        self.inner.flush()
     }
 
-    fn emit_record(&mut self, data: &Bytes, length: usize, record_type: RecordType) {
+    fn emit_record(
+        &mut self,
+        data: &Bytes,
+        length: usize,
+        record_type: RecordType,
+    ) -> io::Result<()> {
         let mut bytes = BytesMut::with_capacity(HEADER_SIZE + length);
 
         let crc = crc32(data);
@@ -65,8 +79,16 @@ impl<T: Write> LogWriter<T> {
         bytes.put_u16_le(length as u16);
         bytes.put_u8(record_type as u8);
         bytes.extend(data);
-        let s = self.inner.write(&bytes).unwrap();
-        self.offset += s;
+        self.inner.write_all(&bytes)?;
+        self.offset += bytes.len();
+        Ok(())
+    }
+}
+
+impl LogWriter<Box<dyn WritableFile>> {
+    // Makes the records written so far survive a power loss
+    pub fn sync(&mut self) -> Result<(), String> {
+        self.inner.sync()
     }
 }
 
@@ -97,7 +119,7 @@ mod tests {
         let mut lw = LogWriter::new(Vec::new());
         let b = Bytes::from("key");
         let size = b.len();
-        lw.add_record(b);
+        lw.add_record(b).unwrap();
         let data = Bytes::from(lw.inner);
 
         let mut offset = 0;
@@ -122,7 +144,7 @@ mod tests {
             byte
         };
         let size = b.len();
-        lw.add_record(b);
+        lw.add_record(b).unwrap();
         let data = Bytes::from(lw.inner);
         let full_data_size = BLOCK_SIZE - 7; // 7 is header size
 
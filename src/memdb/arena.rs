@@ -0,0 +1,157 @@
+use std::{mem, ptr};
+
+const BLOCK_SIZE: usize = 4096;
+const ALIGN: usize = 8; // Alignment of a block, since blocks are allocated as u64 slices
+
+// Arena hands out memory from large blocks which are released all at once when the arena is dropped.
+// Returned pointers stay valid as long as the arena lives, since blocks are never moved or freed.
+pub struct Arena {
+    alloc_ptr: *mut u8,
+    alloc_bytes_remaining: usize,
+    blocks: Vec<Box<[u64]>>,
+    memory_usage: usize,
+}
+
+unsafe impl Send for Arena {}
+
+impl Arena {
+    pub fn new() -> Self {
+        Arena {
+            alloc_ptr: ptr::null_mut(),
+            alloc_bytes_remaining: 0,
+            blocks: vec![],
+            memory_usage: 0,
+        }
+    }
+
+    pub fn allocate(&mut self, bytes: usize) -> *mut u8 {
+        assert!(bytes > 0);
+        if bytes <= self.alloc_bytes_remaining {
+            let result = self.alloc_ptr;
+            self.alloc_ptr = unsafe { self.alloc_ptr.add(bytes) };
+            self.alloc_bytes_remaining -= bytes;
+            return result;
+        }
+
+        self.allocate_fallback(bytes)
+    }
+
+    // Allocate memory aligned for pointers and usize values
+    pub fn allocate_aligned(&mut self, bytes: usize) -> *mut u8 {
+        let align = mem::align_of::<usize>();
+        let current_mod = (self.alloc_ptr as usize) & (align - 1);
+        let slop = if current_mod == 0 {
+            0
+        } else {
+            align - current_mod
+        };
+
+        let needed = bytes + slop;
+        if needed <= self.alloc_bytes_remaining {
+            let result = unsafe { self.alloc_ptr.add(slop) };
+            self.alloc_ptr = unsafe { self.alloc_ptr.add(needed) };
+            self.alloc_bytes_remaining -= needed;
+            return result;
+        }
+
+        // allocate_fallback always returns the head of a new block, which is aligned
+        self.allocate_fallback(bytes)
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage
+    }
+
+    fn allocate_fallback(&mut self, bytes: usize) -> *mut u8 {
+        if bytes > BLOCK_SIZE / 4 {
+            // Object is more than a quarter of our block size. Allocate it separately
+            // to avoid wasting too much space in leftover bytes.
+            return self.allocate_new_block(bytes);
+        }
+
+        // We waste the remaining space in the current block.
+        self.alloc_ptr = self.allocate_new_block(BLOCK_SIZE);
+        self.alloc_bytes_remaining = BLOCK_SIZE;
+
+        let result = self.alloc_ptr;
+        self.alloc_ptr = unsafe { self.alloc_ptr.add(bytes) };
+        self.alloc_bytes_remaining -= bytes;
+        result
+    }
+
+    fn allocate_new_block(&mut self, block_bytes: usize) -> *mut u8 {
+        let words = (block_bytes + ALIGN - 1) / ALIGN;
+        let mut block = vec![0u64; words].into_boxed_slice();
+        let result = block.as_mut_ptr() as *mut u8;
+        self.blocks.push(block);
+        self.memory_usage += words * ALIGN + mem::size_of::<Box<[u64]>>();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{self, Rng};
+    use std::slice;
+
+    #[test]
+    fn arena_empty() {
+        let arena = Arena::new();
+        assert_eq!(arena.memory_usage(), 0);
+    }
+
+    #[test]
+    fn arena_simple() {
+        let mut allocated: Vec<(usize, *mut u8)> = vec![];
+        let mut arena = Arena::new();
+        let mut bytes = 0;
+        let mut rnd = rand::thread_rng();
+
+        for i in 0..10000 {
+            let s = if i % (10000 / 10) == 0 {
+                i
+            } else if rnd.gen_range(0, 4000) == 0 {
+                rnd.gen_range(1, 6000)
+            } else if rnd.gen_range(0, 10) == 0 {
+                rnd.gen_range(1, 100)
+            } else {
+                rnd.gen_range(1, 20)
+            };
+            let s = if s == 0 { 1 } else { s };
+
+            let r = if rnd.gen_range(0, 10) == 0 {
+                arena.allocate_aligned(s)
+            } else {
+                arena.allocate(s)
+            };
+
+            // Fill the "i"th allocation with a known bit pattern
+            let v = unsafe { slice::from_raw_parts_mut(r, s) };
+            for b in 0..s {
+                v[b] = (i % 256) as u8;
+            }
+
+            bytes += s;
+            allocated.push((s, r));
+            assert!(arena.memory_usage() >= bytes);
+        }
+
+        for (i, &(s, r)) in allocated.iter().enumerate() {
+            let v = unsafe { slice::from_raw_parts(r, s) };
+            for b in v {
+                assert_eq!(*b, (i % 256) as u8);
+            }
+        }
+    }
+
+    #[test]
+    fn arena_allocate_aligned() {
+        let mut arena = Arena::new();
+        arena.allocate(3);
+        for _ in 0..100 {
+            let p = arena.allocate_aligned(13);
+            assert_eq!((p as usize) % mem::align_of::<usize>(), 0);
+        }
+    }
+}
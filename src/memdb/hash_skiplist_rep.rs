@@ -0,0 +1,125 @@
+use byteorder::{ByteOrder, LittleEndian};
+use comparator::{Comparator, KeyComparator};
+use slice::{Bytes, U32_BYTE_SIZE, U64_BYTE_SIZE};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::sync::atomic::{self, AtomicPtr, AtomicUsize};
+use std::{mem, ptr};
+
+use super::rep::MemTableRep;
+use super::skiplist::{SkipList, DEFAULT_BRANCHING_FACTOR, DEFAULT_MAX_HEIGHT};
+
+// Smaller than the default since each bucket holds only a part of the memtable
+const BUCKET_MAX_HEIGHT: usize = DEFAULT_MAX_HEIGHT / 2;
+
+// HashSkipListRep spreads entries over a fixed number of buckets by hashing the first prefix_len
+// bytes of the user key. Each bucket is a skiplist which is created on the first insert.
+// A point lookup only walks the skiplist of its prefix, while a full iteration has to sort
+// the entries of all buckets.
+pub struct HashSkipListRep {
+    prefix_len: usize,
+    buckets: Vec<AtomicPtr<SkipList<KeyComparator>>>,
+    memory_usage: AtomicUsize,
+    cmp: KeyComparator,
+}
+
+impl HashSkipListRep {
+    pub fn new(prefix_len: usize, bucket_count: usize) -> Self {
+        assert!(bucket_count > 0, "bucket_count must be positive");
+        HashSkipListRep {
+            prefix_len: prefix_len,
+            buckets: (0..bucket_count)
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+            memory_usage: AtomicUsize::new(bucket_count * mem::size_of::<AtomicPtr<u8>>()),
+            cmp: KeyComparator::new(),
+        }
+    }
+
+    // key is either an entry or a lookup key, both start with a length prefixed internal key
+    fn bucket_index(&self, key: &[u8]) -> usize {
+        let key_size = LittleEndian::read_u32(key) as usize;
+        let user_key = &key[U32_BYTE_SIZE..U32_BYTE_SIZE + key_size - U64_BYTE_SIZE];
+        let prefix = if user_key.len() > self.prefix_len {
+            &user_key[..self.prefix_len]
+        } else {
+            user_key
+        };
+
+        let mut hasher = DefaultHasher::new();
+        hasher.write(prefix);
+        (hasher.finish() % self.buckets.len() as u64) as usize
+    }
+
+    fn bucket<'a>(&'a self, idx: usize) -> Option<&'a SkipList<KeyComparator>> {
+        let p = self.buckets[idx].load(atomic::Ordering::Acquire);
+        if p.is_null() {
+            None
+        } else {
+            Some(unsafe { &*p })
+        }
+    }
+}
+
+impl Drop for HashSkipListRep {
+    fn drop(&mut self) {
+        for b in &self.buckets {
+            let p = b.load(atomic::Ordering::Relaxed);
+            if !p.is_null() {
+                unsafe { drop(Box::from_raw(p)) };
+            }
+        }
+    }
+}
+
+impl MemTableRep for HashSkipListRep {
+    fn insert(&self, entry: &[u8]) {
+        let idx = self.bucket_index(entry);
+        let list = match self.bucket(idx) {
+            Some(list) => list,
+            None => {
+                // Inserts are serialized, so nobody else can create this bucket concurrently
+                let list = Box::new(SkipList::with_config(
+                    KeyComparator::new(),
+                    BUCKET_MAX_HEIGHT,
+                    DEFAULT_BRANCHING_FACTOR,
+                ));
+                let p = Box::into_raw(list);
+                self.buckets[idx].store(p, atomic::Ordering::Release);
+                unsafe { &*p }
+            }
+        };
+
+        let before = list.data_usage();
+        list.insert(entry);
+        self.memory_usage
+            .fetch_add(list.data_usage() - before, atomic::Ordering::Relaxed);
+    }
+
+    fn seek(&self, key: &[u8]) -> Option<Bytes> {
+        self.bucket(self.bucket_index(key))
+            .and_then(|list| list.seek(key))
+            .map(Bytes::from)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = Bytes> + 'a> {
+        let mut entries: Vec<Bytes> = vec![];
+        for idx in 0..self.buckets.len() {
+            if let Some(list) = self.bucket(idx) {
+                entries.extend(list.iter().map(Bytes::from));
+            }
+        }
+
+        let cmp = &self.cmp;
+        entries.sort_by(|a, b| cmp.compare(a, b));
+        Box::new(entries.into_iter())
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.memory_usage.load(atomic::Ordering::Relaxed)
+    }
+
+    fn empty(&self) -> bool {
+        (0..self.buckets.len()).all(|idx| self.bucket(idx).map(|l| l.empty()).unwrap_or(true))
+    }
+}
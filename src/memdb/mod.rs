@@ -1,21 +1,31 @@
 extern crate bytes;
 extern crate rand;
 
+mod arena;
+mod hash_skiplist_rep;
+mod rep;
 mod skiplist;
+mod vector_rep;
 
-use comparator::{Comparator, InternalKeyComparator};
-use ikey::{InternalKey, KeyKind};
+pub use self::skiplist::{DEFAULT_BRANCHING_FACTOR, DEFAULT_MAX_HEIGHT};
+
+use ikey::{InternalKey, KeyKind, LookupResult};
+use options::MemTableRepType;
 use slice::{ByteRead, ByteWrite, Bytes, U32_BYTE_SIZE, U64_BYTE_SIZE};
 use std::iter::Iterator;
 
 pub struct MemDB {
-    inner: skiplist::SkipList<KeyComparator>,
+    inner: Box<dyn rep::MemTableRep>,
 }
 
 impl MemDB {
     pub fn new() -> Self {
+        MemDB::with_rep(&Default::default())
+    }
+
+    pub fn with_rep(rep: &MemTableRepType) -> Self {
         MemDB {
-            inner: skiplist::SkipList::new(KeyComparator(InternalKeyComparator)),
+            inner: rep::new_rep(rep),
         }
     }
 
@@ -24,33 +34,41 @@ impl MemDB {
     }
 
     pub fn approximately_size(&self) -> usize {
-        self.inner.data_usage()
+        self.inner.memory_usage()
     }
 
     pub fn get(&self, key: &InternalKey) -> Option<Bytes> {
-        let k = key.memtable_key();
+        self.lookup(key).value()
+    }
+
+    pub fn lookup(&self, key: &InternalKey) -> LookupResult {
+        let k = key.lookup_key();
         debug!("Get {:?} from memdb", k);
-        self.inner.seek(&k).and_then(|mut v| {
-            let key_size = v.read_u32();
-            let ikey = v.read(key_size as usize - U64_BYTE_SIZE);
-            let seq_kind = v.read_u64();
-            let kind = KeyKind::from((seq_kind & 0xff) as u8);
-
-            match (kind, key.user_key() == ikey) {
-                (KeyKind::Value, true) => Some(get_length_prefixed_key(&v)),
-                _ => None,
+        match self.inner.seek(&k) {
+            Some(mut v) => {
+                let key_size = v.read_u32();
+                let ikey = v.read(key_size as usize - U64_BYTE_SIZE);
+                let seq_kind = v.read_u64();
+                let kind = KeyKind::from((seq_kind & 0xff) as u8);
+
+                match (kind, key.user_key() == ikey) {
+                    (KeyKind::Value, true) => LookupResult::Found(get_length_prefixed_key(&v)),
+                    (KeyKind::Delete, true) => LookupResult::Deleted,
+                    _ => LookupResult::NotFound,
+                }
             }
-        })
+            None => LookupResult::NotFound,
+        }
     }
 
-    pub fn add(&mut self, ikey: &InternalKey, value: &Bytes) {
+    pub fn add(&self, ikey: &InternalKey, value: &Bytes) {
         let mut v = ikey.memtable_key()
             .try_mut()
             .expect("can't convert bytes to mutable bytes");
         v.write_u32(value.len() as u32);
         v.write(value);
         debug!("Set {:?} to memdb", v);
-        self.inner.insert(v.freeze())
+        self.inner.insert(&v)
     }
 
     pub fn iter<'a>(&'a self) -> MemDBIterator<'a> {
@@ -61,7 +79,7 @@ impl MemDB {
 }
 
 pub struct MemDBIterator<'a> {
-    inner: skiplist::SkipListIterator<'a, KeyComparator>,
+    inner: Box<dyn Iterator<Item = Bytes> + 'a>,
 }
 
 impl<'a> Iterator for MemDBIterator<'a> {
@@ -83,23 +101,13 @@ fn get_length_prefixed_key(v: &Bytes) -> Bytes {
     v.gets(U32_BYTE_SIZE, size)
 }
 
-pub struct KeyComparator(InternalKeyComparator);
-
-use std::cmp::Ordering;
-impl Comparator for KeyComparator {
-    fn compare(&self, a: &Bytes, b: &Bytes) -> Ordering {
-        self.0
-            .compare(&get_length_prefixed_key(&a), &get_length_prefixed_key(&b))
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn memdb() {
-        let mut db = MemDB::new();
+        let db = MemDB::new();
 
         let hash = vec![
             ("key", Bytes::from("value")),
@@ -124,7 +132,7 @@ mod tests {
 
     #[test]
     fn memdb_seqeunce() {
-        let mut db = MemDB::new();
+        let db = MemDB::new();
         let key = "key1".as_bytes();
         let value = Bytes::from("value1");
 
@@ -134,9 +142,28 @@ mod tests {
         assert_eq!(db.get(&InternalKey::new(key, 11)), Some(value.clone()));
     }
 
+    #[test]
+    fn memdb_lookup_deleted() {
+        let db = MemDB::new();
+        let key = "key1".as_bytes();
+
+        db.add(&InternalKey::new(key, 10), &Bytes::from("value1"));
+        db.add(&InternalKey::new_delete_key(key, 11), &Bytes::new());
+        assert_eq!(
+            db.lookup(&InternalKey::new(key, 10)),
+            LookupResult::Found(Bytes::from("value1"))
+        );
+        assert_eq!(db.lookup(&InternalKey::new(key, 11)), LookupResult::Deleted);
+        assert_eq!(db.get(&InternalKey::new(key, 11)), None);
+        assert_eq!(
+            db.lookup(&InternalKey::new(b"key2", 11)),
+            LookupResult::NotFound
+        );
+    }
+
     #[test]
     fn memdb_iter() {
-        let mut db = MemDB::new();
+        let db = MemDB::new();
 
         let hash: Vec<(InternalKey, Bytes)> = vec![
             (InternalKey::new("key".as_bytes(), 1), Bytes::from("value")),
@@ -181,7 +208,7 @@ mod tests {
 
     #[test]
     fn memdb_iter_is_desc_order() {
-        let mut db = MemDB::new();
+        let db = MemDB::new();
         let hash: Vec<(InternalKey, Bytes)> = vec![
             (InternalKey::new("key01".as_bytes(), 1), Bytes::from("v")),
             (InternalKey::new("key00".as_bytes(), 1), Bytes::from("v")),
@@ -197,4 +224,78 @@ mod tests {
         assert_eq!(it.next().unwrap().0, hash[1].0);
         assert_eq!(it.next().unwrap().0, hash[0].0);
     }
+
+    fn rep_types() -> Vec<MemTableRepType> {
+        vec![
+            MemTableRepType::default(),
+            MemTableRepType::SkipList {
+                max_height: 4,
+                branching_factor: 2,
+            },
+            MemTableRepType::Vector,
+            MemTableRepType::HashSkipList {
+                prefix_len: 3,
+                bucket_count: 7,
+            },
+        ]
+    }
+
+    #[test]
+    fn memdb_reps_get() {
+        for rep in rep_types() {
+            let db = MemDB::with_rep(&rep);
+            assert!(db.empty());
+
+            for i in 0..100 {
+                let key = format!("{:03}key{:}", i % 10, i);
+                db.add(
+                    &InternalKey::new(key.as_bytes(), 10),
+                    &Bytes::from(format!("old{:}", i)),
+                );
+                db.add(
+                    &InternalKey::new(key.as_bytes(), 20),
+                    &Bytes::from(format!("new{:}", i)),
+                );
+            }
+            assert!(!db.empty());
+            assert!(db.approximately_size() > 0);
+
+            for i in 0..100 {
+                let key = format!("{:03}key{:}", i % 10, i);
+                assert_eq!(db.get(&InternalKey::new(key.as_bytes(), 9)), None);
+                assert_eq!(
+                    db.get(&InternalKey::new(key.as_bytes(), 15)),
+                    Some(Bytes::from(format!("old{:}", i)))
+                );
+                assert_eq!(
+                    db.get(&InternalKey::new(key.as_bytes(), 30)),
+                    Some(Bytes::from(format!("new{:}", i)))
+                );
+            }
+            assert_eq!(db.get(&InternalKey::new(b"notfound", 30)), None);
+        }
+    }
+
+    #[test]
+    fn memdb_reps_iter_in_same_order() {
+        let mut expected: Option<Vec<(Bytes, Bytes)>> = None;
+
+        for rep in rep_types() {
+            let db = MemDB::with_rep(&rep);
+            for i in (0..200).rev() {
+                let key = format!("{:}", (i * 37) % 101);
+                db.add(
+                    &InternalKey::new(key.as_bytes(), i as u64),
+                    &Bytes::from(format!("v{:}", i)),
+                );
+            }
+
+            let actual: Vec<(Bytes, Bytes)> = db.iter().collect();
+            assert_eq!(actual.len(), 200);
+            match expected {
+                Some(ref e) => assert_eq!(&actual, e),
+                None => expected = Some(actual),
+            }
+        }
+    }
 }
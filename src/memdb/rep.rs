@@ -0,0 +1,75 @@
+use comparator::KeyComparator;
+use options::MemTableRepType;
+use slice::Bytes;
+
+use super::hash_skiplist_rep::HashSkipListRep;
+use super::skiplist::SkipList;
+use super::vector_rep::VectorRep;
+
+// MemTableRep stores memtable entries ordered by KeyComparator.
+//
+// An entry is | key size (4 bytes) | internal key | value size (4 bytes) | value |.
+// Inserts are serialized by the caller, while lookups and iteration can run concurrently with them.
+pub trait MemTableRep: Send + Sync {
+    // REQUIRES: nothing that compares equal to entry is in the rep.
+    fn insert(&self, entry: &[u8]);
+
+    // Returns an entry which is greater than or equal to key. Implementations are allowed to
+    // return None when no entry has the same user key as key.
+    fn seek(&self, key: &[u8]) -> Option<Bytes>;
+
+    // Iterates over all entries in order
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = Bytes> + 'a>;
+
+    fn memory_usage(&self) -> usize;
+
+    fn empty(&self) -> bool;
+}
+
+pub fn new_rep(rep: &MemTableRepType) -> Box<dyn MemTableRep> {
+    match rep {
+        &MemTableRepType::SkipList {
+            max_height,
+            branching_factor,
+        } => Box::new(SkipListRep::new(max_height, branching_factor)),
+        &MemTableRepType::Vector => Box::new(VectorRep::new()),
+        &MemTableRepType::HashSkipList {
+            prefix_len,
+            bucket_count,
+        } => Box::new(HashSkipListRep::new(prefix_len, bucket_count)),
+    }
+}
+
+pub struct SkipListRep {
+    inner: SkipList<KeyComparator>,
+}
+
+impl SkipListRep {
+    pub fn new(max_height: usize, branching_factor: usize) -> Self {
+        SkipListRep {
+            inner: SkipList::with_config(KeyComparator::new(), max_height, branching_factor),
+        }
+    }
+}
+
+impl MemTableRep for SkipListRep {
+    fn insert(&self, entry: &[u8]) {
+        self.inner.insert(entry)
+    }
+
+    fn seek(&self, key: &[u8]) -> Option<Bytes> {
+        self.inner.seek(key).map(Bytes::from)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = Bytes> + 'a> {
+        Box::new(self.inner.iter().map(Bytes::from))
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.inner.data_usage()
+    }
+
+    fn empty(&self) -> bool {
+        self.inner.empty()
+    }
+}
@@ -1,154 +1,247 @@
-use bytes::Bytes;
 use comparator::Comparator;
 use rand;
 use rand::Rng;
 use std::cmp::Ordering;
-
-const DATA_SIZE: usize = 8192;
-const MAX_HEIGHT: usize = 7;
-
-#[derive(Debug)]
+use std::marker::PhantomData;
+use std::sync::atomic::{self, AtomicPtr, AtomicUsize};
+use std::sync::Mutex;
+use std::{mem, ptr, slice};
+
+use super::arena::Arena;
+
+pub const DEFAULT_MAX_HEIGHT: usize = 12;
+pub const DEFAULT_BRANCHING_FACTOR: usize = 4;
+
+// Nodes and keys are allocated in the arena and linked by raw pointers.
+//
+// Thread safety: writes require external synchronization among writers, which is done by
+// holding `arena` lock while inserting. Reads never lock. A node is fully initialized before
+// it is published by a release store to its predecessor, and readers follow links with
+// acquire loads, so a reader always observes a consistent node.
 pub struct SkipList<T> {
-    data: Bytes,
-    index: Vec<SkipValueIndex>,
+    head: *mut Node,
+    // Height of the entire list. Modified only by insert, read racily by readers,
+    // but stale values are ok.
+    max_height: AtomicUsize,
+    height_limit: usize,
+    branching: usize,
+    arena: Mutex<Arena>,
     cmp: T,
 }
 
+unsafe impl<T: Send> Send for SkipList<T> {}
+unsafe impl<T: Sync> Sync for SkipList<T> {}
+
 impl<T: Comparator> SkipList<T> {
     pub fn new(c: T) -> Self {
+        SkipList::with_config(c, DEFAULT_MAX_HEIGHT, DEFAULT_BRANCHING_FACTOR)
+    }
+
+    // A node gets one more level with probability 1/branching up to max_height levels
+    pub fn with_config(c: T, max_height: usize, branching: usize) -> Self {
+        assert!(max_height > 0, "max_height must be positive");
+        assert!(branching > 1, "branching factor must be greater than 1");
+
+        let mut arena = Arena::new();
+        let head = Node::new(&mut arena, &[], max_height);
         SkipList {
-            data: Bytes::with_capacity(DATA_SIZE),
-            index: vec![SkipValueIndex::new(0, 0, 0)],
+            head: head,
+            max_height: AtomicUsize::new(1),
+            height_limit: max_height,
+            branching: branching,
+            arena: Mutex::new(arena),
             cmp: c,
         }
     }
 
     pub fn data_usage(&self) -> usize {
-        self.data.len()
+        self.arena.lock().unwrap().memory_usage()
     }
 
     pub fn empty(&self) -> bool {
-        self.data_usage() == 0
+        unsafe { Node::next(self.head, 0).is_null() }
     }
 
-    pub fn seek(&self, key: &Bytes) -> Option<Bytes> {
-        let sv = self.find_greater_than_eq(key, &mut None);
-        if sv.id == self.head().id {
+    // Returns the first entry which is greater than or equal to key
+    pub fn seek<'a>(&'a self, key: &[u8]) -> Option<&'a [u8]> {
+        let node = self.find_greater_or_equal(key, None);
+        if node.is_null() {
             None
         } else {
-            Some(self.load(sv))
+            Some(unsafe { Node::key(node) })
         }
     }
 
-    pub fn insert(&mut self, key: Bytes) {
-        let mut prev = [0; MAX_HEIGHT];
-        let _ = {
-            self.find_greater_than_eq(&key, &mut Some(&mut prev));
-        };
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.seek(key)
+            .map(|v| self.cmp.compare(key, v) == Ordering::Equal)
+            .unwrap_or(false)
+    }
 
-        let new_index = self.index.len();
-        let mut new_v = SkipValueIndex::new(new_index, self.data.len(), key.len());
-        self.store(key);
+    // REQUIRES: nothing that compares equal to key is currently in the list.
+    pub fn insert(&self, key: &[u8]) {
+        let mut arena = self.arena.lock().unwrap();
 
-        let height = rand::thread_rng().gen_range(1, MAX_HEIGHT);
-        for level in 0..height {
-            let i = prev[level];
-            let next_i = self.index[i].next(level);
-            self.index[i].set_next(level, new_index as u16);
-            new_v.set_next(level, next_i as u16);
+        let mut prev = vec![self.head; self.height_limit];
+        self.find_greater_or_equal(key, Some(&mut prev));
+
+        let height = self.random_height();
+        let current_height = self.current_height();
+        if height > current_height {
+            // prev is already filled with head for the upper levels. It is ok to mutate
+            // max_height without any synchronization with concurrent readers. A reader that
+            // observes the new value will see either null or the new node from head.
+            self.max_height.store(height, atomic::Ordering::Relaxed);
         }
 
-        self.index.push(new_v);
+        let node = Node::new(&mut arena, key, height);
+        for level in 0..height {
+            unsafe {
+                // no_barrier is ok since the node is published by the next set_next
+                Node::no_barrier_set_next(node, level, Node::no_barrier_next(prev[level], level));
+                Node::set_next(prev[level], level, node);
+            }
+        }
     }
 
-    fn find_greater_than_eq(
-        &self,
-        key: &Bytes,
-        prev: &mut Option<&mut [usize; MAX_HEIGHT]>,
-    ) -> &SkipValueIndex {
-        let mut level = MAX_HEIGHT;
-        let mut sv: &SkipValueIndex = self.head();
-        let mut next = self.head();
-
-        while level > 0 {
-            next = &self.index[sv.next(level - 1)];
-            if next.id != self.head().id
-                && self.cmp.compare(key, &self.load(&next)) == Ordering::Greater
-            {
-                sv = next;
-            } else {
-                prev.as_mut().map(|p| p[level - 1] = sv.id);
-                level -= 1;
-            }
+    pub fn iter<'a>(&'a self) -> SkipListIterator<'a, T> {
+        SkipListIterator {
+            node: self.head,
+            marker: PhantomData,
         }
-        next
     }
 
-    fn head(&self) -> &SkipValueIndex {
-        &self.index[0]
+    fn current_height(&self) -> usize {
+        self.max_height.load(atomic::Ordering::Relaxed)
     }
 
-    fn load(&self, sr: &SkipValueIndex) -> Bytes {
-        self.data.slice(sr.idx, sr.idx + sr.size)
+    fn random_height(&self) -> usize {
+        let mut rng = rand::thread_rng();
+        let mut height = 1;
+        while height < self.height_limit && rng.gen_range(0, self.branching) == 0 {
+            height += 1;
+        }
+        height
     }
 
-    fn store(&mut self, sr: Bytes) {
-        self.data.extend(sr);
+    // Returns true if key is greater than the data stored in node. null is considered infinite.
+    fn key_is_after_node(&self, key: &[u8], node: *const Node) -> bool {
+        !node.is_null() && self.cmp.compare(unsafe { Node::key(node) }, key) == Ordering::Less
     }
 
-    pub fn iter<'a>(&'a self) -> SkipListIterator<'a, T> {
-        SkipListIterator {
-            pos: 0,
-            inner: &self,
+    // Returns the earliest node that comes at or after key, or null if there is no such node.
+    // If prev is some, fills prev[level] with pointer to previous node at level for every level.
+    fn find_greater_or_equal(
+        &self,
+        key: &[u8],
+        mut prev: Option<&mut Vec<*mut Node>>,
+    ) -> *mut Node {
+        let mut x = self.head;
+        let mut level = self.current_height() - 1;
+
+        loop {
+            let next = unsafe { Node::next(x, level) };
+            if self.key_is_after_node(key, next) {
+                // Keep searching in this list
+                x = next;
+            } else {
+                if let Some(ref mut p) = prev {
+                    p[level] = x;
+                }
+
+                if level == 0 {
+                    return next;
+                }
+                // Switch to next list
+                level -= 1;
+            }
         }
     }
 }
 
-// INDEX data format
-#[derive(Debug, Clone)]
-struct SkipValueIndex {
-    pub id: usize,
-    pub size: usize,
-    pub idx: usize,
-    nexts: [u16; MAX_HEIGHT],
+// Node header. `height` links follow the header in the same arena allocation.
+#[repr(C)]
+struct Node {
+    key: *const u8,
+    key_len: usize,
 }
 
-impl SkipValueIndex {
-    pub fn new(id: usize, idx: usize, size: usize) -> Self {
-        Self {
-            id,
-            idx,
-            size,
-            nexts: [0; MAX_HEIGHT],
+impl Node {
+    fn new(arena: &mut Arena, key: &[u8], height: usize) -> *mut Node {
+        let key_ptr = if key.len() == 0 {
+            ptr::null()
+        } else {
+            let p = arena.allocate(key.len());
+            unsafe { ptr::copy_nonoverlapping(key.as_ptr(), p, key.len()) };
+            p as *const u8
+        };
+
+        let size = mem::size_of::<Node>() + mem::size_of::<AtomicPtr<Node>>() * height;
+        let node = arena.allocate_aligned(size) as *mut Node;
+        unsafe {
+            ptr::write(
+                node,
+                Node {
+                    key: key_ptr,
+                    key_len: key.len(),
+                },
+            );
+
+            for level in 0..height {
+                ptr::write(Node::slot(node, level), AtomicPtr::new(ptr::null_mut()));
+            }
+        }
+        node
+    }
+
+    unsafe fn key<'a>(node: *const Node) -> &'a [u8] {
+        if (*node).key_len == 0 {
+            &[]
+        } else {
+            slice::from_raw_parts((*node).key, (*node).key_len)
         }
     }
 
-    pub fn next(&self, level: usize) -> usize {
-        self.nexts[level] as usize
+    unsafe fn slot(node: *const Node, level: usize) -> *mut AtomicPtr<Node> {
+        let links = (node as *mut u8).add(mem::size_of::<Node>()) as *mut AtomicPtr<Node>;
+        links.add(level)
     }
 
-    pub fn set_next(&mut self, level: usize, v: u16) {
-        self.nexts[level] = v;
+    // Use an acquire load so that we observe a fully initialized version of the returned node.
+    unsafe fn next(node: *const Node, level: usize) -> *mut Node {
+        (*Node::slot(node, level)).load(atomic::Ordering::Acquire)
+    }
+
+    // Use a release store so that anybody who reads through this pointer observes a fully
+    // initialized version of the inserted node.
+    unsafe fn set_next(node: *const Node, level: usize, x: *mut Node) {
+        (*Node::slot(node, level)).store(x, atomic::Ordering::Release)
+    }
+
+    unsafe fn no_barrier_next(node: *const Node, level: usize) -> *mut Node {
+        (*Node::slot(node, level)).load(atomic::Ordering::Relaxed)
+    }
+
+    unsafe fn no_barrier_set_next(node: *const Node, level: usize, x: *mut Node) {
+        (*Node::slot(node, level)).store(x, atomic::Ordering::Relaxed)
     }
 }
 
 pub struct SkipListIterator<'a, T: 'a> {
-    inner: &'a SkipList<T>,
-    pos: usize,
+    node: *const Node,
+    marker: PhantomData<&'a SkipList<T>>,
 }
 
 impl<'a, T: Comparator> Iterator for SkipListIterator<'a, T> {
-    type Item = Bytes;
+    type Item = &'a [u8];
 
     fn next(&mut self) -> Option<Self::Item> {
-        let next_id = self.inner.index[self.pos].next(0); // level 0
-        self.pos = next_id;
-
-        let ref next = self.inner.index[next_id];
-        if next.id == self.inner.head().id {
+        let next = unsafe { Node::next(self.node, 0) }; // level 0
+        if next.is_null() {
             None
         } else {
-            Some(self.inner.load(next))
+            self.node = next;
+            Some(unsafe { Node::key(next) })
         }
     }
 }
@@ -156,44 +249,137 @@ impl<'a, T: Comparator> Iterator for SkipListIterator<'a, T> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::BTreeSet;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
 
     struct TestKeyComparator;
 
     impl Comparator for TestKeyComparator {
-        fn compare(&self, a: &Bytes, b: &Bytes) -> Ordering {
+        fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
             a.cmp(b)
         }
     }
 
+    #[test]
+    fn skiplist_empty() {
+        let sl = SkipList::new(TestKeyComparator);
+        assert!(sl.empty());
+        assert_eq!(sl.seek(b"key"), None);
+        assert_eq!(sl.iter().next(), None);
+    }
+
     #[test]
     fn skiplist_set_and_get_test() {
-        let mut sl = SkipList::new(TestKeyComparator);
+        let sl = SkipList::new(TestKeyComparator);
 
         for i in 0..100 {
-            let key = Bytes::from(format!("key{:?}", i));
-            sl.insert(key.clone());
-            assert_eq!(sl.seek(&key), Some(key))
+            let key = format!("key{:?}", i);
+            sl.insert(key.as_bytes());
+            assert_eq!(sl.seek(key.as_bytes()), Some(key.as_bytes()));
+            assert!(sl.contains(key.as_bytes()));
         }
     }
 
     #[test]
     fn skiplist_iterator_value_is_ordered() {
-        let mut sl = SkipList::new(TestKeyComparator);
+        let sl = SkipList::new(TestKeyComparator);
 
-        let keys: Vec<Bytes> = (0..10)
-            .map(|v: usize| Bytes::from(format!("key{:?}", v)))
-            .collect();
+        let keys: Vec<String> = (0..10).map(|v: usize| format!("key{:?}", v)).collect();
 
         {
             keys.clone().reverse();
             for k in keys.iter() {
-                sl.insert(k.clone())
+                sl.insert(k.as_bytes())
             }
         }
 
         let mut iter = sl.iter();
         for k in keys {
-            assert_eq!(k, iter.next().unwrap());
+            assert_eq!(k.as_bytes(), iter.next().unwrap());
+        }
+    }
+
+    #[test]
+    fn skiplist_insert_and_lookup() {
+        let sl = SkipList::with_config(TestKeyComparator, 7, 2);
+        let mut keys = BTreeSet::new();
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..2000 {
+            let key = format!("{:05}", rng.gen_range(0, 5000));
+            if keys.insert(key.clone()) {
+                sl.insert(key.as_bytes());
+            }
+        }
+
+        for i in 0..5000 {
+            let key = format!("{:05}", i);
+            assert_eq!(sl.contains(key.as_bytes()), keys.contains(&key));
+
+            let expected = keys.range(key.clone()..).next().map(|v| v.as_bytes());
+            assert_eq!(sl.seek(key.as_bytes()), expected);
+        }
+
+        let actual: Vec<&[u8]> = sl.iter().collect();
+        let expected: Vec<&[u8]> = keys.iter().map(|v| v.as_bytes()).collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn skiplist_more_than_u16_entries() {
+        let sl = SkipList::new(TestKeyComparator);
+        let size = 70000;
+
+        for i in (0..size).rev() {
+            sl.insert(format!("{:08}", i).as_bytes());
+        }
+
+        let mut count = 0;
+        for (i, v) in sl.iter().enumerate() {
+            assert_eq!(v, format!("{:08}", i).as_bytes());
+            count += 1;
+        }
+        assert_eq!(count, size);
+        assert!(sl.data_usage() > size * 8);
+    }
+
+    #[test]
+    fn skiplist_concurrent_readers() {
+        let sl = Arc::new(SkipList::new(TestKeyComparator));
+        let done = Arc::new(AtomicBool::new(false));
+        let size = 10000;
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let sl = sl.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    while !done.load(atomic::Ordering::Acquire) {
+                        // Every observed snapshot must be strictly ordered
+                        let mut last: Option<&[u8]> = None;
+                        for v in sl.iter() {
+                            if let Some(l) = last {
+                                assert!(l < v);
+                            }
+                            last = Some(v);
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for i in 0..size {
+            let key = format!("{:08}", (i * 7919) % size);
+            sl.insert(key.as_bytes());
+            assert!(sl.contains(key.as_bytes()));
+        }
+        done.store(true, atomic::Ordering::Release);
+
+        for r in readers {
+            r.join().unwrap();
         }
+        assert_eq!(sl.iter().count(), size);
     }
 }
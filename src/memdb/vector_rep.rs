@@ -0,0 +1,94 @@
+use comparator::{Comparator, KeyComparator};
+use slice::Bytes;
+use std::cmp::Ordering;
+use std::mem;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::RwLock;
+
+use super::rep::MemTableRep;
+
+// VectorRep appends entries without ordering them. Entries are sorted lazily when the rep is read,
+// so a bulk load followed by a flush sorts only once.
+pub struct VectorRep {
+    inner: RwLock<Entries>,
+    memory_usage: AtomicUsize,
+    cmp: KeyComparator,
+}
+
+struct Entries {
+    values: Vec<Bytes>,
+    sorted: bool,
+}
+
+impl VectorRep {
+    pub fn new() -> Self {
+        VectorRep {
+            inner: RwLock::new(Entries {
+                values: vec![],
+                sorted: true,
+            }),
+            memory_usage: AtomicUsize::new(0),
+            cmp: KeyComparator::new(),
+        }
+    }
+
+    // Calls f with sorted entries. Only the first reader after inserts pays for sorting.
+    fn with_sorted<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&Vec<Bytes>) -> R,
+    {
+        {
+            let entries = self.inner.read().unwrap();
+            if entries.sorted {
+                return f(&entries.values);
+            }
+        }
+
+        let mut entries = self.inner.write().unwrap();
+        if !entries.sorted {
+            let cmp = &self.cmp;
+            entries.values.sort_by(|a, b| cmp.compare(a, b));
+            entries.sorted = true;
+        }
+        f(&entries.values)
+    }
+}
+
+impl MemTableRep for VectorRep {
+    fn insert(&self, entry: &[u8]) {
+        let mut entries = self.inner.write().unwrap();
+        entries.values.push(Bytes::from(entry));
+        entries.sorted = false;
+        self.memory_usage.fetch_add(
+            entry.len() + mem::size_of::<Bytes>(),
+            atomic::Ordering::Relaxed,
+        );
+    }
+
+    fn seek(&self, key: &[u8]) -> Option<Bytes> {
+        let cmp = &self.cmp;
+        self.with_sorted(|values| {
+            // Find the first entry which is greater than or equal to key
+            let idx = match values.binary_search_by(|v| match cmp.compare(v, key) {
+                Ordering::Equal => Ordering::Greater,
+                o => o,
+            }) {
+                Ok(i) => i,
+                Err(i) => i,
+            };
+            values.get(idx).cloned()
+        })
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = Bytes> + 'a> {
+        Box::new(self.with_sorted(|values| values.clone()).into_iter())
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.memory_usage.load(atomic::Ordering::Relaxed)
+    }
+
+    fn empty(&self) -> bool {
+        self.inner.read().unwrap().values.is_empty()
+    }
+}
@@ -0,0 +1,187 @@
+use env::{self, Env, Logger};
+use memdb::{DEFAULT_BRANCHING_FACTOR, DEFAULT_MAX_HEIGHT};
+use std::sync::Arc;
+
+pub struct Options {
+    // Amount of data to build up in a memtable before it is switched to an immutable one
+    pub write_buffer_size: usize,
+    // Maximum number of memtables, both mutable and immutable. Writers wait for a flush
+    // when all of them are full.
+    pub max_write_buffer_number: usize,
+    pub memtable_rep: MemTableRepType,
+    // Number of level 0 files to start compaction
+    pub l0_compaction_trigger: usize,
+    // Number of level 0 files at which every write is delayed a bit
+    pub l0_slowdown_writes_trigger: usize,
+    // Number of level 0 files at which writes are stopped until compaction catches up
+    pub l0_stop_writes_trigger: usize,
+    // Size of a table file written by compaction
+    pub max_file_size: usize,
+    // Used to access files and to run background work. Defaults to the operating system.
+    pub env: Arc<dyn Env>,
+    // Reads table files through mmap. The number of mmapped files is limited by Env, and
+    // the rest of the files are read by pread.
+    pub use_mmap_reads: bool,
+    // Messages about recovery, flushes, compactions and so on are written to info_log.
+    // If None, they are written to LOG in the DB directory, and the old one is kept as LOG.old.
+    pub info_log: Option<Arc<dyn Logger>>,
+    // If set, open replays the logs only up to this sequence number and drops the later
+    // writes in them, so that a checkpoint with its logs is restored to a point in time.
+    // Writes already in the tables can not be dropped, so open fails if there are any
+    // after the sequence number.
+    pub recover_to_sequence: Option<u64>,
+    // Moves obsolete logs to archive/ in the DB directory instead of deleting them, so that
+    // updates_since can read older writes. Archived logs are deleted after wal_ttl_seconds
+    // since their last write, and the oldest ones are deleted while the archive is larger
+    // than wal_size_limit bytes. Both are unlimited if None.
+    pub archive_wal: bool,
+    pub wal_ttl_seconds: Option<u64>,
+    pub wal_size_limit: Option<u64>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            write_buffer_size: 4 << 20,
+            max_write_buffer_number: 2,
+            memtable_rep: Default::default(),
+            l0_compaction_trigger: 4,
+            l0_slowdown_writes_trigger: 8,
+            l0_stop_writes_trigger: 12,
+            max_file_size: 2 << 20,
+            env: env::default_env(),
+            use_mmap_reads: true,
+            info_log: None,
+            recover_to_sequence: None,
+            archive_wal: false,
+            wal_ttl_seconds: None,
+            wal_size_limit: None,
+        }
+    }
+}
+
+impl Options {
+    pub fn validate(&self) -> Result<(), String> {
+        // An empty memtable already uses a block of its arena
+        if self.write_buffer_size < 64 << 10 {
+            return Err("write_buffer_size must be at least 64KB".to_owned());
+        }
+
+        if self.max_write_buffer_number < 2 {
+            return Err("max_write_buffer_number must be at least 2".to_owned());
+        }
+
+        if self.l0_slowdown_writes_trigger < self.l0_compaction_trigger {
+            return Err(
+                "l0_slowdown_writes_trigger must not be less than l0_compaction_trigger".to_owned(),
+            );
+        }
+
+        if self.l0_stop_writes_trigger < self.l0_slowdown_writes_trigger {
+            return Err(
+                "l0_stop_writes_trigger must not be less than l0_slowdown_writes_trigger"
+                    .to_owned(),
+            );
+        }
+
+        match self.memtable_rep {
+            MemTableRepType::SkipList {
+                max_height,
+                branching_factor,
+            } => {
+                if max_height == 0 {
+                    return Err("max_height of memtable_rep must be positive".to_owned());
+                }
+                if branching_factor < 2 {
+                    return Err("branching_factor of memtable_rep must be at least 2".to_owned());
+                }
+            }
+            MemTableRepType::HashSkipList { bucket_count, .. } => {
+                if bucket_count == 0 {
+                    return Err("bucket_count of memtable_rep must be positive".to_owned());
+                }
+            }
+            MemTableRepType::Vector => {}
+        }
+        Ok(())
+    }
+}
+
+// Data structure which backs the memtable.
+#[derive(Clone, Debug)]
+pub enum MemTableRepType {
+    // Sorted on insert. Good for mixed read and write workloads.
+    SkipList {
+        max_height: usize,
+        branching_factor: usize,
+    },
+    // Appends entries to a vector and sorts them on flush or on first read.
+    // Good for bulk loading when reads hit the memtable rarely.
+    Vector,
+    // A hash table of skiplists keyed by the first prefix_len bytes of the user key.
+    // Point lookups only search the bucket of the key's prefix.
+    HashSkipList {
+        prefix_len: usize,
+        bucket_count: usize,
+    },
+}
+
+impl Default for MemTableRepType {
+    fn default() -> MemTableRepType {
+        MemTableRepType::SkipList {
+            max_height: DEFAULT_MAX_HEIGHT,
+            branching_factor: DEFAULT_BRANCHING_FACTOR,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct WriteOptions {
+    // If true, the write is synced to the log file before it returns, so it survives a
+    // power loss. Otherwise a crash of the machine may lose the most recent writes, while
+    // a crash of the process alone does not.
+    pub sync: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options_with_rep(memtable_rep: MemTableRepType) -> Options {
+        let mut options = Options::default();
+        options.memtable_rep = memtable_rep;
+        options
+    }
+
+    #[test]
+    fn validate_skiplist_max_height() {
+        let options = options_with_rep(MemTableRepType::SkipList {
+            max_height: 0,
+            branching_factor: DEFAULT_BRANCHING_FACTOR,
+        });
+        assert!(options.validate().unwrap_err().contains("max_height"));
+    }
+
+    #[test]
+    fn validate_skiplist_branching_factor() {
+        for &branching_factor in &[0, 1] {
+            let options = options_with_rep(MemTableRepType::SkipList {
+                max_height: DEFAULT_MAX_HEIGHT,
+                branching_factor,
+            });
+            assert!(options.validate().unwrap_err().contains("branching_factor"));
+        }
+        assert!(options_with_rep(MemTableRepType::default())
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn validate_hash_skiplist_bucket_count() {
+        let options = options_with_rep(MemTableRepType::HashSkipList {
+            prefix_len: 4,
+            bucket_count: 0,
+        });
+        assert!(options.validate().unwrap_err().contains("bucket_count"));
+    }
+}
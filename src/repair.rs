@@ -0,0 +1,477 @@
+use bytes::Bytes;
+use std::sync::Arc;
+
+use batch::WriteBatch;
+use comparator::KeyComparator;
+use env::{Env, EnvOptions, Logger};
+use filename::{self, FileType};
+use ikey::InternalKey;
+use leveldb::{lock_db, open_info_log};
+use log_record::{LogReader, LogWriter};
+use memdb::MemDB;
+use options::Options;
+use table::{self, table_builder, Table};
+use version::{FileMetaData, FileMetaDataBuilder, VersionEdit};
+
+// Rebuilds the DB in dir from the files which survive, when its MANIFEST or CURRENT is lost
+// or corrupted. Logs are converted to tables, every table is scanned for its key range,
+// and a new MANIFEST puts all tables at level 0. Files which can not be read are moved to
+// lost/ in dir. Some data may be lost, so the DB should be checked after the repair.
+pub fn repair(dir: &str, options: Options) -> Result<(), String> {
+    let env = options.env.clone();
+    let db_lock = lock_db(&*env, dir)?;
+
+    let result = open_info_log(&options, dir).and_then(|info_log| {
+        let repairer = Repairer {
+            dbname: dir.to_owned(),
+            env: env.clone(),
+            options,
+            info_log,
+            next_file_number: 1,
+            manifests: vec![],
+            logs: vec![],
+            table_numbers: vec![],
+            tables: vec![],
+            max_sequence: 0,
+        };
+        repairer.run()
+    });
+
+    let unlocked = env.unlock_file(db_lock);
+    result.and(unlocked)
+}
+
+struct Repairer {
+    dbname: String,
+    env: Arc<dyn Env>,
+    options: Options,
+    info_log: Arc<dyn Logger>,
+    next_file_number: u64,
+    // Paths of the old manifests, which are replaced by a new one
+    manifests: Vec<String>,
+    logs: Vec<u64>,
+    // Tables to scan, including those converted from logs
+    table_numbers: Vec<u64>,
+    // Tables which are readable
+    tables: Vec<FileMetaData>,
+    max_sequence: u64,
+}
+
+impl Repairer {
+    fn run(mut self) -> Result<(), String> {
+        self.find_files()?;
+        self.convert_log_files_to_tables();
+        self.extract_meta_data();
+        self.write_descriptor()?;
+
+        info_log!(
+            self.info_log,
+            "**** Repaired leveldb {:}; recovered {:} files; {:} bytes. Some data may have been lost. ****",
+            self.dbname,
+            self.tables.len(),
+            self.tables.iter().map(|f| f.file_size).sum::<u64>()
+        );
+        Ok(())
+    }
+
+    fn find_files(&mut self) -> Result<(), String> {
+        for name in self.env.get_children(&self.dbname)? {
+            let path = format!("{:}/{:}", self.dbname, name);
+            match FileType::parse_name(&path) {
+                Some(FileType::Manifest(_, num)) => {
+                    self.mark_file_num_used(num as u64);
+                    self.manifests.push(path.clone());
+                }
+                Some(FileType::Log(_, num)) => {
+                    self.mark_file_num_used(num);
+                    self.logs.push(num);
+                }
+                Some(FileType::Table(_, num)) => {
+                    self.mark_file_num_used(num);
+                    self.table_numbers.push(num);
+                }
+                _ => {}
+            }
+        }
+
+        if self.manifests.is_empty() && self.logs.is_empty() && self.table_numbers.is_empty() {
+            return Err(format!("repair found no files in {:}", self.dbname));
+        }
+        self.logs.sort();
+        self.table_numbers.sort();
+        Ok(())
+    }
+
+    fn mark_file_num_used(&mut self, num: u64) {
+        if self.next_file_number <= num {
+            self.next_file_number = num + 1;
+        }
+    }
+
+    fn new_file_number(&mut self) -> u64 {
+        let num = self.next_file_number;
+        self.next_file_number += 1;
+        num
+    }
+
+    fn convert_log_files_to_tables(&mut self) {
+        for log in self.logs.clone() {
+            if let Err(msg) = self.convert_log_to_table(log) {
+                info_log!(self.info_log, "Log #{:}: ignoring conversion error: {:}", log, msg);
+            }
+            let fname = FileType::Log(&self.dbname, log).filename();
+            self.archive_file(&fname);
+        }
+    }
+
+    fn convert_log_to_table(&mut self, log: u64) -> Result<(), String> {
+        let fname = FileType::Log(&self.dbname, log).filename();
+        let reader = self.env.new_sequential_file(&fname).map(LogReader::new)?;
+
+        // A corrupted record is skipped rather than failing the whole log
+        let mem = MemDB::with_rep(&self.options.memtable_rep);
+        let mut counter = 0;
+        for record in reader {
+            let result = WriteBatch::load_data(record)
+                .and_then(|batch| batch.insert_into(&mem).map(|_| batch.count()));
+            match result {
+                Ok(count) => counter += count,
+                Err(msg) => info_log!(self.info_log, "Log #{:}: ignoring {:}", log, msg),
+            }
+        }
+
+        if mem.empty() {
+            return Ok(());
+        }
+        let num = self.new_file_number();
+        table::bulid(&*self.env, &self.dbname, &mut mem.iter(), num)?;
+        info_log!(
+            self.info_log,
+            "Log #{:}: {:} ops saved to Table #{:}",
+            log,
+            counter,
+            num
+        );
+        self.table_numbers.push(num);
+        Ok(())
+    }
+
+    fn extract_meta_data(&mut self) {
+        for num in self.table_numbers.clone() {
+            self.scan_table(num);
+        }
+    }
+
+    // Adds the table of num to tables if it is readable. A table with a corrupted block is
+    // rewritten with the entries before the block, and the original is archived.
+    fn scan_table(&mut self, num: u64) {
+        let fname = FileType::Table(&self.dbname, num).filename();
+        let table = self.env.get_file_size(&fname).and_then(|size| {
+            let file = self.env
+                .new_random_access_file(&fname, &EnvOptions::default())?;
+            let table = Table::open(size as usize, file, Arc::new(KeyComparator::new()))?;
+            Ok((table, size))
+        });
+        let (table, size) = match table {
+            Ok(v) => v,
+            Err(msg) => {
+                info_log!(self.info_log, "Table #{:}: {:}; archiving", num, msg);
+                self.archive_file(&fname);
+                return;
+            }
+        };
+
+        let mut counter = 0;
+        let mut smallest: Option<Bytes> = None;
+        let mut largest = Bytes::new();
+        let mut max_sequence = 0;
+        let result = table.scan_checked(|k, _| {
+            let seq = InternalKey::from(k.clone()).seq_number() as u64;
+            if max_sequence < seq {
+                max_sequence = seq;
+            }
+            if smallest.is_none() {
+                smallest = Some(k.clone());
+            }
+            largest = k;
+            counter += 1;
+        });
+        match result {
+            Ok(()) => info_log!(self.info_log, "Table #{:}: {:} entries", num, counter),
+            Err(ref msg) => info_log!(
+                self.info_log,
+                "Table #{:}: {:} entries before {:}",
+                num,
+                counter,
+                msg
+            ),
+        }
+
+        match (result, smallest) {
+            (Ok(()), Some(smallest)) => {
+                let mut meta = FileMetaDataBuilder::new();
+                meta.file_num(num);
+                meta.file_size(size);
+                meta.smallest(InternalKey::from(smallest));
+                meta.largest(InternalKey::from(largest));
+                meta.level(0);
+                match meta.build() {
+                    Ok(meta) => {
+                        if self.max_sequence < max_sequence {
+                            self.max_sequence = max_sequence;
+                        }
+                        self.tables.push(meta);
+                    }
+                    Err(msg) => {
+                        info_log!(self.info_log, "Table #{:}: {:}; archiving", num, msg);
+                        self.archive_file(&fname);
+                    }
+                }
+            }
+            (Ok(()), None) => {
+                info_log!(self.info_log, "Table #{:}: empty; archiving", num);
+                self.archive_file(&fname);
+            }
+            (Err(_), _) => {
+                if counter > 0 {
+                    match self.repair_table(&table) {
+                        Ok(new_num) => {
+                            info_log!(
+                                self.info_log,
+                                "Table #{:}: {:} entries repaired to Table #{:}",
+                                num,
+                                counter,
+                                new_num
+                            );
+                            self.scan_table(new_num);
+                        }
+                        Err(msg) => {
+                            info_log!(self.info_log, "Table #{:}: failed to repair: {:}", num, msg)
+                        }
+                    }
+                }
+                self.archive_file(&fname);
+            }
+        }
+    }
+
+    // Copies the readable entries of table into a new table and returns its number
+    fn repair_table(&mut self, table: &Table) -> Result<u64, String> {
+        let num = self.new_file_number();
+        let fname = FileType::Table(&self.dbname, num).filename();
+        let mut builder = table_builder::new(&*self.env, &fname)?;
+
+        let mut result = Ok(());
+        // The scan fails at the corrupted block again
+        let _ = table.scan_checked(|k, v| {
+            if result.is_ok() {
+                result = builder.add(&k, &v);
+            }
+        });
+        let result = result
+            .and_then(|_| builder.build())
+            .and_then(|_| builder.sync());
+        if result.is_err() {
+            let _ = self.env.remove_file(&fname);
+        }
+        result.map(|_| num)
+    }
+
+    fn write_descriptor(&mut self) -> Result<(), String> {
+        let manifest_num = self.new_file_number();
+        let mut edit = VersionEdit::new(self.next_file_number);
+        edit.last_sequence = self.max_sequence;
+        for meta in &self.tables {
+            edit.add_file(meta.clone());
+        }
+
+        let fname = FileType::Manifest(&self.dbname, manifest_num as usize).filename();
+        let result = self.env
+            .new_writable_file(&fname)
+            .map(LogWriter::new)
+            .and_then(|mut writer| {
+                edit.encode_to(&mut writer)?;
+                writer.sync()
+            });
+        if let Err(msg) = result {
+            let _ = self.env.remove_file(&fname);
+            return Err(msg);
+        }
+
+        // The new manifest replaces the old ones
+        for manifest in self.manifests.clone() {
+            self.archive_file(&manifest);
+        }
+        filename::set_current_file(&*self.env, &self.dbname, manifest_num as usize)
+    }
+
+    // Moves fname into lost/ in the DB directory, so that it is kept but not used by the DB
+    fn archive_file(&self, fname: &str) {
+        let lost = format!("{:}/lost", self.dbname);
+        if !self.env.file_exists(&lost) {
+            let _ = self.env.create_dir(&lost);
+        }
+
+        let base = fname.rsplit('/').next().unwrap_or(fname);
+        let new_name = format!("{:}/{:}", lost, base);
+        let result = self.env.rename_file(fname, &new_name);
+        info_log!(
+            self.info_log,
+            "Archiving {:}: {:}",
+            fname,
+            match result {
+                Ok(()) => "OK".to_owned(),
+                Err(msg) => msg,
+            }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use env::{self, MemEnv};
+    use leveldb::{open, open_read_only};
+    use std::io::Read;
+    use test_util::{mem_options, set_all};
+
+    fn files_with_suffix(env: &Arc<dyn Env>, dir: &str, suffix: &str) -> Vec<String> {
+        let mut names: Vec<String> = env.get_children(dir)
+            .unwrap()
+            .into_iter()
+            .filter(|name| name.ends_with(suffix))
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn repair_lost_manifest() {
+        let dbname = "/repair_lost_manifest";
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        {
+            let db = open(dbname, mem_options(&env)).unwrap();
+            set_all(&db, 0..100, 0);
+            db.compact_range(None, None).unwrap();
+            // Left in the log
+            set_all(&db, 50..150, 1);
+        }
+
+        for name in env.get_children(dbname).unwrap() {
+            if name == "CURRENT" || name.starts_with("MANIFEST-") {
+                env.remove_file(&format!("{:}/{:}", dbname, name)).unwrap();
+            }
+        }
+
+        repair(dbname, mem_options(&env)).unwrap();
+        assert!(files_with_suffix(&env, dbname, ".log").is_empty());
+        assert_eq!(files_with_suffix(&env, &format!("{:}/lost", dbname), ".log").len(), 1);
+
+        let db = open(dbname, mem_options(&env)).unwrap();
+        for i in 0..150 {
+            let round = if i < 50 { 0 } else { 1 };
+            assert_eq!(
+                db.get(&format!("key{:04}", i)),
+                Some(Bytes::from(format!("value{:}-{:}", i, round)))
+            );
+        }
+
+        // Sequence numbers are recovered, so new writes hide the old ones
+        set_all(&db, 0..150, 2);
+        assert_eq!(db.get("key0000"), Some(Bytes::from("value0-2")));
+        assert_eq!(db.get("key0100"), Some(Bytes::from("value100-2")));
+    }
+
+    #[test]
+    fn repair_corrupted_table() {
+        let dbname = "/repair_corrupted_table";
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        {
+            let db = open(dbname, mem_options(&env)).unwrap();
+            set_all(&db, 0..1000, 0);
+            db.compact_range(None, None).unwrap();
+            set_all(&db, 1000..1100, 0);
+            db.compact_range(None, None).unwrap();
+        }
+
+        // Corrupt a data block in the middle of the table which has key0000
+        let tables = files_with_suffix(&env, dbname, ".ldb");
+        assert_eq!(tables.len(), 2);
+        let fname = format!("{:}/{:}", dbname, tables[0]);
+        let mut data = vec![];
+        env.new_sequential_file(&fname)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        let middle = data.len() / 2;
+        data[middle] ^= 0xff;
+        env::write_string_to_file(&*env, &data, &fname, true).unwrap();
+
+        repair(dbname, mem_options(&env)).unwrap();
+        let lost = format!("{:}/lost", dbname);
+        assert_eq!(files_with_suffix(&env, &lost, ".ldb"), vec![tables[0].clone()]);
+
+        // Keys before the corrupted block and keys of the other table survive
+        let db = open(dbname, mem_options(&env)).unwrap();
+        assert_eq!(db.get("key0000"), Some(Bytes::from("value0-0")));
+        assert_eq!(db.get("key1050"), Some(Bytes::from("value1050-0")));
+        let count = db.iter().count();
+        assert!(count > 100 && count < 1100, "{:}", count);
+    }
+
+    #[test]
+    fn repair_no_files() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        env.create_dir("/repair_no_files").unwrap();
+        let err = repair("/repair_no_files", mem_options(&env)).unwrap_err();
+        assert!(err.contains("repair found no files"), "{}", err);
+    }
+
+    #[test]
+    fn repair_corrupted_manifest() {
+        let dbname = "/repair_corrupted_manifest";
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        {
+            let db = open(dbname, mem_options(&env)).unwrap();
+            set_all(&db, 0..100, 0);
+            db.compact_range(None, None).unwrap();
+        }
+        let manifest = files_with_suffix(&env, dbname, "")
+            .into_iter()
+            .find(|name| name.starts_with("MANIFEST-"))
+            .unwrap();
+        let fname = format!("{:}/{:}", dbname, manifest);
+        let records: Vec<Bytes> = env.new_sequential_file(&fname)
+            .map(LogReader::new)
+            .unwrap()
+            .collect();
+
+        // A record which ends in the middle of a new file
+        {
+            let mut writer = LogWriter::new(env.new_writable_file(&fname).unwrap());
+            for record in &records {
+                writer.add_record(record.clone()).unwrap();
+            }
+            writer.add_record(Bytes::from(vec![7, 1])).unwrap();
+        }
+        let err = open(dbname, mem_options(&env)).err().unwrap();
+        assert!(err.contains("corrupted MANIFEST"), "{}", err);
+        assert!(err.contains("run repair"), "{}", err);
+        assert!(open_read_only(dbname, mem_options(&env)).is_err());
+
+        // A record which does not match its checksum
+        let mut data = vec![];
+        env.new_sequential_file(&fname)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        data[10] ^= 0xff;
+        env::write_string_to_file(&*env, &data, &fname, true).unwrap();
+        let err = open(dbname, mem_options(&env)).err().unwrap();
+        assert!(err.contains("checksum mismatch"), "{}", err);
+
+        repair(dbname, mem_options(&env)).unwrap();
+        let db = open(dbname, mem_options(&env)).unwrap();
+        assert_eq!(db.get("key0050"), Some(Bytes::from("value50-0")));
+    }
+}
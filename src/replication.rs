@@ -0,0 +1,403 @@
+// Replication of a primary DB to followers over TCP.
+//
+// A follower connects to the primary and sends the sequence number to start from. The
+// primary replies with frames of a tag byte and a u32 length followed by the data. A batch
+// frame has the data of a WriteBatch, which has the sequence number of the batch in it. An
+// error frame has a message, and the primary closes the connection after it. Integers are in
+// little endian.
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::Bytes;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use batch::WriteBatch;
+use leveldb::LevelDB;
+
+const BATCH_FRAME: u8 = 1;
+const ERROR_FRAME: u8 = 2;
+const FRAME_HEADER_SIZE: usize = 5;
+// Longer frames are rejected before their data is read, so that a corrupted length does not
+// make the follower allocate up to 4GB. Batches are far smaller, since each one is applied to
+// a memtable as a whole.
+const MAX_FRAME_SIZE: usize = 64 << 20;
+
+// How often the primary checks for new writes and new followers
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+// Followers which do not send the sequence number by then are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Followers which do not read a frame by then are dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Streams the writes of a DB to the followers which connect to it. Writes are read from the
+// logs, so a follower can start only from a sequence number which is still in them, see
+// Options::archive_wal.
+pub struct Primary {
+    local_addr: SocketAddr,
+    shutting_down: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Primary {
+    pub fn start<A: ToSocketAddrs>(db: Arc<LevelDB>, addr: A) -> Result<Primary, String> {
+        let listener = TcpListener::bind(addr).map_err(|e| format!("failed to listen: {:}", e))?;
+        let local_addr = listener
+            .local_addr()
+            .and_then(|addr| listener.set_nonblocking(true).map(|_| addr))
+            .map_err(|e| format!("failed to listen: {:}", e))?;
+
+        let shutting_down = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let shutting_down = shutting_down.clone();
+            thread::spawn(move || accept_followers(&db, &listener, &shutting_down))
+        };
+        Ok(Primary {
+            local_addr,
+            shutting_down,
+            thread: Some(thread),
+        })
+    }
+
+    // Returns the address which the primary listens on, which has the actual port if the
+    // port is 0 in the address given to start
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for Primary {
+    fn drop(&mut self) {
+        self.shutting_down.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// A thread which sends updates to a follower
+struct Sender {
+    // A clone of the stream the thread writes to, to wake it up on shutdown
+    stream: TcpStream,
+    done: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+fn accept_followers(db: &Arc<LevelDB>, listener: &TcpListener, shutting_down: &Arc<AtomicBool>) {
+    let mut senders: Vec<Sender> = vec![];
+    while !shutting_down.load(Ordering::Acquire) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                senders.retain(|sender| !sender.done.load(Ordering::Acquire));
+                let clone = match stream.try_clone() {
+                    Ok(clone) => clone,
+                    Err(_) => continue,
+                };
+                let db = db.clone();
+                let shutting_down = shutting_down.clone();
+                let done = Arc::new(AtomicBool::new(false));
+                let thread = {
+                    let done = done.clone();
+                    thread::spawn(move || {
+                        // An error closes the connection
+                        let _ = send_updates(&db, stream, &shutting_down);
+                        done.store(true, Ordering::Release);
+                    })
+                };
+                senders.push(Sender {
+                    stream: clone,
+                    done,
+                    thread,
+                });
+            }
+            // No follower is waiting, or accept failed and is retried
+            Err(_) => thread::sleep(POLL_INTERVAL),
+        }
+    }
+    for sender in senders {
+        // Wakes up the thread blocked in writing to a follower which does not read
+        let _ = sender.stream.shutdown(Shutdown::Both);
+        let _ = sender.thread.join();
+    }
+}
+
+fn send_updates(db: &LevelDB, mut stream: TcpStream, shutting_down: &AtomicBool) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut buf = [0; 8];
+    stream.read_exact(&mut buf)?;
+    let mut next_seq = LittleEndian::read_u64(&buf);
+
+    while !shutting_down.load(Ordering::Acquire) {
+        // Listing the logs to find next_seq takes a while, so it is done only when there are
+        // new writes
+        if db.last_sequence() < next_seq {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+        let mut updates = match db.updates_since(next_seq) {
+            Ok(updates) => updates,
+            Err(msg) => return write_frame(&mut stream, ERROR_FRAME, msg.as_bytes()),
+        };
+        for (seq, batch) in updates.by_ref() {
+            let data = batch.data();
+            if data.len() > MAX_FRAME_SIZE {
+                let msg = format!("batch at sequence {:} is too large to replicate", seq);
+                return write_frame(&mut stream, ERROR_FRAME, msg.as_bytes());
+            }
+            write_frame(&mut stream, BATCH_FRAME, &data)?;
+            next_seq = seq + batch.count() as u64;
+        }
+        if let Err(msg) = updates.status() {
+            return write_frame(&mut stream, ERROR_FRAME, msg.as_bytes());
+        }
+    }
+    Ok(())
+}
+
+fn write_frame(stream: &mut TcpStream, tag: u8, data: &[u8]) -> io::Result<()> {
+    let mut header = [0; FRAME_HEADER_SIZE];
+    header[0] = tag;
+    LittleEndian::write_u32(&mut header[1..], data.len() as u32);
+    stream.write_all(&header)?;
+    stream.write_all(data)
+}
+
+// Applies the writes of a primary to a DB in order. The DB must be a copy of the primary,
+// such as a checkpoint of it, and must not be written by others.
+pub struct Follower {
+    stream: TcpStream,
+    stopping: Arc<AtomicBool>,
+    error: Arc<Mutex<Option<String>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Follower {
+    // Connects to the primary at addr and catches up from the write after the last one of db
+    pub fn start<A: ToSocketAddrs>(db: Arc<LevelDB>, addr: A) -> Result<Follower, String> {
+        let connect_err = |e: io::Error| format!("failed to connect to the primary: {:}", e);
+        let mut stream = TcpStream::connect(addr).map_err(connect_err)?;
+        let next_seq = db.last_sequence() + 1;
+        let mut buf = [0; 8];
+        LittleEndian::write_u64(&mut buf, next_seq);
+        stream.write_all(&buf).map_err(connect_err)?;
+        let reader = stream.try_clone().map_err(connect_err)?;
+
+        let stopping = Arc::new(AtomicBool::new(false));
+        let error = Arc::new(Mutex::new(None));
+        let thread = {
+            let stopping = stopping.clone();
+            let error = error.clone();
+            thread::spawn(move || {
+                let result = apply_updates(&db, reader, next_seq);
+                if let Err(msg) = result {
+                    if !stopping.load(Ordering::Acquire) {
+                        *error.lock().unwrap() = Some(msg);
+                    }
+                }
+            })
+        };
+        Ok(Follower {
+            stream,
+            stopping,
+            error,
+            thread: Some(thread),
+        })
+    }
+
+    // Returns the error which stopped replication, if any
+    pub fn status(&self) -> Result<(), String> {
+        match *self.error.lock().unwrap() {
+            Some(ref msg) => Err(msg.clone()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::Release);
+        // Wakes up the thread blocked in reading
+        let _ = self.stream.shutdown(Shutdown::Both);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn apply_updates(db: &LevelDB, mut stream: TcpStream, mut next_seq: u64) -> Result<(), String> {
+    let read_err = |e: io::Error| format!("failed to read from the primary: {:}", e);
+    loop {
+        let mut header = [0; FRAME_HEADER_SIZE];
+        match stream.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err("the primary closed the connection".to_owned());
+            }
+            Err(e) => return Err(read_err(e)),
+        }
+        let size = LittleEndian::read_u32(&header[1..]) as usize;
+        if size > MAX_FRAME_SIZE {
+            return Err(format!("frame of {:} bytes is too large", size));
+        }
+        let mut data = vec![0; size];
+        stream.read_exact(&mut data).map_err(read_err)?;
+
+        match header[0] {
+            BATCH_FRAME => {
+                let batch = WriteBatch::load_data(Bytes::from(data))?;
+                let seq = batch.seq() as u64;
+                if seq != next_seq {
+                    return Err(format!(
+                        "expected a batch at sequence {:}, got {:}",
+                        next_seq, seq
+                    ));
+                }
+                // The DB gives the batch the next sequence number of its own
+                if db.last_sequence() + 1 != seq {
+                    return Err(format!(
+                        "the DB is at sequence {:}, which is not from the primary",
+                        db.last_sequence()
+                    ));
+                }
+                next_seq = seq + batch.count() as u64;
+                db.apply(batch)?;
+            }
+            ERROR_FRAME => {
+                return Err(format!("primary: {:}", String::from_utf8_lossy(&data)));
+            }
+            tag => return Err(format!("unknown frame type {:}", tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use env::{Env, MemEnv};
+    use leveldb::open;
+    use std::time::Instant;
+    use test_util::{mem_options, set_all};
+
+    fn wait_for_sequence(follower: &Follower, db: &LevelDB, seq: u64) {
+        let start = Instant::now();
+        while db.last_sequence() < seq {
+            follower.status().unwrap();
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "follower is at {:}",
+                db.last_sequence()
+            );
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    #[test]
+    fn follower_catches_up_from_checkpoint() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let mut options = mem_options(&env);
+        options.archive_wal = true;
+        let primary_db = Arc::new(open("/primary", options).unwrap());
+        set_all(&primary_db, 0..100, 0);
+        primary_db.checkpoint("/follower").unwrap();
+        // Flushed and archived before the follower starts
+        set_all(&primary_db, 50..150, 1);
+        primary_db.compact_range(None, None).unwrap();
+
+        let primary = Primary::start(primary_db.clone(), "127.0.0.1:0").unwrap();
+        let follower_db = Arc::new(open("/follower", mem_options(&env)).unwrap());
+        let follower = Follower::start(follower_db.clone(), primary.local_addr()).unwrap();
+
+        // Streamed as they are written
+        let mut batch = WriteBatch::new();
+        batch.delete("key0000");
+        batch.put("key0150", "value150-2");
+        primary_db.apply(batch).unwrap();
+        set_all(&primary_db, 100..200, 2);
+
+        wait_for_sequence(&follower, &follower_db, primary_db.last_sequence());
+        assert_eq!(follower_db.last_sequence(), primary_db.last_sequence());
+        let primary_entries: Vec<_> = primary_db.iter().collect();
+        let follower_entries: Vec<_> = follower_db.iter().collect();
+        assert_eq!(primary_entries, follower_entries);
+        assert_eq!(follower_db.get("key0000"), None);
+        follower.status().unwrap();
+    }
+
+    #[test]
+    fn follower_fails_without_updates_in_logs() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let primary_db = Arc::new(open("/primary", mem_options(&env)).unwrap());
+        set_all(&primary_db, 0..100, 0);
+        // The log is deleted after the flush
+        primary_db.compact_range(None, None).unwrap();
+        let primary = Primary::start(primary_db.clone(), "127.0.0.1:0").unwrap();
+
+        let follower_db = Arc::new(open("/follower", mem_options(&env)).unwrap());
+        let follower = Follower::start(follower_db.clone(), primary.local_addr()).unwrap();
+        let start = Instant::now();
+        while follower.status().is_ok() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(POLL_INTERVAL);
+        }
+        let err = follower.status().unwrap_err();
+        assert!(err.contains("updates from sequence 1 "), "{}", err);
+        assert_eq!(follower_db.last_sequence(), 0);
+    }
+
+    #[test]
+    fn primary_drops_stalled_follower() {
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let mut options = mem_options(&env);
+        options.archive_wal = true;
+        let primary_db = Arc::new(open("/primary", options).unwrap());
+        let value = "v".repeat(2 << 10);
+        for i in 0..5000 {
+            primary_db.set(&format!("key{:05}", i), &value).unwrap();
+        }
+        let primary = Primary::start(primary_db.clone(), "127.0.0.1:0").unwrap();
+
+        // Asks for updates but never reads them, so the primary blocks once the socket
+        // buffers are full
+        let mut stream = TcpStream::connect(primary.local_addr()).unwrap();
+        let mut buf = [0; 8];
+        LittleEndian::write_u64(&mut buf, 1);
+        stream.write_all(&buf).unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        let start = Instant::now();
+        drop(primary);
+        assert!(start.elapsed() < WRITE_TIMEOUT / 2);
+    }
+
+    #[test]
+    fn follower_rejects_oversized_frame() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let primary = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 8];
+            stream.read_exact(&mut buf).unwrap();
+            let mut header = [BATCH_FRAME, 0, 0, 0, 0];
+            LittleEndian::write_u32(&mut header[1..], u32::max_value());
+            stream.write_all(&header).unwrap();
+            // Kept open, so that the follower fails by the length rather than by the end
+            stream
+        });
+
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let follower_db = Arc::new(open("/follower", mem_options(&env)).unwrap());
+        let follower = Follower::start(follower_db.clone(), addr).unwrap();
+        let start = Instant::now();
+        while follower.status().is_ok() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(POLL_INTERVAL);
+        }
+        let err = follower.status().unwrap_err();
+        assert!(err.contains("too large"), "{}", err);
+        drop(primary.join().unwrap());
+    }
+}
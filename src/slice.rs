@@ -141,24 +141,30 @@ pub trait ByteWrite {
     fn write_slice(&mut self, n: &[u8]);
 }
 
+// put_* methods of BytesMut panic when capacity is short, so reserve it beforehand
 impl ByteWrite for BytesMut {
     fn write_u8(&mut self, n: u8) {
+        self.reserve(1);
         self.put_u8(n);
     }
 
     fn write_u16(&mut self, n: u16) {
+        self.reserve(2);
         self.put_u16_le(n);
     }
 
     fn write_u32(&mut self, n: u32) {
+        self.reserve(4);
         self.put_u32_le(n);
     }
 
     fn write_u64(&mut self, n: u64) {
+        self.reserve(8);
         self.put_u64_le(n);
     }
 
     fn write_i64(&mut self, n: i64) {
+        self.reserve(8);
         self.put_i64_le(n);
     }
 
@@ -1,7 +1,10 @@
 use super::format;
-use random_access_file::RandomAccessFile;
+use comparator::{BytewiseComparator, Comparator};
+use env::RandomAccessFile;
 use slice::{ByteRead, Bytes, U32_BYTE_SIZE};
+use std::cmp::Ordering;
 use std::io;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct Block {
@@ -16,7 +19,7 @@ pub fn read<T: io::Read + io::Seek>(reader: &mut T, bh_value: &Bytes) -> Block {
     block
 }
 
-pub fn read2<T: RandomAccessFile>(inner: &T, bh_value: &Bytes) -> Block {
+pub fn read2(inner: &dyn RandomAccessFile, bh_value: &Bytes) -> Block {
     let bh = format::BlockHandle::decode_from(&mut bh_value.clone());
     let block = format::read_block2(inner, &bh).expect("block ga!!!!");
     block
@@ -24,6 +27,11 @@ pub fn read2<T: RandomAccessFile>(inner: &T, bh_value: &Bytes) -> Block {
 
 impl Block {
     pub fn new(inner: Bytes) -> Self {
+        Block::try_new(inner).expect("invalid block")
+    }
+
+    // Fails if the restart points do not fit in inner
+    pub fn try_new(inner: Bytes) -> Result<Self, String> {
         let size = inner.len();
         let mut b = Block {
             size: size,
@@ -31,17 +39,22 @@ impl Block {
             restart_offset: 0,
         };
 
-        b.restart_offset = if size < U32_BYTE_SIZE {
-            panic!("TODO: fix")
-        } else {
-            size - ((b.restart_count() + 1) * U32_BYTE_SIZE)
+        if size < U32_BYTE_SIZE {
+            return Err("block is too short".to_owned());
+        }
+        b.restart_offset = match (b.restart_count() + 1)
+            .checked_mul(U32_BYTE_SIZE)
+            .and_then(|trailer| size.checked_sub(trailer))
+        {
+            Some(offset) => offset,
+            None => return Err("bad restart points of block".to_owned()),
         };
 
         debug!(
             "new block restart_offset={:?}, size={:?}",
             b.restart_offset, b.size
         );
-        b
+        Ok(b)
     }
 
     pub fn restart_count(&self) -> usize {
@@ -49,10 +62,16 @@ impl Block {
     }
 
     pub fn iter(&self) -> BlockIterator {
+        self.iter_with_comparator(Arc::new(BytewiseComparator))
+    }
+
+    // Keys in the block must be sorted by cmp
+    pub fn iter_with_comparator(&self, cmp: Arc<dyn Comparator>) -> BlockIterator {
         BlockIterator::new(
             self.inner.clone(),
             self.restart_offset,
             self.restart_count(),
+            cmp,
         )
     }
 }
@@ -64,10 +83,16 @@ pub struct BlockIterator {
     pub key: Option<Bytes>,
     pub value: Option<Bytes>,
     current: usize,
+    cmp: Arc<dyn Comparator>,
 }
 
 impl BlockIterator {
-    pub fn new(inner: Bytes, restart_offset: usize, restart_num: usize) -> Self {
+    pub fn new(
+        inner: Bytes,
+        restart_offset: usize,
+        restart_num: usize,
+        cmp: Arc<dyn Comparator>,
+    ) -> Self {
         debug!(
             "new blockiterator restart_offset={:?}, restart_num={:?}",
             restart_offset, restart_num
@@ -79,6 +104,7 @@ impl BlockIterator {
             key: None,
             value: None,
             current: 0,
+            cmp,
         }
     }
 
@@ -87,7 +113,12 @@ impl BlockIterator {
             .get_u32(idx * U32_BYTE_SIZE + self.restart_offset) as usize)
     }
 
+    // Returns the value of the first entry whose key is greater than or equal to key
     pub fn seek(&mut self, key: &Bytes) -> Option<Bytes> {
+        self.seek_entry(key).map(|(_, v)| v)
+    }
+
+    pub fn seek_entry(&mut self, key: &Bytes) -> Option<(Bytes, Bytes)> {
         let mut left = 0;
         let mut right = self.restart_num - 1;
 
@@ -102,8 +133,7 @@ impl BlockIterator {
                 shared, not_shared, value_length, index_key
             );
 
-            // MUST use comparator
-            if &index_key < key {
+            if self.cmp.compare(&index_key, key) == Ordering::Less {
                 left = mid;
             } else {
                 right = mid - 1;
@@ -113,16 +143,14 @@ impl BlockIterator {
         let p = self.restart_point(left).expect("invalid restart pont");
         self.set_seek_point(p);
         while self.parse_key() {
-            if let Some(k) = self.key.as_ref() {
-                if k >= key {
-                    break;
+            if let (Some(k), Some(v)) = (self.key.as_ref(), self.value.as_ref()) {
+                if self.cmp.compare(k, key) != Ordering::Less {
+                    return Some((k.clone(), v.clone()));
                 }
-            } else {
-                break;
             }
         }
 
-        self.value.clone()
+        None
     }
 
     pub fn parse_key(&mut self) -> bool {
@@ -250,4 +278,19 @@ mod tests {
             assert_eq!(block.seek(&d.0).as_ref(), Some(&d.1));
         }
     }
+
+    #[test]
+    fn test_block_iterator_seek_between_and_after_keys() {
+        let mut bb = BlockBuilder::new();
+        let dic = create_seed_helper(30);
+
+        for v in &dic {
+            bb.add(&v.0, &v.1);
+        }
+
+        let mut block = Block::new(bb.build()).iter();
+        assert_eq!(block.seek(&Bytes::from("key")), Some(dic[0].1.clone()));
+        assert_eq!(block.seek(&Bytes::from("key170")), Some(dic[18].1.clone()));
+        assert_eq!(block.seek(&Bytes::from("key30")), None);
+    }
 }
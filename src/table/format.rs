@@ -1,6 +1,7 @@
 use super::table_builder::TRAILER_SIZE;
 use super::{Compression, block::Block};
-use random_access_file::RandomAccessFile;
+use crc::{Hasher32, crc32};
+use env::RandomAccessFile;
 use slice::{ByteRead, ByteWrite, Bytes, BytesMut};
 use std::io;
 
@@ -9,7 +10,7 @@ use std::io;
 // and taking the leading 64 bits.
 
 pub const FOOTER_MAX_LENGTH: usize = 2 * 2 * 8 + 8;
-const TABLE_MAGIC_NUMBER: i64 = 0xdb4775248b80fb57;
+const TABLE_MAGIC_NUMBER: u64 = 0xdb4775248b80fb57;
 
 #[derive(Debug)]
 pub struct BlockHandle {
@@ -78,25 +79,29 @@ impl Footer {
         }
     }
 
-    pub fn decode(input: &[u8]) -> Self {
+    pub fn decode(input: &[u8]) -> Result<Self, String> {
+        if input.len() < FOOTER_MAX_LENGTH {
+            return Err("footer is too short".to_owned());
+        }
+
         let mut slice = Bytes::from(input);
         let index_block_handle = BlockHandle::decode_from(&mut slice);
         let metaindex_block_handle = BlockHandle::decode_from(&mut slice);
-        if slice.read_i64() == TABLE_MAGIC_NUMBER {
-            return Self {
+        if slice.read_u64() == TABLE_MAGIC_NUMBER {
+            return Ok(Self {
                 index_block_handle: index_block_handle,
                 metaindex_block_handle: metaindex_block_handle,
-            };
+            });
         };
 
-        panic!("magic number is not correct")
+        Err("magic number is not correct".to_owned())
     }
 
     pub fn encode(&self) -> Bytes {
         let mut slice = BytesMut::with_capacity(FOOTER_MAX_LENGTH);
         slice.write(&self.index_block_handle.encode());
         slice.write(&self.metaindex_block_handle.encode());
-        slice.write_i64(TABLE_MAGIC_NUMBER);
+        slice.write_u64(TABLE_MAGIC_NUMBER);
 
         slice.freeze()
     }
@@ -122,23 +127,9 @@ pub fn read_block<T: io::Read + io::Seek>(
     })
 }
 
-pub fn read_block2<T: RandomAccessFile>(reader: &T, block_handle: &BlockHandle) -> Option<Block> {
-    let block_size = block_handle.size() as usize;
-    let slice = reader
-        .read(block_handle.offset() as usize, block_size + TRAILER_SIZE)
-        .map(|b| Bytes::from(b));
-
-    match slice {
-        Ok(mut b) => {
-            let mut content = b.read(block_size + 1);
-            let _crc = b.read_u32();
-            // check crc
-
-            let v = content.read(block_size);
-            Some(match Compression::from(content[0]) {
-                Compression::No => Block::new(v),
-            })
-        }
+pub fn read_block2(reader: &dyn RandomAccessFile, block_handle: &BlockHandle) -> Option<Block> {
+    match read_block_contents(reader, block_handle, false) {
+        Ok(block) => Some(block),
         Err(e) => {
             error!("{:?}", e);
             None
@@ -146,6 +137,43 @@ pub fn read_block2<T: RandomAccessFile>(reader: &T, block_handle: &BlockHandle)
     }
 }
 
+// Reads the block of block_handle. If verify_checksum is true, the block is checked against
+// the CRC in its trailer, so that a corrupted block is an error rather than garbage.
+pub fn read_block_contents(
+    reader: &dyn RandomAccessFile,
+    block_handle: &BlockHandle,
+    verify_checksum: bool,
+) -> Result<Block, String> {
+    let offset = block_handle.offset() as usize;
+    let block_size = block_handle.size() as usize;
+    let mut b = reader.read(offset, block_size + TRAILER_SIZE)?;
+    if b.len() != block_size + TRAILER_SIZE {
+        return Err(format!("truncated block at offset {:}", offset));
+    }
+
+    // The CRC covers the contents and the compression type
+    let content = b.read(block_size + 1);
+    let crc = b.read_u32();
+    if verify_checksum {
+        let mut digest = crc32::Digest::new(crc32::IEEE);
+        digest.write(&content);
+        if digest.sum32() != crc {
+            return Err(format!("block checksum mismatch at offset {:}", offset));
+        }
+    }
+
+    let kind = content[block_size];
+    if kind == Compression::No as u8 {
+        Block::try_new(content.gets(0, block_size))
+            .map_err(|msg| format!("{:} at offset {:}", msg, offset))
+    } else {
+        Err(format!(
+            "unknown compression type {:} of block at offset {:}",
+            kind, offset
+        ))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::BlockHandle;
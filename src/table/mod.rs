@@ -5,9 +5,9 @@ mod table;
 pub mod table_builder;
 mod table_cache;
 
+use env::Env;
 use filename;
 use ikey;
-use memdb::MemDBIterator;
 use slice::Bytes;
 use version::{FileMetaData, FileMetaDataBuilder};
 
@@ -24,16 +24,17 @@ impl From<u8> for Compression {
     }
 }
 
-pub fn bulid(
+pub fn bulid<I: Iterator<Item = (Bytes, Bytes)>>(
+    env: &dyn Env,
     dbname: &str,
-    iterator: &mut MemDBIterator,
+    iterator: &mut I,
     num: u64,
-) -> Result<FileMetaData, &'static str> {
+) -> Result<FileMetaData, String> {
     let mut meta_builder = FileMetaDataBuilder::new();
     meta_builder.file_num(num);
 
     let fname = filename::FileType::Table(dbname, num).filename();
-    let mut builder = table_builder::new(&fname);
+    let mut builder = table_builder::new(env, &fname)?;
     let mut largest = Bytes::new(); // XXX
 
     for (i, (k, v)) in iterator.enumerate() {
@@ -42,14 +43,17 @@ pub fn bulid(
         }
 
         largest = k.clone();
-        builder.add(&k, &v);
+        builder.add(&k, &v)?;
     }
 
     meta_builder.largest(ikey::InternalKey::from(largest));
-    builder.build();
+    builder.build()?;
+    builder.sync()?;
 
     meta_builder.file_size(builder.size() as u64);
-    meta_builder.build()
+    meta_builder.build().map_err(|e| e.to_owned())
 }
 
+pub use self::format::{BlockHandle, Footer};
+pub use self::table::Table;
 pub use self::table_cache::TableCache;
@@ -1,36 +1,54 @@
 use std::sync::Arc;
 
 use super::block::{Block, BlockIterator};
-use super::format::{Footer, FOOTER_MAX_LENGTH};
+use super::format::{BlockHandle, Footer, FOOTER_MAX_LENGTH};
+use super::table_builder::{NUM_ENTRIES_PROPERTY, PROPERTIES_BLOCK_KEY, TRAILER_SIZE};
 use super::{block, format};
-use random_access_file::RandomAccessFile;
-use slice::Bytes;
+use comparator::Comparator;
+use env::RandomAccessFile;
+use slice::{ByteRead, Bytes};
 
-pub struct Table<T> {
+pub struct Table {
     index_block: Block,
-    inner: Arc<T>,
+    footer: Footer,
+    // None for tables written without the properties block
+    num_entries: Option<u64>,
+    inner: Arc<dyn RandomAccessFile>,
+    cmp: Arc<dyn Comparator>,
 }
 
-impl<T> Table<T> {
-    pub fn iter(&self) -> TableIterator<T> {
+impl Table {
+    pub fn iter(&self) -> TableIterator {
         TableIterator {
-            index_block: self.index_block.iter(),
+            index_block: self.index_block.iter_with_comparator(self.cmp.clone()),
             data_block: None,
             inner: self.inner.clone(),
+            cmp: self.cmp.clone(),
         }
     }
-}
 
-impl<T: RandomAccessFile> Table<T> {
-    pub fn open(size: usize, inner: T) -> Self {
+    // Keys in the table must be sorted by cmp. The index block is checked against its CRC.
+    pub fn open(
+        size: usize,
+        inner: Box<dyn RandomAccessFile>,
+        cmp: Arc<dyn Comparator>,
+    ) -> Result<Self, String> {
         if FOOTER_MAX_LENGTH > size {
-            error!("Size is too samll {:?} for footer", size);
+            return Err(format!("file is too short ({:} bytes) to be a table", size));
         }
 
         let footer = inner
             .read(size - FOOTER_MAX_LENGTH, FOOTER_MAX_LENGTH)
-            .map(|v| Footer::decode(v))
-            .unwrap();
+            .and_then(|v| Footer::decode(&v))?;
+        for handle in &[&footer.index_block_handle, &footer.metaindex_block_handle] {
+            let end = handle
+                .size()
+                .checked_add(TRAILER_SIZE as u64)
+                .and_then(|n| handle.offset().checked_add(n));
+            if end.map_or(true, |end| end > size as u64) {
+                return Err("block handle in footer is out of the file".to_owned());
+            }
+        }
 
         debug!(
             "Read footer data index_block(offset={:?}, size={:?}), metaindex(offset={:?}, size={:?})",
@@ -40,32 +58,117 @@ impl<T: RandomAccessFile> Table<T> {
             footer.metaindex_block_handle.size()
         );
 
-        let index_block =
-            format::read_block2(&inner, &footer.index_block_handle).expect("block need");
+        let index_block = format::read_block_contents(&*inner, &footer.index_block_handle, true)?;
+        let num_entries = format::read_block_contents(&*inner, &footer.metaindex_block_handle, true)
+            .ok()
+            .and_then(|metaindex_block| read_num_entries(&*inner, &metaindex_block));
 
-        Self {
+        Ok(Self {
             index_block: index_block,
-            inner: Arc::new(inner),
-        }
+            footer,
+            num_entries,
+            inner: Arc::from(inner),
+            cmp: cmp,
+        })
     }
 
     pub fn get(&self, key: &Bytes) -> Option<Bytes> {
-        if let Some(ref index_value) = self.index_block.iter().seek(key).as_ref() {
-            let mut b = block::read2(&*self.inner, index_value);
-            b.iter().seek(key)
-        } else {
-            None
+        self.seek(key).map(|(_, v)| v)
+    }
+
+    // Returns the first entry whose key is greater than or equal to key
+    pub fn seek(&self, key: &Bytes) -> Option<(Bytes, Bytes)> {
+        let index_value = self.index_block
+            .iter_with_comparator(self.cmp.clone())
+            .seek(key);
+
+        index_value.and_then(|v| {
+            block::read2(&*self.inner, &v)
+                .iter_with_comparator(self.cmp.clone())
+                .seek_entry(key)
+        })
+    }
+
+    // Returns the approximate offset in the file where data of key begins, or would begin
+    // if key is not in the table. Only the index block is read.
+    pub fn approximate_offset_of(&self, key: &Bytes) -> u64 {
+        let index_value = self.index_block
+            .iter_with_comparator(self.cmp.clone())
+            .seek(key);
+
+        match index_value {
+            Some(v) => BlockHandle::decode_from(&mut v.clone()).offset(),
+            // key is past the last data block
+            None => self.data_size(),
+        }
+    }
+
+    // Approximate size of the data blocks, which are followed by the meta blocks
+    pub fn data_size(&self) -> u64 {
+        self.footer.metaindex_block_handle.offset()
+    }
+
+    pub fn footer(&self) -> &Footer {
+        &self.footer
+    }
+
+    // Returns the entries of the index block, which map the last key of each data block to
+    // the handle of the block
+    pub fn index_entries(&self) -> Vec<(Bytes, BlockHandle)> {
+        self.index_block
+            .iter_with_comparator(self.cmp.clone())
+            .map(|(k, v)| (k, BlockHandle::decode_from(&mut v.clone())))
+            .collect()
+    }
+
+    pub fn num_entries(&self) -> Option<u64> {
+        self.num_entries
+    }
+
+    // Calls f with every entry in order. Data blocks are checked against their CRCs, and the
+    // scan stops at the first block which can not be read with its error.
+    pub fn scan_checked<F: FnMut(Bytes, Bytes)>(&self, mut f: F) -> Result<(), String> {
+        for (_, handle) in self.index_entries() {
+            self.scan_block(&handle, &mut f)?;
         }
+        Ok(())
+    }
+
+    // Calls f with every entry of the data block at handle after checking its CRC
+    pub fn scan_block<F: FnMut(Bytes, Bytes)>(
+        &self,
+        handle: &BlockHandle,
+        f: &mut F,
+    ) -> Result<(), String> {
+        let block = format::read_block_contents(&*self.inner, handle, true)?;
+        for (k, v) in block.iter_with_comparator(self.cmp.clone()) {
+            f(k, v);
+        }
+        Ok(())
     }
 }
 
-pub struct TableIterator<T> {
+fn read_num_entries(inner: &dyn RandomAccessFile, metaindex_block: &Block) -> Option<u64> {
+    let (_, handle) = metaindex_block
+        .iter()
+        .find(|(k, _)| k.as_ref() == PROPERTIES_BLOCK_KEY.as_bytes())?;
+    let properties_block =
+        format::read_block_contents(inner, &BlockHandle::decode_from(&mut handle.clone()), true)
+            .ok()?;
+    properties_block
+        .iter()
+        .find(|(k, _)| k.as_ref() == NUM_ENTRIES_PROPERTY.as_bytes())
+        .map(|(_, v)| v.get_u64(0))
+}
+
+pub struct TableIterator {
     index_block: BlockIterator,
     data_block: Option<BlockIterator>,
-    inner: Arc<T>,
+    inner: Arc<dyn RandomAccessFile>,
+    cmp: Arc<dyn Comparator>,
 }
 
-impl<T: RandomAccessFile> Iterator for TableIterator<T> {
+impl Iterator for TableIterator {
     type Item = (Bytes, Bytes);
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -74,7 +177,10 @@ impl<T: RandomAccessFile> Iterator for TableIterator<T> {
             .and_then(|dblock| dblock.next())
             .or_else(|| {
                 self.index_block.next().and_then(|(_, index_value)| {
-                    self.data_block = Some(block::read2(&*self.inner, &index_value).iter());
+                    self.data_block = Some(
+                        block::read2(&*self.inner, &index_value)
+                            .iter_with_comparator(self.cmp.clone()),
+                    );
                     self.data_block.as_mut().and_then(|block| block.next())
                 })
             })
@@ -86,17 +192,19 @@ mod tests {
     use super::super::table_builder::TableBuilder;
     use super::*;
     use bytes::Bytes;
-    use random_access_file::RandomAccessFile;
+    use comparator::BytewiseComparator;
     use std::io::{BufWriter, Cursor};
 
+    const ENTRY_NUM: usize = 300;
+
     fn built_table_value() -> (Vec<u8>, Vec<(Bytes, Bytes)>) {
         let mut value: Vec<u8> = vec![];
-        let dic: Vec<(Bytes, Bytes)> = (0..30)
+        let dic: Vec<(Bytes, Bytes)> = (0..ENTRY_NUM)
             .into_iter()
             .map(|v| {
                 (
-                    Bytes::from(format!("key{:02?}", v).as_bytes()),
-                    Bytes::from(format!("value{:02?}", v).as_bytes()),
+                    Bytes::from(format!("key{:03?}", v).as_bytes()),
+                    Bytes::from(format!("value{:03?}", v).as_bytes()),
                 )
             })
             .collect();
@@ -105,10 +213,10 @@ mod tests {
             let mut b = TableBuilder::new(BufWriter::new(Cursor::new(&mut value)));
 
             for &(ref k, ref v) in &dic {
-                b.add(k, v)
+                b.add(k, v).unwrap();
             }
 
-            b.build();
+            b.build().unwrap();
         }
         (value, dic)
     }
@@ -118,39 +226,86 @@ mod tests {
     }
 
     impl RandomAccessFile for TestRandomAccessFile {
-        fn open(fname: &str) -> Self {
-            let (v, _) = built_table_value();
-            TestRandomAccessFile { inner: v }
-        }
-
-        fn read(&self, offset: usize, size: usize) -> Result<&[u8], String> {
+        fn read(&self, offset: usize, size: usize) -> Result<Bytes, String> {
             let lim = offset + size;
             if lim > self.inner.len() {
                 Err("invalid index".to_owned())
             } else {
-                Ok(&self.inner[offset..lim])
+                Ok(Bytes::from(&self.inner[offset..lim]))
             }
         }
     }
 
+    fn test_file(value: Vec<u8>) -> Box<dyn RandomAccessFile> {
+        Box::new(TestRandomAccessFile { inner: value })
+    }
+
     #[test]
     fn test_table() {
         let (value, dic) = built_table_value();
-        let t = Table::open(value.len(), TestRandomAccessFile::open("dummy"));
+        let t = Table::open(value.len(), test_file(value), Arc::new(BytewiseComparator)).unwrap();
 
         for (k, v) in dic {
             assert_eq!(Some(v), t.get(&k))
         }
+        assert_eq!(t.get(&Bytes::from("key999")), None);
     }
 
     #[test]
     fn test_table_iter() {
         let (value, dic) = built_table_value();
-        let mut titer = Table::open(value.len(), TestRandomAccessFile::open("dummy")).iter();
+        let mut titer = Table::open(value.len(), test_file(value), Arc::new(BytewiseComparator))
+            .unwrap()
+            .iter();
 
         for exp in dic {
             let t = titer.next().unwrap();
             assert_eq!(exp, t);
         }
+        assert_eq!(titer.next(), None);
+    }
+
+    #[test]
+    fn test_table_approximate_offset_of() {
+        let (value, _) = built_table_value();
+        let size = value.len() as u64;
+        let t = Table::open(value.len(), test_file(value), Arc::new(BytewiseComparator)).unwrap();
+
+        assert_eq!(t.num_entries(), Some(ENTRY_NUM as u64));
+        assert_eq!(t.approximate_offset_of(&Bytes::from("key")), 0);
+
+        let middle = t.approximate_offset_of(&Bytes::from("key150"));
+        let end = t.approximate_offset_of(&Bytes::from("key999"));
+        assert!(0 < middle && middle < end, "{:} {:}", middle, end);
+        assert!(end < size);
+    }
+
+    #[test]
+    fn test_table_corruption() {
+        let (mut value, dic) = built_table_value();
+        assert!(Table::open(10, test_file(value.clone()), Arc::new(BytewiseComparator)).is_err());
+
+        // Corrupt the first data block
+        value[10] ^= 0xff;
+        let t = Table::open(value.len(), test_file(value), Arc::new(BytewiseComparator)).unwrap();
+        let mut entries = vec![];
+        let err = t.scan_checked(|k, v| entries.push((k, v))).unwrap_err();
+        assert!(err.contains("checksum mismatch at offset 0"), "{}", err);
+        assert!(entries.is_empty());
+
+        // An index block handle whose end overflows
+        let (mut value, _) = built_table_value();
+        let footer = value.len() - FOOTER_MAX_LENGTH;
+        let handle = BlockHandle::from(u64::max_value() - 1, 16).encode();
+        value[footer..footer + handle.len()].copy_from_slice(&handle);
+        let len = value.len();
+        let err = Table::open(len, test_file(value), Arc::new(BytewiseComparator)).err();
+        assert_eq!(err.unwrap(), "block handle in footer is out of the file");
+
+        let (value, _) = built_table_value();
+        let t = Table::open(value.len(), test_file(value), Arc::new(BytewiseComparator)).unwrap();
+        let mut entries = vec![];
+        t.scan_checked(|k, v| entries.push((k, v))).unwrap();
+        assert_eq!(entries, dic);
     }
 }
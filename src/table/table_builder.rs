@@ -1,9 +1,7 @@
 use crc::{Hasher32, crc32};
-use slice;
+use env::{Env, WritableFile};
 use slice::{ByteWrite, Bytes, BytesMut};
-use std::fs;
 use std::io;
-use std::io::BufWriter;
 use table::{Compression, block_builder::BlockBuilder, format::{BlockHandle, Footer}};
 
 pub struct TableBuilder<T: io::Write> {
@@ -14,19 +12,19 @@ pub struct TableBuilder<T: io::Write> {
     pending_handle: BlockHandle,
     pending_index_entry: bool,
     last_key: Bytes,
+    num_entries: u64,
 }
 
 pub const TRAILER_SIZE: usize = 5;
 
-pub fn new(fname: &str) -> TableBuilder<BufWriter<fs::File>> {
-    debug!("Open file {:?} for table", fname);
-    let fd = fs::OpenOptions::new() // add read permission?
-        .write(true)
-        .create(true)
-        .open(fname)
-        .unwrap();
+// Key of the metaindex entry which points to the properties block
+pub const PROPERTIES_BLOCK_KEY: &str = "leveldb.properties";
+// Key of the number of entries in the properties block. The value is a u64.
+pub const NUM_ENTRIES_PROPERTY: &str = "leveldb.num.entries";
 
-    TableBuilder::new(BufWriter::new(fd))
+pub fn new(env: &dyn Env, fname: &str) -> Result<TableBuilder<Box<dyn WritableFile>>, String> {
+    debug!("Open file {:?} for table", fname);
+    env.new_writable_file(fname).map(TableBuilder::new)
 }
 
 impl<T: io::Write> TableBuilder<T> {
@@ -39,55 +37,75 @@ impl<T: io::Write> TableBuilder<T> {
             pending_index_entry: false,
             filter_block: None,
             last_key: Bytes::new(),
+            num_entries: 0,
         }
     }
 
-    pub fn add(&mut self, key: &Bytes, value: &Bytes) {
-        let mut k = key.clone();
+    pub fn add(&mut self, key: &Bytes, value: &Bytes) -> Result<(), String> {
         if self.pending_index_entry {
-            slice::short_successor(k.to_mut());
-
+            // The last key of the previous block is greater than or equal to every key in that
+            // block and smaller than key, so it works as the index key of the block.
             let content = self.pending_handle.encode();
-            self.index_block.add(&k, &content);
+            self.index_block.add(&self.last_key, &content);
             self.pending_index_entry = false;
         }
 
-        self.data_block.add(&k, value);
-        self.last_key = k;
+        self.data_block.add(key, value);
+        self.last_key = key.clone();
+        self.num_entries += 1;
 
         // FIX: 1024
         if self.data_block.estimated_current_size() >= 1024 {
             debug!("Estimated size exceeds specifed size");
-            self.build()
+            self.flush().map_err(write_error)?;
         }
+        Ok(())
+    }
+
+    pub fn build(&mut self) -> Result<(), String> {
+        self.write_rest().map_err(write_error)
     }
 
-    pub fn build(&mut self) {
-        self.flush();
+    fn write_rest(&mut self) -> io::Result<()> {
+        self.flush()?;
 
         if let Some(_) = self.filter_block {
             // TODO: write filter block
         }
 
+        let properties_block_handle = {
+            let mut properties_block = BlockBuilder::new();
+            let mut num_entries = BytesMut::with_capacity(8);
+            num_entries.write_u64(self.num_entries);
+            properties_block.add(
+                &Bytes::from(NUM_ENTRIES_PROPERTY.as_bytes()),
+                &num_entries.freeze(),
+            );
+            let content = properties_block.build();
+            self.write_block(&content)?
+        };
+
         let metaindex_block_handle = {
             let mut meta_index_block = BlockBuilder::new();
             if let Some(_) = self.filter_block {
                 // TODO: write filter block
             }
+            meta_index_block.add(
+                &Bytes::from(PROPERTIES_BLOCK_KEY.as_bytes()),
+                &properties_block_handle.encode(),
+            );
             let content = meta_index_block.build();
             debug!(
                 "Write metaindex block handle offset={:?}, size={:?}",
                 self.writer.offset(),
                 content.len(),
             );
-            self.write_block(&content)
+            self.write_block(&content)?
         };
 
         // index
         let index_block_handle = {
             if self.pending_index_entry {
-                slice::short_successor(self.last_key.to_mut());
-                // let ss = TableBuilder::succ(&self.last_key);
                 let content = self.pending_handle.encode();
                 self.index_block.add(&self.last_key, &content);
                 self.pending_index_entry = false;
@@ -98,7 +116,7 @@ impl<T: io::Write> TableBuilder<T> {
                 self.writer.offset(),
                 content.len(),
             );
-            self.write_block(&content)
+            self.write_block(&content)?
         };
 
         // footer
@@ -106,45 +124,44 @@ impl<T: io::Write> TableBuilder<T> {
             let footer = Footer::new(index_block_handle, metaindex_block_handle);
             let content = footer.encode();
             debug!("Write footer to file. offset is {:?}", self.size());
-            self.writer
-                .write(content.as_ref())
-                .expect("Writing data is failed");
+            self.writer.write(content.as_ref())?;
         }
+        self.writer.flush()
     }
 
     pub fn size(&self) -> usize {
         self.writer.offset() as usize
     }
 
-    fn flush(&mut self) {
+    fn flush(&mut self) -> io::Result<()> {
         if self.data_block.empty() {
-            return;
+            return Ok(());
         }
 
         let content = self.data_block.build();
+        self.data_block = BlockBuilder::new();
         debug!(
             "Flush data offset={:?}, size={:?}",
             self.writer.offset(),
             content.len(),
         );
-        self.pending_handle = self.write_block(&content);
+        self.pending_handle = self.write_block(&content)?;
         self.pending_index_entry = true;
+        Ok(())
     }
 
-    fn write_block(&mut self, content: &Bytes) -> BlockHandle {
+    fn write_block(&mut self, content: &Bytes) -> io::Result<BlockHandle> {
         let kind = Compression::No;
         self.write_raw_block(content, kind)
     }
 
-    fn write_raw_block(&mut self, content: &Bytes, kindt: Compression) -> BlockHandle {
+    fn write_raw_block(&mut self, content: &Bytes, kindt: Compression) -> io::Result<BlockHandle> {
         // offset must be set before writer.write
         let bh = BlockHandle::from((content.len()) as u64, self.writer.offset());
 
         let kind = kindt as u8;
         let content_slice = content.as_ref();
-        self.writer
-            .write(content_slice)
-            .expect("Writing data is failed");
+        self.writer.write(content_slice)?;
 
         // crc
         {
@@ -158,15 +175,24 @@ impl<T: io::Write> TableBuilder<T> {
             let mut trailer = BytesMut::with_capacity(TRAILER_SIZE);
             trailer.write_u8(kind);
             trailer.write_u32(crc);
-            self.writer
-                .write(trailer.as_ref())
-                .expect("Writing data is failed");
+            self.writer.write(trailer.as_ref())?;
         }
 
-        bh
+        Ok(bh)
+    }
+}
+
+impl TableBuilder<Box<dyn WritableFile>> {
+    // Makes the table survive a power loss. Call it after build.
+    pub fn sync(&mut self) -> Result<(), String> {
+        self.writer.inner.sync()
     }
 }
 
+fn write_error(e: io::Error) -> String {
+    format!("failed to write table: {:}", e)
+}
+
 pub struct TableWriter<T> {
     inner: T,
     offset: usize,
@@ -182,8 +208,13 @@ impl<T: io::Write> TableWriter<T> {
 
     pub fn write(&mut self, content: &[u8]) -> Result<usize, io::Error> {
         debug!("write data to table {:?}", content);
+        self.inner.write_all(content)?;
         self.offset += content.len();
-        self.inner.write(content)
+        Ok(content.len())
+    }
+
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        self.inner.flush()
     }
 
     pub fn offset(&self) -> u64 {
@@ -1,54 +1,85 @@
-use bytes::Bytes;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
 use super::table::{Table, TableIterator};
+use comparator::KeyComparator;
 use filename;
-use random_access_file::RandomAccessFile;
+use ikey::{InternalKey, KeyKind, LookupResult};
+use env::{Env, EnvOptions};
+use options::Options;
 
-pub struct TableCache<T> {
-    cache: HashMap<u64, TableAndFile<T>>, // TODO: use more smart cache
+// TableCache is shared by readers and the background thread, so it is synchronized by itself
+// rather than by the lock of DB state.
+pub struct TableCache {
+    cache: Mutex<HashMap<u64, Arc<Table>>>, // TODO: use more smart cache
     db_name: String,
+    env: Arc<dyn Env>,
+    env_options: EnvOptions,
 }
 
-pub struct TableAndFile<T> {
-    table: Table<T>,
-    // file?
-}
-
-impl<T> TableCache<T> {
-    pub fn new(name: &str) -> Self {
+impl TableCache {
+    pub fn new(name: &str, options: &Options) -> Self {
         Self {
-            cache: HashMap::new(),
+            cache: Mutex::new(HashMap::new()),
             db_name: name.to_owned(),
+            env: options.env.clone(),
+            env_options: EnvOptions {
+                use_mmap_reads: options.use_mmap_reads,
+            },
         }
     }
-}
 
-impl<T: RandomAccessFile> TableCache<T> {
-    pub fn find_or_create_table(&mut self, file_number: u64, size: u64) -> &mut Table<T> {
-        let db_name = self.db_name.clone();
-        &mut self.cache
-            .entry(file_number)
-            .or_insert_with(|| {
-                let name = filename::FileType::Table(&db_name, file_number).filename();
-                TableAndFile {
-                    table: Table::open(size as usize, T::open(&name)),
-                }
-            })
-            .table
+    // Drops the table of a deleted file
+    pub fn evict(&self, file_number: u64) {
+        self.cache.lock().unwrap().remove(&file_number);
     }
 
-    pub fn find_table(&self, file_number: u64) -> Option<&Table<T>> {
-        let db_name = &self.db_name;
-        self.cache.get(&file_number).map(|v| &v.table)
+    // Returns the table of the file, opening it if it is not cached. A file which can not be
+    // opened is not cached, so it is tried again next time.
+    pub fn find_or_create_table(&self, file_number: u64, size: u64) -> Result<Arc<Table>, String> {
+        if let Some(table) = self.cache.lock().unwrap().get(&file_number) {
+            return Ok(table.clone());
+        }
+
+        // Opened without the lock, since it reads the file
+        let name = filename::FileType::Table(&self.db_name, file_number).filename();
+        let file = self.env.new_random_access_file(&name, &self.env_options)?;
+        let table = Table::open(size as usize, file, Arc::new(KeyComparator::new()))
+            .map_err(|msg| format!("{:}: {:}", name, msg))?;
+        Ok(self
+            .cache
+            .lock()
+            .unwrap()
+            .entry(file_number)
+            .or_insert_with(|| Arc::new(table))
+            .clone())
     }
 
-    pub fn get(&mut self, key: &Bytes, file_number: u64, size: u64) -> Option<Bytes> {
-        let table = self.find_or_create_table(file_number, size);
-        table.get(key)
+    pub fn get(
+        &self,
+        key: &InternalKey,
+        file_number: u64,
+        size: u64,
+    ) -> Result<LookupResult, String> {
+        let table = self.find_or_create_table(file_number, size)?;
+        let result = match table.seek(&key.lookup_key()) {
+            Some((k, v)) => {
+                let found = InternalKey::from(k);
+                if found.user_key() != key.user_key() {
+                    LookupResult::NotFound
+                } else if found.kind() == KeyKind::Delete {
+                    LookupResult::Deleted
+                } else {
+                    LookupResult::Found(v)
+                }
+            }
+            None => LookupResult::NotFound,
+        };
+        Ok(result)
     }
 
-    pub fn inner_iter(&mut self, file_number: u64, size: u64) -> TableIterator<T> {
-        self.find_or_create_table(file_number, size).iter()
+    pub fn inner_iter(&self, file_number: u64, size: u64) -> Result<TableIterator, String> {
+        self.find_or_create_table(file_number, size)
+            .map(|table| table.iter())
     }
 }
@@ -0,0 +1,21 @@
+// Fixtures shared by the tests of modules which work on a whole DB
+use std::ops::Range;
+use std::sync::Arc;
+
+use env::Env;
+use leveldb::LevelDB;
+use options::Options;
+
+pub fn mem_options(env: &Arc<dyn Env>) -> Options {
+    let mut options = Options::default();
+    options.env = env.clone();
+    options
+}
+
+// Sets key{i} to value{i}-{round} for each i in keys, one write per key
+pub fn set_all(db: &LevelDB, keys: Range<usize>, round: usize) {
+    for i in keys {
+        db.set(&format!("key{:04}", i), &format!("value{:}-{:}", i, round))
+            .unwrap();
+    }
+}
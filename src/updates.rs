@@ -0,0 +1,276 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::Arc;
+
+use batch::WriteBatch;
+use env::{Env, SequentialFile};
+use filename::{self, FileType};
+use log_record::LogReader;
+
+type Reader = LogReader<Box<dyn SequentialFile>>;
+
+// Iterates the write batches in the logs of a DB with their sequence numbers, see
+// LevelDB::updates_since. Batches follow each other without gaps in sequence numbers. If a
+// log can not be read or some batches are missing, the iteration stops and status returns
+// the error.
+pub struct UpdatesIterator {
+    env: Arc<dyn Env>,
+    dbname: String,
+    // Numbers of the logs left to read, from older to newer
+    logs: VecDeque<u64>,
+    // Name of the log being read and its reader
+    reader: Option<(String, Reader)>,
+    // Sequence number of the next write to return
+    next_seq: u64,
+    // Writes after this are not returned, since they may be partly written
+    last_sequence: u64,
+    error: Option<String>,
+}
+
+impl UpdatesIterator {
+    pub fn new(
+        env: Arc<dyn Env>,
+        dbname: &str,
+        seq: u64,
+        last_sequence: u64,
+    ) -> Result<UpdatesIterator, String> {
+        let mut nums = BTreeSet::new();
+        for dir in &[dbname.to_owned(), filename::archive_dir(dbname)] {
+            if !env.file_exists(dir) {
+                continue;
+            }
+            for name in env.get_children(dir)? {
+                let path = format!("{:}/{:}", dir, name);
+                if let Some(FileType::Log(_, num)) = FileType::parse_name(&path) {
+                    nums.insert(num);
+                }
+            }
+        }
+
+        let mut iter = UpdatesIterator {
+            env,
+            dbname: dbname.to_owned(),
+            logs: nums.into_iter().collect(),
+            reader: None,
+            next_seq: seq.max(1),
+            last_sequence,
+            error: None,
+        };
+        iter.skip_older_logs();
+        Ok(iter)
+    }
+
+    // Returns the error which stopped the iteration, if any
+    pub fn status(&self) -> Result<(), String> {
+        match self.error {
+            Some(ref msg) => Err(msg.clone()),
+            None => Ok(()),
+        }
+    }
+
+    // Drops the logs before the newest one which starts at or before next_seq, so that they
+    // are not read through
+    fn skip_older_logs(&mut self) {
+        let start = self.logs.iter().rposition(|&num| {
+            self.first_sequence(num)
+                .map_or(false, |first| first <= self.next_seq)
+        });
+        if let Some(start) = start {
+            self.logs.drain(..start);
+        }
+    }
+
+    fn first_sequence(&self, num: u64) -> Option<u64> {
+        let (_, mut reader) = self.open_log(num).ok()?;
+        let record = reader.read_record()?;
+        WriteBatch::load_data(record)
+            .ok()
+            .map(|batch| batch.seq() as u64)
+    }
+
+    fn open_log(&self, num: u64) -> Result<(String, Reader), String> {
+        let live = FileType::Log(&self.dbname, num).filename();
+        // A live log may be archived after it is listed
+        let archived = FileType::Log(&filename::archive_dir(&self.dbname), num).filename();
+        for fname in &[live, archived] {
+            if let Ok(file) = self.env.new_sequential_file(fname) {
+                return Ok((fname.clone(), LogReader::new(file)));
+            }
+        }
+        Err(format!("log #{:} is deleted while it is read", num))
+    }
+}
+
+impl Iterator for UpdatesIterator {
+    type Item = (u64, WriteBatch);
+
+    fn next(&mut self) -> Option<(u64, WriteBatch)> {
+        while self.error.is_none() && self.next_seq <= self.last_sequence {
+            if self.reader.is_none() {
+                let opened = match self.logs.pop_front() {
+                    Some(num) => self.open_log(num),
+                    None => Err(format!(
+                        "updates from sequence {:} are not in the logs",
+                        self.next_seq
+                    )),
+                };
+                match opened {
+                    Ok(reader) => self.reader = Some(reader),
+                    Err(msg) => self.error = Some(msg),
+                }
+                continue;
+            }
+
+            let result = match self.reader {
+                Some((ref fname, ref mut reader)) => next_batch(fname, reader, self.next_seq),
+                None => continue,
+            };
+            match result {
+                Ok(Some(batch)) => {
+                    let seq = batch.seq() as u64;
+                    if seq > self.next_seq {
+                        self.error = Some(format!(
+                            "updates from sequence {:} to {:} are not in the logs",
+                            self.next_seq,
+                            seq - 1
+                        ));
+                        break;
+                    }
+                    self.next_seq = seq + batch.count() as u64;
+                    return Some((seq, batch));
+                }
+                Ok(None) => self.reader = None,
+                Err(msg) => self.error = Some(msg),
+            }
+        }
+        None
+    }
+}
+
+// Returns the first batch in reader which has writes at or after next_seq, or None at the
+// end of the log
+fn next_batch(
+    fname: &str,
+    reader: &mut Reader,
+    next_seq: u64,
+) -> Result<Option<WriteBatch>, String> {
+    while let Some(record) = reader.read_record() {
+        let batch = WriteBatch::load_data(record).map_err(|msg| {
+            format!(
+                "{:} at offset {:}: {:}",
+                fname,
+                reader.last_record_offset(),
+                msg
+            )
+        })?;
+        if (batch.seq() + batch.count()) as u64 > next_seq {
+            return Ok(Some(batch));
+        }
+    }
+    match reader.corruption() {
+        Some((offset, msg)) => Err(format!("{:} at offset {:}: {:}", fname, offset, msg)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use env::MemEnv;
+    use leveldb::{open, LevelDB};
+    use options::Options;
+    use test_util::{mem_options, set_all};
+
+    fn archive_options(env: &Arc<dyn Env>) -> Options {
+        let mut options = mem_options(env);
+        options.archive_wal = true;
+        options
+    }
+
+    // Writes keys in [start, end) with one batch per key, and flushes them to a table
+    fn set_and_flush(db: &LevelDB, start: usize, end: usize) {
+        set_all(db, start..end, 0);
+        db.compact_range(None, None).unwrap();
+    }
+
+    fn archived_logs(env: &Arc<dyn Env>, dbname: &str) -> Vec<String> {
+        env.get_children(&filename::archive_dir(dbname)).unwrap()
+    }
+
+    #[test]
+    fn updates_since_reads_archived_logs() {
+        let dbname = "/updates_since";
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let db = open(dbname, archive_options(&env)).unwrap();
+        set_and_flush(&db, 0, 100);
+        set_and_flush(&db, 100, 200);
+        // Left in the live log
+        let mut batch = WriteBatch::new();
+        batch.put("key0200", "value200");
+        batch.delete("key0000");
+        db.apply(batch).unwrap();
+        assert_eq!(archived_logs(&env, dbname).len(), 2);
+
+        let mut iter = db.updates_since(1).unwrap();
+        let seqs: Vec<u64> = iter.by_ref().map(|(seq, _)| seq).collect();
+        iter.status().unwrap();
+        assert_eq!(seqs, (1..202).collect::<Vec<u64>>());
+
+        // The batch which has the sequence number is the first one
+        let mut iter = db.updates_since(202).unwrap();
+        let (seq, batch) = iter.next().unwrap();
+        assert_eq!((seq, batch.count()), (201, 2));
+        assert!(iter.next().is_none());
+        iter.status().unwrap();
+
+        let mut iter = db.updates_since(150).unwrap();
+        assert_eq!(iter.next().unwrap().0, 150);
+        assert!(db.updates_since(203).unwrap().next().is_none());
+
+        // Logs are deleted without archival, the live one after it is replayed
+        drop(db);
+        let mut options = archive_options(&env);
+        options.archive_wal = false;
+        let db = open(dbname, options).unwrap();
+        set_and_flush(&db, 300, 310);
+        let mut iter = db.updates_since(1).unwrap();
+        assert_eq!(iter.next().unwrap().0, 1);
+        assert_eq!(iter.by_ref().count(), 199);
+        let err = iter.status().unwrap_err();
+        assert!(err.contains("updates from sequence 201 "), "{}", err);
+    }
+
+    #[test]
+    fn archived_logs_are_purged() {
+        let dbname = "/purge_archived_logs";
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let mut options = archive_options(&env);
+        options.wal_ttl_seconds = Some(0);
+        {
+            let db = open(dbname, options).unwrap();
+            set_and_flush(&db, 0, 100);
+            assert!(archived_logs(&env, dbname).is_empty());
+        }
+
+        let mut options = archive_options(&env);
+        options.wal_size_limit = Some(8 << 10);
+        let db = open(dbname, options).unwrap();
+        for round in 0..10 {
+            set_and_flush(&db, round * 100, round * 100 + 100);
+        }
+        let logs = archived_logs(&env, dbname);
+        let dir = filename::archive_dir(dbname);
+        let size: u64 = logs
+            .iter()
+            .map(|name| env.get_file_size(&format!("{:}/{:}", dir, name)).unwrap())
+            .sum();
+        assert!(!logs.is_empty() && size <= 8 << 10, "{:?} {:}", logs, size);
+
+        // Only the writes of the last round are sure to be left
+        let mut iter = db.updates_since(1).unwrap();
+        assert!(iter.next().is_none());
+        assert!(iter.status().is_err());
+        let mut iter = db.updates_since(1001).unwrap();
+        assert_eq!(iter.by_ref().count(), 100);
+        iter.status().unwrap();
+    }
+}
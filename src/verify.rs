@@ -0,0 +1,251 @@
+use bytes::Bytes;
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use batch::WriteBatch;
+use comparator::{Comparator, KeyComparator};
+use env::{Env, EnvOptions};
+use filename::FileType;
+use ikey::InternalKey;
+use log_record::LogReader;
+use table::Table;
+use version::{FileMetaData, Version, LEVEL};
+
+// A problem found by verify_checksums
+#[derive(Clone, Debug, PartialEq)]
+pub struct Corruption {
+    pub file: String,
+    // Offset of the corrupted block or log record, or None if the file can not be read at all
+    pub offset: Option<u64>,
+    pub message: String,
+}
+
+// What verify_checksums checked and the corruptions it found
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChecksumReport {
+    pub tables: usize,
+    pub logs: usize,
+    pub corruptions: Vec<Corruption>,
+}
+
+impl ChecksumReport {
+    pub fn is_ok(&self) -> bool {
+        self.corruptions.is_empty()
+    }
+}
+
+// Reads every block of the tables in version and every record of the logs with their CRCs.
+// Keys in a table must be sorted and start and end with the keys in the manifest.
+pub fn verify_checksums(
+    env: &dyn Env,
+    dbname: &str,
+    version: &Version,
+    log_numbers: &[u64],
+) -> ChecksumReport {
+    let mut report = ChecksumReport::default();
+    for level in 0..LEVEL {
+        for f in version.files(level) {
+            report.tables += 1;
+            verify_table(env, dbname, f, &mut report.corruptions);
+        }
+    }
+
+    for &num in log_numbers {
+        let fname = FileType::Log(dbname, num).filename();
+        // The log of a memtable is deleted once it is flushed
+        if !env.file_exists(&fname) {
+            continue;
+        }
+        report.logs += 1;
+        verify_log(env, &fname, &mut report.corruptions);
+    }
+    report
+}
+
+fn corruption(file: &str, offset: Option<u64>, message: String) -> Corruption {
+    Corruption {
+        file: file.to_owned(),
+        offset,
+        message,
+    }
+}
+
+fn verify_table(env: &dyn Env, dbname: &str, f: &FileMetaData, corruptions: &mut Vec<Corruption>) {
+    let fname = FileType::Table(dbname, f.file_num).filename();
+    let table = env.get_file_size(&fname).and_then(|size| {
+        if size != f.file_size {
+            return Err(format!(
+                "file size is {:} bytes, but {:} in the manifest",
+                size, f.file_size
+            ));
+        }
+        let file = env.new_random_access_file(&fname, &EnvOptions::default())?;
+        Table::open(size as usize, file, Arc::new(KeyComparator::new()))
+    });
+    let table = match table {
+        Ok(table) => table,
+        Err(msg) => {
+            corruptions.push(corruption(&fname, None, msg));
+            return;
+        }
+    };
+
+    let cmp = KeyComparator::new();
+    let found = corruptions.len();
+    // Keys with the offsets of their blocks
+    let mut first: Option<(Bytes, u64)> = None;
+    let mut last: Option<(Bytes, u64)> = None;
+    for (index_key, handle) in table.index_entries() {
+        let offset = handle.offset();
+        let mut disorder = None;
+        let result = table.scan_block(&handle, &mut |k, _| {
+            if disorder.is_some() {
+                return;
+            }
+            if let Some((ref prev, _)) = last {
+                if cmp.compare(prev, &k) != Ordering::Less {
+                    disorder = Some(format!(
+                        "key {:} is not after {:}",
+                        key_string(&k),
+                        key_string(prev)
+                    ));
+                    return;
+                }
+            }
+            if cmp.compare(&k, &index_key) == Ordering::Greater {
+                disorder = Some(format!(
+                    "key {:} is after the index key {:}",
+                    key_string(&k),
+                    key_string(&index_key)
+                ));
+                return;
+            }
+            if first.is_none() {
+                first = Some((k.clone(), offset));
+            }
+            last = Some((k, offset));
+        });
+        if let Err(msg) = result {
+            corruptions.push(corruption(&fname, Some(offset), msg));
+        }
+        if let Some(msg) = disorder {
+            corruptions.push(corruption(&fname, Some(offset), msg));
+        }
+    }
+
+    // Keys next to a corrupted block are not known, so the range is checked only without one
+    if corruptions.len() > found {
+        return;
+    }
+    let bounds = [
+        ("smallest", first, &f.smallest),
+        ("largest", last, &f.largest),
+    ];
+    for &(name, ref key, expected) in &bounds {
+        match *key {
+            Some((ref key, offset)) if *key != expected.inner() => corruptions.push(corruption(
+                &fname,
+                Some(offset),
+                format!(
+                    "{:} key {:} does not match {:} in the manifest",
+                    name,
+                    key_string(key),
+                    key_string(&expected.inner())
+                ),
+            )),
+            Some(_) => {}
+            None => corruptions.push(corruption(&fname, None, "table has no entries".to_owned())),
+        }
+    }
+}
+
+fn verify_log(env: &dyn Env, fname: &str, corruptions: &mut Vec<Corruption>) {
+    let mut reader = match env.new_sequential_file(fname) {
+        Ok(file) => LogReader::new(file),
+        Err(msg) => {
+            corruptions.push(corruption(fname, None, msg));
+            return;
+        }
+    };
+
+    while let Some(record) = reader.read_record() {
+        if let Err(msg) = WriteBatch::load_data(record) {
+            corruptions.push(corruption(fname, Some(reader.last_record_offset()), msg));
+        }
+    }
+    if let Some((offset, msg)) = reader.corruption() {
+        corruptions.push(corruption(fname, Some(offset), msg.to_owned()));
+    }
+}
+
+fn key_string(key: &Bytes) -> String {
+    InternalKey::from(key.clone()).debug_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use env::{self, MemEnv};
+    use leveldb::open;
+    use std::io::Read;
+    use test_util::{mem_options, set_all};
+
+    fn find_file(env: &Arc<dyn Env>, dir: &str, suffix: &str) -> String {
+        let name = env
+            .get_children(dir)
+            .unwrap()
+            .into_iter()
+            .find(|name| name.ends_with(suffix))
+            .unwrap();
+        format!("{:}/{:}", dir, name)
+    }
+
+    fn flip_byte(env: &Arc<dyn Env>, fname: &str, offset: usize) {
+        let mut data = vec![];
+        env.new_sequential_file(fname)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        data[offset] ^= 0xff;
+        env::write_string_to_file(&**env, &data, fname, true).unwrap();
+    }
+
+    #[test]
+    fn verify_checksums_finds_corruptions() {
+        let dbname = "/verify_checksums";
+        let env: Arc<dyn Env> = Arc::new(MemEnv::new());
+        let db = open(dbname, mem_options(&env)).unwrap();
+        set_all(&db, 0..1000, 0);
+        db.compact_range(None, None).unwrap();
+        set_all(&db, 1000..1010, 0);
+
+        let report = db.verify_checksums();
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!((report.tables, report.logs), (1, 1));
+
+        // A data block in the middle of the table, and the second record of the log
+        let table = find_file(&env, dbname, ".ldb");
+        let size = env.get_file_size(&table).unwrap() as usize;
+        flip_byte(&env, &table, size / 2);
+        let log = find_file(&env, dbname, ".log");
+        let record_size = env.get_file_size(&log).unwrap() / 10;
+        flip_byte(&env, &log, record_size as usize + 20);
+
+        let report = db.verify_checksums();
+        assert_eq!(report.corruptions.len(), 2, "{:?}", report);
+
+        let table_corruption = &report.corruptions[0];
+        assert_eq!(table_corruption.file, table);
+        let offset = table_corruption.offset.unwrap() as usize;
+        assert!(0 < offset && offset <= size / 2, "{:?}", table_corruption);
+        assert!(
+            table_corruption.message.contains("checksum mismatch"),
+            "{:?}",
+            table_corruption
+        );
+
+        let log_corruption = &report.corruptions[1];
+        assert_eq!(log_corruption.file, log);
+        assert_eq!(log_corruption.offset, Some(record_size));
+    }
+}
@@ -1,21 +1,61 @@
 use std::cmp;
+use std::sync::Arc;
 
-use super::FileMetaData;
-
-const LEVEL: usize = 12;
+use super::{FileMetaData, Version, VersionEdit, LEVEL};
 
+// A Compaction merges inputs[0] of level and inputs[1] of level + 1 into new files of level + 1
 pub struct Compaction {
     pub level: usize,
     pub inputs: Vec<Vec<FileMetaData>>,
+    pub max_output_file_size: u64,
+    pub edit: VersionEdit,
+    // Keeps input files alive until the compaction finishes
+    input_version: Arc<Version>,
 }
 
 impl Compaction {
-    pub fn new(level: usize) -> Self {
+    pub fn new(level: usize, input_version: Arc<Version>, max_output_file_size: u64) -> Self {
         Self {
             level: level,
-            inputs: vec![Vec::new(); LEVEL],
+            inputs: vec![Vec::new(); 2],
+            max_output_file_size: max_output_file_size,
+            edit: VersionEdit::new(0),
+            input_version: input_version,
+        }
+    }
+
+    pub fn input_version(&self) -> &Version {
+        &self.input_version
+    }
+
+    // A single file which overlaps nothing in the next level can be moved without merging
+    pub fn is_trivial_move(&self) -> bool {
+        self.inputs[0].len() == 1 && self.inputs[1].is_empty()
+    }
+
+    // Marks all inputs as deleted in edit
+    pub fn add_input_deletions(&mut self) {
+        for (which, files) in self.inputs.iter().enumerate() {
+            for f in files {
+                let mut f = f.clone();
+                f.level = (self.level + which) as u64;
+                self.edit.deleted_files.push(f);
+            }
         }
     }
+
+    // Returns true if no level deeper than the output level contains user_key,
+    // so that a deletion marker of user_key can be dropped.
+    pub fn is_base_level_for_key(&self, user_key: &[u8]) -> bool {
+        for level in (self.level + 2)..LEVEL {
+            for f in self.input_version.files(level) {
+                if &f.smallest.user_key()[..] <= user_key && user_key <= &f.largest.user_key()[..] {
+                    return false;
+                }
+            }
+        }
+        true
+    }
 }
 
 pub struct TwoLevelIterator<F, S> {
@@ -58,6 +98,7 @@ pub struct MergeingIterator<I: Iterator> {
     nexts: Vec<Option<I::Item>>,
     idx: usize,
     first: bool,
+    cmp: fn(&I::Item, &I::Item) -> cmp::Ordering,
 }
 
 impl<I> MergeingIterator<I>
@@ -66,12 +107,24 @@ where
     I::Item: Clone + cmp::Ord,
 {
     pub fn new(iters: Vec<I>) -> Self {
+        MergeingIterator::with_comparator(iters, cmp::Ord::cmp)
+    }
+}
+
+impl<I> MergeingIterator<I>
+where
+    I: Iterator,
+    I::Item: Clone,
+{
+    // Each iterator must yield items in the order of cmp
+    pub fn with_comparator(iters: Vec<I>, cmp: fn(&I::Item, &I::Item) -> cmp::Ordering) -> Self {
         let l = iters.len();
         Self {
             iters: iters,
             nexts: vec![None; l],
             idx: 0,
             first: true,
+            cmp: cmp,
         }
     }
 }
@@ -79,7 +132,7 @@ where
 impl<I> Iterator for MergeingIterator<I>
 where
     I: Iterator,
-    I::Item: Clone + cmp::Ord,
+    I::Item: Clone,
 {
     type Item = I::Item;
 
@@ -108,7 +161,13 @@ where
                 continue;
             }
 
-            if self.nexts[self.idx].is_none() || &self.nexts[self.idx] > n {
+            let smaller = match (&self.nexts[self.idx], n) {
+                (&Some(ref current), &Some(ref n)) => {
+                    (self.cmp)(current, n) == cmp::Ordering::Greater
+                }
+                _ => true,
+            };
+            if smaller {
                 self.idx = i;
             }
         }
@@ -152,6 +211,17 @@ mod tests {
         }
     }
 
+    #[test]
+    fn mergeing_iterator_with_comparator() {
+        let v = vec![vec![40, 30, 20, 1], vec![55, 8, 2], vec![]]
+            .into_iter()
+            .map(|v| v.into_iter())
+            .collect();
+        let actuals: Vec<u8> =
+            MergeingIterator::with_comparator(v, |a: &u8, b: &u8| b.cmp(a)).collect();
+        assert_eq!(actuals, vec![55, 40, 30, 20, 8, 2, 1]);
+    }
+
     #[test]
     fn empty_mergeing_iterator() {
         let v: Vec<Vec<u8>> = vec![vec![]];
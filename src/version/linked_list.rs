@@ -6,6 +6,9 @@ pub struct CircularLinkedList<T> {
     dummy: Link<T>,
 }
 
+// Nodes are owned by the list, so it can be moved to another thread as long as T can
+unsafe impl<T: Send> Send for CircularLinkedList<T> {}
+
 pub struct Node<T> {
     elem: T,
     next: *mut Node<T>,
@@ -34,8 +37,9 @@ impl<T> Drop for CircularLinkedList<T> {
 
         while (n as *const _) != finish {
             unsafe {
+                let prev = (*n).prev;
                 Box::from_raw(n); // take back the right of releasing
-                n = (*n).prev;
+                n = prev;
             }
         }
     }
@@ -82,9 +86,52 @@ impl<T> CircularLinkedList<T> {
         Some(unsafe { &(*r).elem })
     }
 
+    // Removes all elements for which f returns false
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        let head = &mut *self.dummy as *mut Node<T>;
+        let mut n = self.dummy.next;
+
+        while n != head {
+            unsafe {
+                let next = (*n).next;
+                if !f(&(*n).elem) {
+                    (*(*n).prev).next = next;
+                    (*next).prev = (*n).prev;
+                    drop(Box::from_raw(n));
+                }
+                n = next;
+            }
+        }
+    }
+
     pub fn iter(&self) -> Iter<T> {
         let r = self.dummy.as_ref();
         let v = unsafe { self.dummy.next.as_ref().expect("failed to unwrap") };
         Iter { head: r, next: &v }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn circular_linked_list_retain() {
+        let mut l = CircularLinkedList::new(0);
+        for i in 1..6 {
+            l.append(i);
+        }
+
+        l.retain(|&v| v % 2 == 0);
+        assert_eq!(l.iter().cloned().collect::<Vec<_>>(), vec![2, 4]);
+        assert_eq!(l.current(), Some(&4));
+
+        l.append(6);
+        l.retain(|&v| v != 4);
+        assert_eq!(l.iter().cloned().collect::<Vec<_>>(), vec![2, 6]);
+        assert_eq!(l.current(), Some(&6));
+
+        l.retain(|_| false);
+        assert_eq!(l.iter().count(), 0);
+    }
+}
@@ -8,6 +8,7 @@ pub use self::compaction::{Compaction, MergeingIterator, TwoLevelIterator};
 use self::linked_list::CircularLinkedList;
 pub use self::metadata::{FileMetaData, FileMetaDataBuilder};
 pub use self::version_edit::VersionEdit;
-pub use self::version_set::{Version, VersionSet};
+pub use self::version_set::{total_file_size, Version, VersionSet};
 
 const BLOCK_SIZE: usize = 2 << 15; // duplicated
+pub const LEVEL: usize = 12;
@@ -4,7 +4,7 @@ use std::io::Write;
 use super::{FileMetaData, BLOCK_SIZE};
 use ikey::InternalKey;
 use log_record::LogWriter;
-use slice::{ByteRead, Bytes, BytesMut};
+use slice::{ByteRead, Bytes, BytesMut, U64_BYTE_SIZE};
 
 enum Tag {
     Comparator = 1,
@@ -17,18 +17,18 @@ enum Tag {
     PrevLogNumber = 8,
 }
 
-impl From<u8> for Tag {
-    fn from(v: u8) -> Self {
+impl Tag {
+    fn decode(v: u8) -> Option<Tag> {
         match v {
-            1 => Tag::CompactPointer,
-            2 => Tag::LogNumber,
-            3 => Tag::NextFileNumber,
-            4 => Tag::LastSequence,
-            5 => Tag::Comparator,
-            6 => Tag::DeletedFile,
-            7 => Tag::NewFile,
-            8 => Tag::PrevLogNumber,
-            _ => panic!(format!("convert failed {:?}", v)),
+            1 => Some(Tag::Comparator),
+            2 => Some(Tag::LogNumber),
+            3 => Some(Tag::NextFileNumber),
+            4 => Some(Tag::LastSequence),
+            5 => Some(Tag::CompactPointer),
+            6 => Some(Tag::DeletedFile),
+            7 => Some(Tag::NewFile),
+            8 => Some(Tag::PrevLogNumber),
+            _ => None,
         }
     }
 }
@@ -62,55 +62,35 @@ impl VersionEdit {
         &self.deleted_files
     }
 
-    pub fn decode_from(&mut self, record: Bytes) {
-        let mut input = record.clone(); // copy?
+    // Fails on an unknown or unsupported tag, and on a record which ends in a field
+    pub fn try_decode_from(&mut self, record: Bytes) -> Result<(), String> {
+        let mut input = record;
 
-        while input.len() > 0 {
+        while !input.is_empty() {
             let tag = input.read_u8();
-            match Tag::from(tag) {
-                Tag::LogNumber => self.log_number = input.read_u64(),
-                Tag::NextFileNumber => self.next_file_number = input.read_u64(),
-                Tag::LastSequence => self.last_sequence = input.read_u64(),
-                Tag::PrevLogNumber => self.prev_log_number = input.read_u64(),
-                Tag::CompactPointer => unimplemented!(),
-                Tag::Comparator => unimplemented!(),
-                Tag::DeletedFile => {
-                    let level = input.read_u64();
-                    let file_num = input.read_u64();
-                    let file_size = input.read_u64();
-                    let largest_size = input.read_u64() as usize;
-                    let largest = input.read(largest_size);
-                    let smallest_size = input.read_u64() as usize;
-                    let smallest = input.read(smallest_size);
-                    self.deleted_files.push(FileMetaData {
-                        file_num: file_num,
-                        file_size: file_size,
-                        largest: InternalKey::from(largest),
-                        smallest: InternalKey::from(smallest),
-                        level: level,
-                    });
+            match Tag::decode(tag) {
+                Some(Tag::LogNumber) => self.log_number = read_u64(&mut input)?,
+                Some(Tag::NextFileNumber) => self.next_file_number = read_u64(&mut input)?,
+                Some(Tag::LastSequence) => self.last_sequence = read_u64(&mut input)?,
+                Some(Tag::PrevLogNumber) => self.prev_log_number = read_u64(&mut input)?,
+                Some(Tag::CompactPointer) | Some(Tag::Comparator) => {
+                    return Err(format!("unsupported tag {:}", tag));
                 }
-                Tag::NewFile => {
-                    let level = input.read_u64();
-                    let file_num = input.read_u64();
-                    let file_size = input.read_u64();
-                    let largest_size = input.read_u64() as usize;
-                    let largest = input.read(largest_size);
-                    let smallest_size = input.read_u64() as usize;
-                    let smallest = input.read(smallest_size);
-                    self.files.push(FileMetaData {
-                        file_num: file_num,
-                        file_size: file_size,
-                        largest: InternalKey::from(largest),
-                        smallest: InternalKey::from(smallest),
-                        level: level,
-                    });
+                Some(Tag::DeletedFile) => {
+                    let meta = read_file(&mut input)?;
+                    self.deleted_files.push(meta);
                 }
+                Some(Tag::NewFile) => {
+                    let meta = read_file(&mut input)?;
+                    self.files.push(meta);
+                }
+                None => return Err(format!("unknown tag {:}", tag)),
             }
         }
+        Ok(())
     }
 
-    pub fn encode_to<T: Write>(&self, writer: &mut LogWriter<T>) {
+    pub fn encode_to<T: Write>(&self, writer: &mut LogWriter<T>) -> Result<(), String> {
         let mut res = BytesMut::with_capacity(BLOCK_SIZE);
 
         if self.log_number != 0 {
@@ -162,7 +142,7 @@ impl VersionEdit {
             self.next_file_number,
             self.last_sequence
         );
-        writer.add_record(res.freeze());
+        writer.add_record(res.freeze())
     }
 
     pub fn add_file(&mut self, meta: FileMetaData) {
@@ -170,6 +150,36 @@ impl VersionEdit {
     }
 }
 
+fn read_u64(input: &mut Bytes) -> Result<u64, String> {
+    if input.len() < U64_BYTE_SIZE {
+        return Err("record is truncated".to_owned());
+    }
+    Ok(input.read_u64())
+}
+
+fn read_key(input: &mut Bytes) -> Result<InternalKey, String> {
+    let size = read_u64(input)?;
+    if (input.len() as u64) < size {
+        return Err("record is truncated".to_owned());
+    }
+    Ok(InternalKey::from(input.read(size as usize)))
+}
+
+fn read_file(input: &mut Bytes) -> Result<FileMetaData, String> {
+    let level = read_u64(input)?;
+    let file_num = read_u64(input)?;
+    let file_size = read_u64(input)?;
+    let largest = read_key(input)?;
+    let smallest = read_key(input)?;
+    Ok(FileMetaData {
+        file_num,
+        file_size,
+        largest,
+        smallest,
+        level,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -208,13 +218,13 @@ mod tests {
         {
             let w = BufWriter::new(Cursor::new(&mut result));
             let mut lw = LogWriter::new(w);
-            ve.encode_to(&mut lw);
+            ve.encode_to(&mut lw).unwrap();
         }
 
         let r = BufReader::new(Cursor::new(result));
         let mut reader = LogReader::new(r);
         let mut ve2 = VersionEdit::new(0);
-        ve2.decode_from(reader.read_record().unwrap());
+        ve2.try_decode_from(reader.read_record().unwrap()).unwrap();
 
         assert_eq!(ve2.files[0], ve.files[0]);
         assert_eq!(ve2.deleted_files[0], ve.deleted_files[0]);
@@ -223,4 +233,29 @@ mod tests {
         assert_eq!(ve2.last_sequence, ve.last_sequence);
         assert_eq!(ve2.prev_log_number, ve.prev_log_number);
     }
+
+    #[test]
+    fn version_edit_try_decode_corrupted() {
+        let mut record = vec![Tag::LogNumber as u8];
+        record.extend_from_slice(&[1, 0, 0, 0]);
+        let err = VersionEdit::new(0)
+            .try_decode_from(Bytes::from(record))
+            .unwrap_err();
+        assert!(err.contains("truncated"), "{}", err);
+
+        // A key longer than the rest of the record
+        let mut record = vec![Tag::NewFile as u8];
+        for v in &[0, 1, 2, 100] {
+            record.extend_from_slice(&[*v, 0, 0, 0, 0, 0, 0, 0]);
+        }
+        let err = VersionEdit::new(0)
+            .try_decode_from(Bytes::from(record))
+            .unwrap_err();
+        assert!(err.contains("truncated"), "{}", err);
+
+        for &tag in &[0, Tag::Comparator as u8, Tag::CompactPointer as u8, 9] {
+            let result = VersionEdit::new(0).try_decode_from(Bytes::from(vec![tag]));
+            assert!(result.is_err());
+        }
+    }
 }
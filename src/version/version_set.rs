@@ -1,19 +1,24 @@
-use bytes::Bytes;
-use random_access_file::RandomAccessFile;
+use comparator::{Comparator, KeyComparator};
+use env::{self, Env, WritableFile};
+use std::cmp::Ordering;
 use std::collections::BTreeSet;
-use std::fs;
-use std::io::BufReader;
-use std::io::BufWriter;
-use std::io::Read;
+use std::sync::Arc;
 
 use log_record::{LogReader, LogWriter};
 use filename;
 use ikey;
-use super::{CircularLinkedList, FileMetaData, VersionEdit};
+use ikey::LookupResult;
+use slice::Bytes;
+use options::Options;
+use super::{CircularLinkedList, Compaction, FileMetaData, TwoLevelIterator, VersionEdit, LEVEL};
 use table;
 
+// Level 1 holds up to 10MB and each deeper level is 10 times larger than the previous one
+const MAX_BYTES_FOR_LEVEL_BASE: f64 = 10.0 * 1048576.0;
+
 pub struct VersionSet {
     dbname: String,
+    env: Arc<dyn Env>,
     pub manifest_file_number: u64,
     pub log_number: u64,
     pub next_file_number: u64,
@@ -22,33 +27,47 @@ pub struct VersionSet {
 
     // dummy_version is the head of a doubly-linked list of versions.
     // dummy_Version.prev is the current version.
-    dummy_version: CircularLinkedList<Version>,
-    manifest: Option<LogWriter<BufWriter<fs::File>>>,
+    // Readers, iterators and compactions hold a reference to a version while they use it.
+    // A version is removed from the list once nobody refers it but the list.
+    dummy_version: CircularLinkedList<Arc<Version>>,
+    manifest: Option<LogWriter<Box<dyn WritableFile>>>,
+
+    // Per level key at which the next compaction at that level should start
+    compact_pointer: Vec<Option<ikey::InternalKey>>,
+    l0_compaction_trigger: usize,
+    max_file_size: u64,
 }
 
 impl VersionSet {
-    pub fn new(dbname: &str) -> Self {
+    pub fn new(dbname: &str, options: &Options) -> Self {
         Self {
             dbname: dbname.to_owned(),
+            env: options.env.clone(),
             manifest_file_number: 0, // will be filled in recover
             log_number: 0,
             next_file_number: 2, // 1 is reserved by Manifest file?
             prev_log_number: 0,
             last_sequence: 0,
-            dummy_version: CircularLinkedList::new(Version::new()),
+            dummy_version: CircularLinkedList::new(Arc::new(Version::new())),
             manifest: None,
+            compact_pointer: vec![None; LEVEL],
+            l0_compaction_trigger: options.l0_compaction_trigger,
+            max_file_size: options.max_file_size as u64,
         }
     }
 
-    pub fn current(&self) -> Option<&Version> {
-        self.dummy_version.current()
+    pub fn current(&self) -> Arc<Version> {
+        self.dummy_version
+            .current()
+            .expect("current version does not exist")
+            .clone()
     }
 
-    pub fn log_and_apply(&mut self, edit: &mut VersionEdit) {
+    pub fn log_and_apply(&mut self, edit: &mut VersionEdit) -> Result<(), String> {
         // TODO: check log versoin is consistent
 
         if self.manifest.is_none() {
-            self.create_manifest_file();
+            self.create_manifest_file()?;
         }
 
         if edit.log_number == 0 {
@@ -57,18 +76,28 @@ impl VersionSet {
 
         edit.next_file_number = self.next_file_number;
         edit.last_sequence = self.last_sequence;
-        if let Some(m) = self.manifest.as_mut() {
-            edit.encode_to(m);
+        // The edit must be durable before CURRENT refers to the manifest
+        let result = match self.manifest.as_mut() {
+            Some(m) => edit.encode_to(m).and_then(|_| m.sync()),
+            None => Ok(()),
+        };
+        if let Err(msg) = result {
+            // The manifest may end with a broken record, so switch to a new one next time
+            self.manifest = None;
+            self.manifest_file_number = self.next_file_num();
+            return Err(msg);
         }
-        filename::set_current_file(&self.dbname, self.manifest_file_number as usize);
+        filename::set_current_file(
+            &*self.env,
+            &self.dbname,
+            self.manifest_file_number as usize,
+        )?;
 
         let mut vb = VersionBuilder::new();
         vb.apply(edit);
 
-        let v = {
-            let c = self.current().expect("current version does not exist");
-            vb.save_to(c)
-        };
+        let mut v = vb.save_to(&self.current());
+        self.finalize(&mut v);
         self.append(v);
 
         if edit.log_number != 0 {
@@ -78,33 +107,32 @@ impl VersionSet {
         if edit.prev_log_number != 0 {
             self.prev_log_number = edit.prev_log_number;
         }
+        Ok(())
     }
 
-    fn create_manifest_file(&mut self) {
+    fn create_manifest_file(&mut self) -> Result<(), String> {
         let manifest =
             filename::FileType::Manifest(&self.dbname, self.manifest_file_number as usize)
                 .filename();
 
         debug!("open new manifest_file {:?}", manifest);
-        let mut writer = fs::File::create(manifest)
-            .map(|fs| LogWriter::new(BufWriter::new(fs)))
-            .expect("Failed to create writer for manifest file");
+        let mut writer = self.env.new_writable_file(&manifest).map(LogWriter::new)?;
 
         // Save compaction pointers
 
         let mut edit = VersionEdit::new(0); // 0 is ok?
-        if let Some(current_version) = self.current() {
-            for i in 0..LEVEL {
-                for meta in current_version.files[i].iter() {
-                    edit.add_file(meta.clone());
-                }
+        let current_version = self.current();
+        for i in 0..LEVEL {
+            for meta in current_version.files[i].iter() {
+                edit.add_file(meta.clone());
             }
         }
 
         debug!("Save current version info");
-        edit.encode_to(&mut writer);
+        edit.encode_to(&mut writer)?;
 
         self.manifest = Some(writer);
+        Ok(())
     }
 
     pub fn next_file_num(&mut self) -> u64 {
@@ -113,18 +141,13 @@ impl VersionSet {
         r
     }
 
-    pub fn recover(&mut self) {
+    pub fn recover(&mut self) -> Result<(), String> {
         let current = filename::FileType::Current(&self.dbname).filename();
-        let mut fs = fs::File::open(current).expect("fail to open current file");
-        let mut name = String::new();
-        fs.read_to_string(&mut name)
-            .expect("failed to read current file content");
+        let name = env::read_file_to_string(&*self.env, &current)?;
 
         let n = format!("{:}/{:}", &self.dbname, name);
         debug!("Load current manifest file {:?}", name);
-        let reader = fs::File::open(n)
-            .map(|fs| LogReader::new(BufReader::new(fs)))
-            .expect("failed to read manifest");
+        let mut reader = self.env.new_sequential_file(&n).map(LogReader::new)?;
 
         let mut log_number = 0;
         let mut prev_log_number = 0;
@@ -132,9 +155,16 @@ impl VersionSet {
         let mut last_sequence = 0;
         let mut vb = VersionBuilder::new();
 
-        for record in reader.into_iter() {
+        while let Some(record) = reader.read_record() {
             let mut ve = VersionEdit::new(0);
-            ve.decode_from(record);
+            ve.try_decode_from(record).map_err(|msg| {
+                format!(
+                    "corrupted MANIFEST {:} at offset {:}: {:}; run repair",
+                    n,
+                    reader.last_record_offset(),
+                    msg
+                )
+            })?;
 
             vb.apply(&ve);
 
@@ -154,6 +184,12 @@ impl VersionSet {
                 last_sequence = ve.last_sequence
             }
         }
+        if let Some((offset, msg)) = reader.corruption() {
+            return Err(format!(
+                "corrupted MANIFEST {:} at offset {:}: {:}; run repair",
+                n, offset, msg
+            ));
+        }
         self.next_file_number = next_file_number;
         self.mark_file_num_used(log_number);
         self.mark_file_num_used(prev_log_number);
@@ -163,8 +199,9 @@ impl VersionSet {
         self.log_number = log_number;
         self.prev_log_number = prev_log_number;
 
-        let mut ver = Version::new();
-        let v = vb.save_to(&mut ver);
+        let ver = Version::new();
+        let mut v = vb.save_to(&ver);
+        self.finalize(&mut v);
         self.append(v);
 
         debug!(
@@ -176,6 +213,7 @@ impl VersionSet {
             self.next_file_number,
             self.last_sequence
         );
+        Ok(())
     }
 
     pub fn mark_file_num_used(&mut self, num: u64) {
@@ -186,13 +224,23 @@ impl VersionSet {
 
     fn append(&mut self, v: Version) {
         debug!("Append version {:?}", v);
-        self.dummy_version.append(v)
+        self.dummy_version.append(Arc::new(v));
+        self.remove_unreferenced_versions();
     }
 
-    pub fn live_files(&self) -> Vec<u64> {
+    fn remove_unreferenced_versions(&mut self) {
+        let current = self.current();
+        self.dummy_version
+            .retain(|v| Arc::ptr_eq(v, &current) || Arc::strong_count(v) > 1);
+    }
+
+    // Returns the files of all versions which are still referenced
+    pub fn live_files(&mut self) -> Vec<u64> {
+        self.remove_unreferenced_versions();
+
         let mut vec = vec![];
         for v in self.dummy_version.iter() {
-            for level in 0..12 {
+            for level in 0..LEVEL {
                 let ref fmds: Vec<FileMetaData> = v.files[level];
                 for md in fmds {
                     vec.push(md.file_num);
@@ -207,25 +255,154 @@ impl VersionSet {
         self.last_sequence = v;
     }
 
-// TODO: use comparetor
-fn max_key_range2<'a>(
-    files1: &'a Vec<FileMetaData>,
-    files2: &'a Vec<FileMetaData>,
-) -> (ikey::InternalKey, ikey::InternalKey) {
-    let (s1, l1) = max_key_range(files1);
-    let (s2, l2) = max_key_range(files2);
-    (if s1 < s2 { s1 } else { s2 }, if l1 < l2 { l2 } else { l1 })
+    pub fn num_level_files(&self, level: usize) -> usize {
+        self.current().files[level].len()
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.current().compaction_score >= 1.0
+    }
+
+    // Picks the level which needs compaction most and the files to compact.
+    // Returns None if no level needs compaction.
+    pub fn pick_compaction(&mut self) -> Option<Compaction> {
+        let current = self.current();
+        if current.compaction_score < 1.0 {
+            return None;
+        }
+        let level = current.compaction_level;
+        let cmp = KeyComparator::new();
+
+        // Pick the first file that comes after compact_pointer[level]
+        let first = current.files[level]
+            .iter()
+            .find(|f| match self.compact_pointer[level] {
+                Some(ref p) => cmp.compare(f.largest.as_ref(), p.as_ref()) == Ordering::Greater,
+                None => true,
+            })
+            .or_else(|| current.files[level].first()) // Wrap-around to the beginning
+            .cloned();
+
+        let mut c = Compaction::new(level, current, self.max_file_size);
+        match first {
+            Some(f) => c.inputs[0].push(f),
+            None => return None,
+        }
+
+        // Files in level 0 may overlap each other, so pick up all overlapping ones
+        if level == 0 {
+            let (smallest, largest) = key_range(&c.inputs[0]);
+            c.inputs[0] = c.input_version()
+                .get_overlapping_inputs(0, &smallest, &largest);
+        }
+
+        self.setup_other_inputs(&mut c);
+        Some(c)
+    }
+
+    // Returns a compaction of files in level which overlap [begin, end] in user key order, or
+    // None if there is no such file. None for begin means before all keys, and for end after
+    // all keys.
+    pub fn compact_range(
+        &mut self,
+        level: usize,
+        begin: Option<&ikey::InternalKey>,
+        end: Option<&ikey::InternalKey>,
+    ) -> Option<Compaction> {
+        let current = self.current();
+        if current.files[level].is_empty() {
+            return None;
+        }
+
+        let (smallest, largest) = key_range(&current.files[level]);
+        let mut inputs = current.get_overlapping_inputs(
+            level,
+            begin.unwrap_or(&smallest),
+            end.unwrap_or(&largest),
+        );
+        if inputs.is_empty() {
+            return None;
+        }
+
+        // Avoid compacting too much in one shot in case the range is large. Files of level 0
+        // may overlap each other, so they are not truncated.
+        if level > 0 {
+            let limit = self.max_file_size;
+            let mut total = 0;
+            let last = inputs.iter().position(|f| {
+                total += f.file_size;
+                total >= limit
+            });
+            if let Some(i) = last {
+                inputs.truncate(i + 1);
+            }
+        }
+
+        let mut c = Compaction::new(level, current, self.max_file_size);
+        c.inputs[0] = inputs;
+        self.setup_other_inputs(&mut c);
+        Some(c)
+    }
+
+    // Picks files of the next level which overlap inputs[0] of c
+    fn setup_other_inputs(&mut self, c: &mut Compaction) {
+        let (smallest, largest) = key_range(&c.inputs[0]);
+        c.inputs[1] = c.input_version()
+            .get_overlapping_inputs(c.level + 1, &smallest, &largest);
+
+        // Update the place where we will do the next compaction for this level
+        self.compact_pointer[c.level] = Some(largest);
+    }
+
+    // Precomputes the best level for the next compaction
+    fn finalize(&self, v: &mut Version) {
+        let mut best_level = 0;
+        let mut best_score = -1.0;
+
+        for level in 0..(LEVEL - 1) {
+            let score = if level == 0 {
+                // Level 0 is limited by the number of files rather than bytes since every read
+                // has to merge all level 0 files.
+                v.files[level].len() as f64 / self.l0_compaction_trigger as f64
+            } else {
+                total_file_size(&v.files[level]) as f64 / max_bytes_for_level(level)
+            };
+
+            if score > best_score {
+                best_level = level;
+                best_score = score;
+            }
+        }
+
+        v.compaction_level = best_level;
+        v.compaction_score = best_score;
+    }
+}
+
+fn max_bytes_for_level(level: usize) -> f64 {
+    let mut result = MAX_BYTES_FOR_LEVEL_BASE;
+    for _ in 1..level {
+        result *= 10.0;
+    }
+    result
 }
 
-fn max_key_range<'a>(files: &'a Vec<FileMetaData>) -> (ikey::InternalKey, ikey::InternalKey) {
+pub fn total_file_size(files: &[FileMetaData]) -> u64 {
+    files.iter().map(|f| f.file_size).sum()
+}
+
+// Returns the smallest and the largest internal keys of files.
+// REQUIRES: files is not empty
+fn key_range(files: &[FileMetaData]) -> (ikey::InternalKey, ikey::InternalKey) {
+    let cmp = KeyComparator::new();
     let mut smallest = &files[0].smallest;
     let mut largest = &files[0].largest;
     for f in files {
-        if smallest > &f.smallest {
+        if cmp.compare(f.smallest.as_ref(), smallest.as_ref()) == Ordering::Less {
             smallest = &f.smallest;
         }
 
-        if largest < &f.largest {
+        if cmp.compare(f.largest.as_ref(), largest.as_ref()) == Ordering::Greater {
             largest = &f.largest;
         }
     }
@@ -233,59 +410,188 @@ fn max_key_range<'a>(files: &'a Vec<FileMetaData>) -> (ikey::InternalKey, ikey::
     (smallest.clone(), largest.clone())
 }
 
-const LEVEL: usize = 12;
-
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Version {
     files: Vec<Vec<FileMetaData>>, // table type file
-                                   // Add field COMPACTION_LEVEL
+
+    // Level that should be compacted next and its score.
+    // score < 1 means compaction is not strictly needed.
+    compaction_score: f64,
+    compaction_level: usize,
 }
 
 impl Version {
     pub fn new() -> Self {
         Self {
             files: vec![vec![]; LEVEL],
+            compaction_score: -1.0,
+            compaction_level: 0,
         }
     }
 
-    // name(cache) is correct?
-    pub fn get<T: RandomAccessFile>(
+    pub fn files(&self, level: usize) -> &Vec<FileMetaData> {
+        &self.files[level]
+    }
+
+    // Lists files of each level with their numbers, sizes and key ranges
+    pub fn debug_string(&self) -> String {
+        let mut s = String::new();
+        for (level, files) in self.files.iter().enumerate() {
+            s.push_str(&format!("--- level {:} ---\n", level));
+            for f in files {
+                s.push_str(&format!(
+                    " {:}:{:}[{:} .. {:}]\n",
+                    f.file_num,
+                    f.file_size,
+                    f.smallest.debug_string(),
+                    f.largest.debug_string()
+                ));
+            }
+        }
+        s
+    }
+
+    // Search files from newer to older. Stops at the first file which has key or its deletion.
+    pub fn get(
         &self,
         key: &ikey::InternalKey,
-        cache: &mut table::TableCache<T>,
-    ) -> Option<Bytes> {
+        cache: &table::TableCache,
+    ) -> Result<LookupResult, String> {
         let ukey = key.user_key();
+        let lookup_key = key.lookup_key();
+        let cmp = KeyComparator::new();
+
+        // Files in level 0 may overlap each other. Newer files have larger file numbers.
+        let mut meta_files: Vec<&FileMetaData> = self.files[0]
+            .iter()
+            .filter(|f| ukey >= f.smallest.user_key() && ukey <= f.largest.user_key())
+            .collect();
+        meta_files.sort_by(|a, b| b.file_num.cmp(&a.file_num));
+
+        for meta in meta_files {
+            debug!("{:?} may be in level 0 file {:?}", ukey, meta.file_num);
+            match cache.get(key, meta.file_num, meta.file_size)? {
+                LookupResult::NotFound => continue,
+                r => return Ok(r),
+            }
+        }
 
-        for i in 0..LEVEL {
-            let mut meta_files: Vec<&FileMetaData> = vec![];
+        // Other levels are sorted and disjoint, so at most one file can contain key
+        for level in 1..LEVEL {
+            let found = self.files[level]
+                .iter()
+                .find(|f| cmp.compare(f.largest.as_ref(), &lookup_key) != Ordering::Less);
 
-            // level0
-            if i == 0 {
-                if self.files[0].len() != 0 {
-                    for file in &self.files[0] {
-                        if ukey <= file.largest.user_key() && ukey >= file.smallest.user_key() {
-                            debug!("{:?} is found in level 0 file", ukey);
-                            meta_files.push(file);
-                        }
-                    }
+            if let Some(meta) = found {
+                if ukey < meta.smallest.user_key() {
+                    continue;
                 }
 
-                meta_files.sort();
-            } else {
+                match cache.get(key, meta.file_num, meta.file_size)? {
+                    LookupResult::NotFound => continue,
+                    r => return Ok(r),
+                }
+            }
+        }
 
+        Ok(LookupResult::NotFound)
+    }
+
+    // Returns the approximate offset of key in the data of this version, as if the files of
+    // all levels were concatenated. Only index blocks are read. A file which can not be opened
+    // counts as empty where key is in it.
+    pub fn approximate_offset_of(&self, key: &ikey::InternalKey, cache: &table::TableCache) -> u64 {
+        let cmp = KeyComparator::new();
+        let mut result = 0;
+        for level in 0..LEVEL {
+            for f in &self.files[level] {
+                if cmp.compare(f.largest.as_ref(), key.as_ref()) != Ordering::Greater {
+                    // The whole file is before key
+                    result += f.file_size;
+                } else if cmp.compare(f.smallest.as_ref(), key.as_ref()) == Ordering::Greater {
+                    // The whole file is after key. Files of levels other than 0 are sorted,
+                    // so the rest of the level is after key too.
+                    if level > 0 {
+                        break;
+                    }
+                } else {
+                    if let Ok(table) = cache.find_or_create_table(f.file_num, f.file_size) {
+                        result += table.approximate_offset_of(&key.inner());
+                    }
+                }
             }
+        }
+        result
+    }
 
-            for meta in meta_files {
-                let v = cache.get(&ukey, meta.file_num, meta.file_size);
-                if v.is_some() {
-                    return v;
+    // Returns the approximate number of entries in [start, limit), assuming entries are spread
+    // evenly over the data of each file. None if a file in the range does not record its
+    // number of entries or can not be opened.
+    pub fn approximate_num_entries(
+        &self,
+        start: &ikey::InternalKey,
+        limit: &ikey::InternalKey,
+        cache: &table::TableCache,
+    ) -> Option<u64> {
+        let cmp = KeyComparator::new();
+        let mut result = 0.0;
+        for level in 0..LEVEL {
+            for f in &self.files[level] {
+                if cmp.compare(f.largest.as_ref(), start.as_ref()) == Ordering::Less
+                    || cmp.compare(f.smallest.as_ref(), limit.as_ref()) != Ordering::Less
+                {
+                    continue;
+                }
+
+                let table = cache.find_or_create_table(f.file_num, f.file_size).ok()?;
+                let entries = table.num_entries()?;
+                let begin = table.approximate_offset_of(&start.inner());
+                let end = table.approximate_offset_of(&limit.inner());
+                if table.data_size() > 0 {
+                    result += entries as f64 * end.saturating_sub(begin) as f64
+                        / table.data_size() as f64;
                 }
             }
         }
+        Some(result.round() as u64)
+    }
 
-        None
+    // Appends iterators over all files of this version. Files of level 0 may overlap each
+    // other, so each of them needs its own iterator, while a level is iterated as a whole.
+    pub fn add_iterators(
+        &self,
+        cache: &table::TableCache,
+        iters: &mut Vec<Box<dyn Iterator<Item = (Bytes, Bytes)> + Send>>,
+    ) -> Result<(), String> {
+        for f in &self.files[0] {
+            iters.push(Box::new(cache.inner_iter(f.file_num, f.file_size)?));
+        }
+
+        for level in 1..LEVEL {
+            if self.files[level].is_empty() {
+                continue;
+            }
+
+            let level_iters = self.files[level]
+                .iter()
+                .map(|f| cache.inner_iter(f.file_num, f.file_size))
+                .collect::<Result<Vec<_>, String>>()?;
+            iters.push(Box::new(TwoLevelIterator::new(level_iters.into_iter())));
+        }
+        Ok(())
     }
 
+    // Returns true if a file in level overlaps [begin, end] in user key order. None for begin
+    // means before all keys, and for end after all keys.
+    pub fn overlap_in_level(&self, level: usize, begin: Option<&[u8]>, end: Option<&[u8]>) -> bool {
+        self.files[level].iter().any(|f| {
+            let before = end.map_or(false, |end| end < &f.smallest.user_key()[..]);
+            let after = begin.map_or(false, |begin| &f.largest.user_key()[..] < begin);
+            !before && !after
+        })
+    }
+
+    // Returns files in level which overlap [left, right] in user key order
     pub fn get_overlapping_inputs(
         &self,
         level: usize,
@@ -301,42 +607,31 @@ impl Version {
         let mut left_key = left.user_key();
         let mut right_key = right.user_key();
 
-        // TODO:
-        if level == 0 {
-            loop {
-                let mut retry = false;
-
-                for f in &self.files[level] {
-                    let smallest_key = f.smallest.user_key();
-                    let largest_key = f.largest.user_key();
-
-                    if smallest_key < left_key && right_key < largest_key {
-                        left_key = smallest_key;
-                        right_key = largest_key;
-                        retry = true;
-                    } else if smallest_key < left_key {
-                        left_key = smallest_key;
-                        retry = true;
-                    } else if right_key < largest_key {
-                        right_key = largest_key;
-                        retry = true;
-                    }
-                }
+        let files = &self.files[level];
+        let mut i = 0;
+        while i < files.len() {
+            let f = &files[i];
+            i += 1;
 
-                if !retry {
-                    break;
-                }
-            }
-        }
-
-        for f in &self.files[level] {
             let smallest_key = f.smallest.user_key();
             let largest_key = f.largest.user_key();
-
             if largest_key < left_key || right_key < smallest_key {
                 continue;
-            } else {
-                ret.push(f.clone());
+            }
+            ret.push(f.clone());
+
+            // Level 0 files may overlap each other. So if the newly added file has expanded
+            // the range, restart search with the new range.
+            if level == 0 {
+                if smallest_key < left_key {
+                    left_key = smallest_key;
+                    ret.clear();
+                    i = 0;
+                } else if right_key < largest_key {
+                    right_key = largest_key;
+                    ret.clear();
+                    i = 0;
+                }
             }
         }
 
@@ -391,7 +686,12 @@ impl VersionBuilder {
                 version.files[i].push(f.clone())
             }
 
-            version.files[i].sort();
+            if i == 0 {
+                version.files[i].sort();
+            } else {
+                let cmp = KeyComparator::new();
+                version.files[i].sort_by(|a, b| cmp.compare(a.smallest.as_ref(), b.smallest.as_ref()));
+            }
         }
 
         version
@@ -403,6 +703,7 @@ mod tests {
     use super::*;
     use version::FileMetaData;
     use ikey::InternalKey;
+    use env::MemEnv;
 
     fn file_meta_data(i: u64) -> FileMetaData {
         FileMetaData {
@@ -414,6 +715,30 @@ mod tests {
         }
     }
 
+    #[test]
+    fn version_set_live_files_of_referenced_versions() {
+        let mut options = Options::default();
+        options.env = Arc::new(MemEnv::new());
+        options.env.create_dir("/leveldb_test").unwrap();
+        let mut vs = VersionSet::new("/leveldb_test", &options);
+
+        let mut edit = VersionEdit::new(0);
+        edit.add_file(file_meta_data(1));
+        vs.log_and_apply(&mut edit).unwrap();
+        let old = vs.current();
+
+        let mut edit = VersionEdit::new(0);
+        edit.deleted_files.push(file_meta_data(1));
+        edit.add_file(file_meta_data(2));
+        vs.log_and_apply(&mut edit).unwrap();
+
+        // old still refers file 1
+        assert_eq!(vs.live_files(), vec![1, 2]);
+
+        drop(old);
+        assert_eq!(vs.live_files(), vec![2]);
+    }
+
     #[test]
     fn version_builder_level_0() {
         let f1 = file_meta_data(1);
@@ -0,0 +1,131 @@
+use options::Options;
+use std::time::Duration;
+
+// Delay of a write when level 0 reaches l0_slowdown_writes_trigger. It grows by this amount for
+// every additional level 0 file until writes are stopped.
+const SLOWDOWN_DELAY_MICROS: u64 = 1000;
+
+#[derive(Debug, PartialEq)]
+pub enum WriteStall {
+    // All memtables are full and wait for being flushed
+    MemtableLimit,
+    // Level 0 has too many files and waits for compaction
+    Level0Limit,
+}
+
+// WriteController decides how writers are throttled by the state of background work.
+// Rather than stopping a writer for a long time at the hard limit, it starts to delay every
+// write a little when compaction falls behind, so that latency grows gradually.
+pub struct WriteController {
+    slowdown_trigger: usize,
+    stop_trigger: usize,
+    max_imm_count: usize,
+
+    delayed_writes: u64,
+    stopped_writes: u64,
+    stall_micros: u64,
+}
+
+impl WriteController {
+    pub fn new(options: &Options) -> Self {
+        WriteController {
+            slowdown_trigger: options.l0_slowdown_writes_trigger,
+            stop_trigger: options.l0_stop_writes_trigger,
+            max_imm_count: options.max_write_buffer_number - 1,
+            delayed_writes: 0,
+            stopped_writes: 0,
+            stall_micros: 0,
+        }
+    }
+
+    // Returns how long a write should sleep before it is applied
+    pub fn delay(&self, l0_files: usize) -> Option<Duration> {
+        if l0_files < self.slowdown_trigger {
+            return None;
+        }
+
+        let steps = (l0_files.min(self.stop_trigger) - self.slowdown_trigger + 1) as u64;
+        Some(Duration::from_micros(SLOWDOWN_DELAY_MICROS * steps))
+    }
+
+    // Returns the reason if a writer must wait for background work before it switches
+    // to a new memtable.
+    pub fn stall(&self, l0_files: usize, imm_count: usize) -> Option<WriteStall> {
+        if imm_count >= self.max_imm_count {
+            Some(WriteStall::MemtableLimit)
+        } else if l0_files >= self.stop_trigger {
+            Some(WriteStall::Level0Limit)
+        } else {
+            None
+        }
+    }
+
+    pub fn record_delay(&mut self, d: Duration) {
+        self.delayed_writes += 1;
+        self.stall_micros += duration_micros(d);
+    }
+
+    pub fn record_stop(&mut self, d: Duration) {
+        self.stopped_writes += 1;
+        self.stall_micros += duration_micros(d);
+    }
+
+    pub fn delayed_writes(&self) -> u64 {
+        self.delayed_writes
+    }
+
+    pub fn stopped_writes(&self) -> u64 {
+        self.stopped_writes
+    }
+
+    pub fn stall_micros(&self) -> u64 {
+        self.stall_micros
+    }
+}
+
+fn duration_micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + u64::from(d.subsec_micros())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller() -> WriteController {
+        let mut options = Options::default();
+        options.max_write_buffer_number = 3;
+        options.l0_slowdown_writes_trigger = 8;
+        options.l0_stop_writes_trigger = 12;
+        WriteController::new(&options)
+    }
+
+    #[test]
+    fn write_controller_delay_grows_with_level0_files() {
+        let c = controller();
+        assert_eq!(c.delay(7), None);
+        assert_eq!(c.delay(8), Some(Duration::from_millis(1)));
+        assert_eq!(c.delay(9), Some(Duration::from_millis(2)));
+        assert_eq!(c.delay(12), Some(Duration::from_millis(5)));
+        assert_eq!(c.delay(20), Some(Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn write_controller_stall() {
+        let c = controller();
+        assert_eq!(c.stall(0, 0), None);
+        assert_eq!(c.stall(11, 1), None);
+        assert_eq!(c.stall(11, 2), Some(WriteStall::MemtableLimit));
+        assert_eq!(c.stall(12, 0), Some(WriteStall::Level0Limit));
+    }
+
+    #[test]
+    fn write_controller_stats() {
+        let mut c = controller();
+        c.record_delay(Duration::from_millis(1));
+        c.record_delay(Duration::from_millis(2));
+        c.record_stop(Duration::from_secs(1));
+        assert_eq!(c.delayed_writes(), 2);
+        assert_eq!(c.stopped_writes(), 1);
+        assert_eq!(c.stall_micros(), 1_003_000);
+    }
+}